@@ -0,0 +1,76 @@
+//! Alarm state as reported through the APIs
+//!
+//! Timestamps are seconds since the UNIX epoch.
+
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    utoipa::ToSchema,
+    serde::Serialize,
+    serde::Deserialize,
+    juniper::GraphQLEnum,
+)]
+pub enum AlarmSeverity {
+    /// Informs the operator, the crab keeps running
+    Warning,
+    /// Stops the crab until the fault is reset
+    Fault,
+}
+
+#[derive(Debug, Clone, PartialEq, utoipa::ToSchema, serde::Serialize, juniper::GraphQLObject)]
+pub struct AlarmInfo {
+    pub id: String,
+    pub description: String,
+    pub severity: AlarmSeverity,
+    /// Alarm stays active after its condition cleared until the fault is reset
+    pub latching: bool,
+    pub active: bool,
+    pub acknowledged: bool,
+    /// This alarm was raised first and probably caused the others
+    pub first_out: bool,
+    pub raised_at: Option<f64>,
+    pub cleared_at: Option<f64>,
+    pub acknowledged_at: Option<f64>,
+}
+
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    utoipa::ToSchema,
+    serde::Serialize,
+    serde::Deserialize,
+    juniper::GraphQLEnum,
+)]
+pub enum AlarmEventKind {
+    Raised,
+    Cleared,
+    Acknowledged,
+}
+
+#[derive(Debug, Clone, PartialEq, utoipa::ToSchema, serde::Serialize, juniper::GraphQLObject)]
+pub struct AlarmEvent {
+    pub time: f64,
+    pub id: String,
+    pub kind: AlarmEventKind,
+}
+
+#[derive(
+    Debug, Default, Clone, PartialEq, utoipa::ToSchema, serde::Serialize, juniper::GraphQLObject,
+)]
+pub struct AlarmsSnapshot {
+    pub alarms: Vec<AlarmInfo>,
+    /// Most recent alarm events, oldest first
+    pub history: Vec<AlarmEvent>,
+}
+
+impl AlarmsSnapshot {
+    pub fn contains(&self, id: &str) -> bool {
+        self.alarms.iter().any(|a| a.id == id)
+    }
+}
@@ -0,0 +1,339 @@
+//! Audit log of the control actions taken through the APIs
+//!
+//! Every POST to one of the [`ROUTE_ROLES`](crate::auth::ROUTE_ROLES) and every GraphQL mutation
+//! is recorded, including the ones which were denied.  Entries are appended to a JSON lines file
+//! and the most recent ones are kept in memory for `GET /crab/audit`.
+
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::Method,
+    middleware::Next,
+    response::{IntoResponse as _, Response},
+};
+use std::net::SocketAddr;
+
+/// Number of entries kept in memory
+const RECENT_ENTRIES: usize = 1000;
+
+/// Largest error message recorded as result
+const MAX_AUDIT_RESPONSE: usize = 64 * 1024;
+
+/// Location of the audit log, from the `[audit]` section of the configuration
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuditConfig {
+    /// JSON lines file the entries are appended to, only kept in memory when not set
+    pub path: Option<std::path::PathBuf>,
+}
+
+impl Default for AuditConfig {
+    fn default() -> Self {
+        Self {
+            path: Some("crab-audit.jsonl".into()),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum AuditError {
+    Io(std::path::PathBuf, std::io::Error),
+}
+
+impl std::fmt::Display for AuditError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AuditError::Io(path, e) => write!(f, "failed accessing {}: {e}", path.display()),
+        }
+    }
+}
+
+impl std::error::Error for AuditError {}
+
+#[derive(Debug, Clone, PartialEq, utoipa::ToSchema, serde::Serialize, serde::Deserialize)]
+pub struct AuditEntry {
+    /// Seconds since the UNIX epoch
+    pub time: f64,
+    /// Address of the client which made the request
+    pub client: Option<String>,
+    /// Authenticated user, `None` for anonymous requests and invalid tokens
+    pub user: Option<String>,
+    pub role: Option<crate::auth::Role>,
+    /// Route or GraphQL mutation, like `POST /crab/inflate` or `mutation triggerFan`
+    pub action: String,
+    /// Request body or mutation arguments, without tokens
+    #[schema(value_type = Object)]
+    pub parameters: serde_json::Value,
+    pub success: bool,
+    /// Why the action failed
+    pub error: Option<String>,
+}
+
+impl AuditEntry {
+    pub fn new(
+        client: Option<SocketAddr>,
+        identity: Option<&crate::auth::Identity>,
+        action: impl Into<String>,
+        mut parameters: serde_json::Value,
+        result: Result<(), String>,
+    ) -> Self {
+        if let Some(parameters) = parameters.as_object_mut() {
+            parameters.remove("token");
+        }
+
+        Self {
+            time: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs_f64(),
+            client: client.map(|c| c.to_string()),
+            user: identity.and_then(|i| i.user.clone()),
+            role: identity.map(|i| i.role),
+            action: action.into(),
+            parameters,
+            success: result.is_ok(),
+            error: result.err(),
+        }
+    }
+}
+
+/// Append-only log of [`AuditEntry`]s
+#[derive(Debug)]
+pub struct AuditLog {
+    path: Option<std::path::PathBuf>,
+    inner: std::sync::Mutex<AuditLogInner>,
+}
+
+#[derive(Debug)]
+struct AuditLogInner {
+    file: Option<std::fs::File>,
+    recent: std::collections::VecDeque<AuditEntry>,
+}
+
+impl AuditLog {
+    /// Open the log, loading the most recent entries of an existing file
+    pub fn open(config: &AuditConfig) -> Result<Self, AuditError> {
+        let mut recent = std::collections::VecDeque::new();
+        let mut file = None;
+
+        if let Some(path) = &config.path {
+            let io_error = |e| AuditError::Io(path.clone(), e);
+            match std::fs::read_to_string(path) {
+                Ok(contents) => {
+                    for line in contents.lines().filter(|l| !l.trim().is_empty()) {
+                        match serde_json::from_str(line) {
+                            Ok(entry) => recent.push_back(entry),
+                            Err(e) => log::warn!("Skipping broken audit entry: {e}"),
+                        }
+                        if recent.len() > RECENT_ENTRIES {
+                            recent.pop_front();
+                        }
+                    }
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+                Err(e) => return Err(io_error(e)),
+            }
+
+            file = Some(
+                std::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .map_err(io_error)?,
+            );
+        }
+
+        Ok(Self {
+            path: config.path.clone(),
+            inner: std::sync::Mutex::new(AuditLogInner { file, recent }),
+        })
+    }
+
+    pub fn record(&self, entry: AuditEntry) {
+        use std::io::Write as _;
+
+        log::info!(
+            "Audit: {} by {} from {}: {}",
+            entry.action,
+            entry.user.as_deref().unwrap_or("anonymous"),
+            entry.client.as_deref().unwrap_or("unknown"),
+            entry.error.as_deref().unwrap_or("ok"),
+        );
+
+        let mut inner = self.inner.lock().unwrap();
+        if let (Some(file), Some(path)) = (&mut inner.file, &self.path) {
+            let mut line = serde_json::to_string(&entry).unwrap();
+            line.push('\n');
+            if let Err(e) = file.write_all(line.as_bytes()) {
+                // Keep the crab running, the entry is still available from memory
+                log::error!("Failed writing audit log {}: {e}", path.display());
+            }
+        }
+
+        if inner.recent.len() == RECENT_ENTRIES {
+            inner.recent.pop_front();
+        }
+        inner.recent.push_back(entry);
+    }
+
+    /// Most recent entries matching the filter, oldest first
+    pub fn query(&self, filter: &AuditQuery) -> Vec<AuditEntry> {
+        let inner = self.inner.lock().unwrap();
+        let mut entries: Vec<AuditEntry> = inner
+            .recent
+            .iter()
+            .rev()
+            .filter(|e| filter.since.is_none_or(|since| e.time >= since))
+            .filter(|e| filter.user.is_none() || e.user == filter.user)
+            .take(filter.limit.unwrap_or(100))
+            .cloned()
+            .collect();
+        entries.reverse();
+        entries
+    }
+}
+
+#[derive(Debug, Default, utoipa::IntoParams, serde::Deserialize)]
+#[into_params(parameter_in = Query)]
+pub struct AuditQuery {
+    /// Only entries at or after this time, in seconds since the UNIX epoch
+    pub since: Option<f64>,
+    /// Only entries of this user
+    pub user: Option<String>,
+    /// Maximum number of entries, 100 by default
+    pub limit: Option<usize>,
+}
+
+/// Middleware recording the state-changing requests
+///
+/// Runs outside of [`crate::auth::authenticate`], so denied requests are recorded as well.
+pub async fn record(
+    State(state): State<crate::AppState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let path = request.uri().path();
+    if request.method() != Method::POST
+        || !crate::auth::ROUTE_ROLES
+            .iter()
+            .any(|(m, p, _)| m == Method::POST && *p == path)
+    {
+        return next.run(request).await;
+    }
+
+    let action = format!("{} {path}", request.method());
+    let client = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|c| c.0);
+
+    let (parts, body) = request.into_parts();
+    let bytes = match axum::body::to_bytes(body, crate::MAX_REQUEST_BODY).await {
+        Ok(bytes) => bytes,
+        Err(_) => {
+            return (
+                axum::http::StatusCode::PAYLOAD_TOO_LARGE,
+                "request too large",
+            )
+                .into_response();
+        }
+    };
+    let parameters = serde_json::from_slice(&bytes).unwrap_or_default();
+
+    let response = next.run(Request::from_parts(parts, bytes.into())).await;
+
+    let status = response.status();
+    let (parts, body) = response.into_parts();
+    let (result, body) = if status.is_success() {
+        (Ok(()), body)
+    } else {
+        // Error responses are short messages, keep them for the log
+        let bytes = axum::body::to_bytes(body, MAX_AUDIT_RESPONSE)
+            .await
+            .unwrap_or_default();
+        let message = String::from_utf8_lossy(&bytes);
+        let error = if message.is_empty() {
+            status.to_string()
+        } else {
+            format!("{status}: {message}")
+        };
+        (Err(error), bytes.into())
+    };
+
+    let identity = parts.extensions.get::<crate::auth::Identity>();
+    state.audit.record(AuditEntry::new(
+        client, identity, action, parameters, result,
+    ));
+
+    Response::from_parts(parts, body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(user: &str, action: &str) -> AuditEntry {
+        let identity = crate::auth::Identity {
+            user: Some(user.into()),
+            role: crate::auth::Role::Operator,
+        };
+        AuditEntry::new(
+            Some(([127, 0, 0, 1], 4242).into()),
+            Some(&identity),
+            action,
+            serde_json::json!({ "token": "secret", "high": 20.0 }),
+            Ok(()),
+        )
+    }
+
+    #[test]
+    fn persist_and_query() {
+        let path = std::env::temp_dir().join(format!("crab-audit-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let config = AuditConfig {
+            path: Some(path.clone()),
+        };
+
+        let log = AuditLog::open(&config).unwrap();
+        log.record(entry("orga", "POST /crab/inflate"));
+        log.record(entry("stage", "POST /crab/talk"));
+        drop(log);
+
+        let log = AuditLog::open(&config).unwrap();
+        log.record(entry("orga", "mutation resetFault"));
+
+        let all = log.query(&Default::default());
+        assert_eq!(all.len(), 3);
+        assert_eq!(all[0].action, "POST /crab/inflate");
+        assert_eq!(all[0].client.as_deref(), Some("127.0.0.1:4242"));
+        assert_eq!(all[0].parameters, serde_json::json!({ "high": 20.0 }));
+
+        let orga = log.query(&AuditQuery {
+            user: Some("orga".into()),
+            limit: Some(1),
+            ..Default::default()
+        });
+        assert_eq!(orga.len(), 1);
+        assert_eq!(orga[0].action, "mutation resetFault");
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(contents.lines().count(), 3);
+        assert!(!contents.contains("secret"));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn largest_sequence_fits() {
+        let step = crate::sequences::SequenceStep {
+            emotion: Some(crate::emotionmanager::Emotion::new("Surprised")),
+            channels: (0..32)
+                .map(|i| (format!("some_rather_long_channel_{i}"), true))
+                .collect(),
+            duration_s: crate::sequences::MAX_STEP_DURATION_S,
+        };
+        let upload = serde_json::json!({
+            "name": "x".repeat(64),
+            "steps": vec![step; crate::sequences::MAX_STEPS],
+        });
+        assert!(serde_json::to_vec(&upload).unwrap().len() < crate::MAX_REQUEST_BODY);
+    }
+}
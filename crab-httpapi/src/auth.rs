@@ -0,0 +1,620 @@
+//! Authentication of API users and their roles
+//!
+//! Users are configured with a token hash (see [`TokenHash`]) and a role.  Clients send their
+//! token as `Authorization: Bearer <token>` header.  For older clients, a `token` field in a JSON
+//! request body is accepted as well.  Requests without a token get the anonymous role, which
+//! defaults to [`Role::Performer`] so the public talk page keeps working.
+//!
+//! The required role of each method and path is listed in [`ROUTE_ROLES`], all other requests
+//! are open to viewers.  GraphQL mutations check the role of the request themselves.
+//!
+//! Checking a token the first time takes a while because of the slow hash.  It runs on the
+//! blocking thread pool, with at most [`MAX_CONCURRENT_CHECKS`] at once, and the result is
+//! remembered whether the token was valid or not.
+
+use axum::{
+    extract::{Request, State},
+    http::{HeaderValue, Method, StatusCode, header},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use sha2::Digest as _;
+
+/// Iterations used for new token hashes
+pub const DEFAULT_ITERATIONS: u32 = 600_000;
+
+/// Tokens checked against the hashes at the same time, further requests are turned away
+pub const MAX_CONCURRENT_CHECKS: usize = 2;
+
+/// Remembered results of token checks, failed ones are forgotten when there are more
+const MAX_CHECKED_TOKENS: usize = 1024;
+
+/// Minimum role for the routes which change anything, or show more than the state of the crab
+pub const ROUTE_ROLES: &[(Method, &str, Role)] = &[
+    (Method::GET, "/crab/audit", Role::Operator),
+    (Method::POST, "/crab/emotion", Role::Performer),
+    (Method::POST, "/crab/talk", Role::Performer),
+    (Method::POST, "/crab/sequences/start", Role::Performer),
+    (Method::POST, "/crab/sequences/stop", Role::Performer),
+    (Method::POST, "/crab/sequences/upload", Role::Operator),
+    (Method::POST, "/crab/inflate", Role::Operator),
+    (Method::POST, "/crab/sleep", Role::Operator),
+    (Method::POST, "/crab/fault_reset", Role::Operator),
+    (Method::POST, "/crab/acknowledge-alarms", Role::Operator),
+    (Method::POST, "/crab/plant", Role::Operator),
+    (Method::POST, "/crab/set-pressure-limits", Role::Admin),
+    (Method::POST, "/crab/set-logic-parameters", Role::Admin),
+];
+
+/// Role required for `method` on `path`
+pub fn required_role(method: &Method, path: &str) -> Role {
+    ROUTE_ROLES
+        .iter()
+        .find(|(m, p, _)| m == method && *p == path)
+        .map(|(_, _, role)| *role)
+        .unwrap_or(Role::Viewer)
+}
+
+/// Roles of API users, each one includes the permissions of the ones before
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    utoipa::ToSchema,
+    serde::Serialize,
+    serde::Deserialize,
+    juniper::GraphQLEnum,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// Read the state of the crab
+    Viewer,
+    /// Make the crab feel things
+    Performer,
+    /// Operate the crab: inflate, sleep, reset faults, acknowledge alarms, change the plant model
+    Operator,
+    /// Change the pressure limits and logic parameters
+    Admin,
+}
+
+impl std::fmt::Display for Role {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Role::Viewer => "viewer",
+            Role::Performer => "performer",
+            Role::Operator => "operator",
+            Role::Admin => "admin",
+        })
+    }
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(default)]
+pub struct AuthConfig {
+    /// Role of requests without a token
+    pub anonymous_role: Role,
+    pub users: Vec<UserConfig>,
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        Self {
+            anonymous_role: Role::Performer,
+            users: Vec::new(),
+        }
+    }
+}
+
+impl AuthConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        for (i, user) in self.users.iter().enumerate() {
+            if user.name.is_empty() {
+                return Err("auth user without a name".into());
+            }
+            if self.users[..i].iter().any(|u| u.name == user.name) {
+                return Err(format!("auth user {:?} is configured twice", user.name));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct UserConfig {
+    pub name: String,
+    pub role: Role,
+    pub token_hash: TokenHash,
+}
+
+/// Salted PBKDF2-HMAC-SHA256 hash of a token
+///
+/// Written as `pbkdf2-sha256$<iterations>$<salt hex>$<hash hex>`.
+#[derive(Clone, PartialEq, Eq, serde::Deserialize)]
+#[serde(try_from = "String")]
+pub struct TokenHash {
+    iterations: u32,
+    salt: Vec<u8>,
+    hash: [u8; 32],
+}
+
+impl TokenHash {
+    /// Hash `token` with a fresh salt
+    pub fn new(token: &str, iterations: u32) -> Self {
+        let mut salt = vec![0u8; 16];
+        getrandom::fill(&mut salt).expect("no random numbers from the operating system");
+        Self::with_salt(token, salt, iterations)
+    }
+
+    fn with_salt(token: &str, salt: Vec<u8>, iterations: u32) -> Self {
+        let hash = pbkdf2_sha256(token.as_bytes(), &salt, iterations);
+        Self {
+            iterations,
+            salt,
+            hash,
+        }
+    }
+
+    /// Check `token` against the hash in constant time
+    pub fn verify(&self, token: &str) -> bool {
+        let hash = pbkdf2_sha256(token.as_bytes(), &self.salt, self.iterations);
+        constant_time_eq(&hash, &self.hash)
+    }
+}
+
+impl std::fmt::Display for TokenHash {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "pbkdf2-sha256${}${}${}",
+            self.iterations,
+            to_hex(&self.salt),
+            to_hex(&self.hash)
+        )
+    }
+}
+
+impl std::fmt::Debug for TokenHash {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "TokenHash({self})")
+    }
+}
+
+impl std::str::FromStr for TokenHash {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            format!("invalid token hash {s:?}, expected pbkdf2-sha256$<iterations>$<salt>$<hash>")
+        };
+
+        let mut parts = s.split('$');
+        if parts.next() != Some("pbkdf2-sha256") {
+            return Err(invalid());
+        }
+        let iterations = parts
+            .next()
+            .and_then(|i| i.parse().ok())
+            .filter(|i| *i > 0)
+            .ok_or_else(invalid)?;
+        let salt = parts.next().and_then(from_hex).ok_or_else(invalid)?;
+        let hash = parts
+            .next()
+            .and_then(from_hex)
+            .and_then(|h| h.try_into().ok())
+            .ok_or_else(invalid)?;
+        if parts.next().is_some() {
+            return Err(invalid());
+        }
+
+        Ok(Self {
+            iterations,
+            salt,
+            hash,
+        })
+    }
+}
+
+impl TryFrom<String> for TokenHash {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+/// The user a request was made by
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Identity {
+    /// Name of the user, `None` for anonymous requests
+    pub user: Option<String>,
+    pub role: Role,
+}
+
+impl Identity {
+    /// Check that this user may act as `role`
+    pub fn require(&self, role: Role) -> Result<(), AuthError> {
+        if self.role >= role {
+            Ok(())
+        } else if self.user.is_none() {
+            Err(AuthError::Unauthenticated(role))
+        } else {
+            Err(AuthError::Forbidden(role))
+        }
+    }
+}
+
+/// Failed authentication or authorization of a request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthError {
+    /// The token does not belong to any user
+    InvalidToken,
+    /// Anonymous requests are not allowed to do this
+    Unauthenticated(Role),
+    /// The role of the user is not allowed to do this
+    Forbidden(Role),
+    /// Too many unknown tokens are being checked already
+    Busy,
+}
+
+impl std::fmt::Display for AuthError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AuthError::InvalidToken => write!(f, "invalid token"),
+            AuthError::Unauthenticated(role) => write!(f, "a token with role {role} is required"),
+            AuthError::Forbidden(role) => write!(f, "role {role} is required"),
+            AuthError::Busy => write!(f, "too many token checks, try again later"),
+        }
+    }
+}
+
+impl std::error::Error for AuthError {}
+
+impl IntoResponse for AuthError {
+    fn into_response(self) -> Response {
+        let status = match self {
+            AuthError::InvalidToken | AuthError::Unauthenticated(_) => StatusCode::UNAUTHORIZED,
+            AuthError::Forbidden(_) => StatusCode::FORBIDDEN,
+            AuthError::Busy => StatusCode::TOO_MANY_REQUESTS,
+        };
+        let mut response = (status, self.to_string()).into_response();
+        if status == StatusCode::UNAUTHORIZED {
+            response
+                .headers_mut()
+                .insert(header::WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+        } else if status == StatusCode::TOO_MANY_REQUESTS {
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, HeaderValue::from_static("1"));
+        }
+        response
+    }
+}
+
+/// Configured users, checks tokens and roles
+#[derive(Debug)]
+pub struct Auth {
+    anonymous_role: Role,
+    users: std::sync::Arc<[UserConfig]>,
+    /// Matching user by SHA-256 of the tokens which were already checked, so the slow hash only
+    /// runs once per token
+    checked: std::sync::Mutex<std::collections::HashMap<[u8; 32], Option<usize>>>,
+    checks: tokio::sync::Semaphore,
+}
+
+impl Auth {
+    pub fn new(config: &AuthConfig) -> Self {
+        Self {
+            anonymous_role: config.anonymous_role,
+            users: config.users.clone().into(),
+            checked: Default::default(),
+            checks: tokio::sync::Semaphore::new(MAX_CONCURRENT_CHECKS),
+        }
+    }
+
+    pub fn anonymous(&self) -> Identity {
+        Identity {
+            user: None,
+            role: self.anonymous_role,
+        }
+    }
+
+    /// Find the user with this token
+    pub async fn authenticate(&self, token: &str) -> Result<Identity, AuthError> {
+        let key: [u8; 32] = sha2::Sha256::digest(token.as_bytes()).into();
+        let cached = self.checked.lock().unwrap().get(&key).copied();
+
+        let index = match cached {
+            Some(index) => index,
+            None => {
+                let _permit = self.checks.try_acquire().map_err(|_| AuthError::Busy)?;
+                let users = self.users.clone();
+                let token = token.to_owned();
+                let index = tokio::task::spawn_blocking(move || find_user(&users, &token))
+                    .await
+                    .map_err(|_| AuthError::InvalidToken)?;
+                self.remember(key, index);
+                index
+            }
+        };
+
+        let user = &self.users[index.ok_or(AuthError::InvalidToken)?];
+        Ok(Identity {
+            user: Some(user.name.clone()),
+            role: user.role,
+        })
+    }
+
+    /// Identity for an optional token, anonymous without one
+    pub async fn identify(&self, token: Option<&str>) -> Result<Identity, AuthError> {
+        match token {
+            Some(token) => self.authenticate(token).await,
+            None => Ok(self.anonymous()),
+        }
+    }
+
+    fn remember(&self, key: [u8; 32], index: Option<usize>) {
+        let mut checked = self.checked.lock().unwrap();
+        if checked.len() >= MAX_CHECKED_TOKENS {
+            checked.retain(|_, index| index.is_some());
+        }
+        checked.insert(key, index);
+    }
+}
+
+/// Index of the user with this token, runs the slow hash for every user
+fn find_user(users: &[UserConfig], token: &str) -> Option<usize> {
+    // Check all users so the time taken does not tell which one matched
+    let mut found = None;
+    for (i, user) in users.iter().enumerate() {
+        if user.token_hash.verify(token) && found.is_none() {
+            found = Some(i);
+        }
+    }
+    found
+}
+
+/// Token from an `Authorization: Bearer` header
+fn bearer_token(request: &Request) -> Result<Option<&str>, AuthError> {
+    let Some(value) = request.headers().get(header::AUTHORIZATION) else {
+        return Ok(None);
+    };
+    value
+        .to_str()
+        .ok()
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .map(|token| Some(token.trim()))
+        .ok_or(AuthError::InvalidToken)
+}
+
+/// Middleware authenticating every request and enforcing [`ROUTE_ROLES`]
+///
+/// The [`Identity`] of the request is added to the extensions of the request and the response.
+pub async fn authenticate(
+    State(state): State<crate::AppState>,
+    request: Request,
+    next: Next,
+) -> Result<Response, AuthError> {
+    let required = required_role(request.method(), request.uri().path());
+
+    let token = bearer_token(&request)?.map(str::to_owned);
+    let mut identity = state.auth.identify(token.as_deref()).await?;
+    let mut request = request;
+    if identity.role < required && identity.user.is_none() {
+        let (token, buffered) = body_token(request).await?;
+        request = buffered;
+        if let Some(token) = token {
+            identity = state.auth.authenticate(&token).await?;
+        }
+    }
+
+    let mut response = match identity.require(required) {
+        Ok(()) => {
+            request.extensions_mut().insert(identity.clone());
+            next.run(request).await
+        }
+        Err(e) => e.into_response(),
+    };
+    // For the audit log
+    response.extensions_mut().insert(identity);
+    Ok(response)
+}
+
+/// Legacy `token` field of a JSON body, returns the request with the body buffered again
+async fn body_token(request: Request) -> Result<(Option<String>, Request), AuthError> {
+    let is_json = request
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("application/json"));
+    if !is_json {
+        return Ok((None, request));
+    }
+
+    let (parts, body) = request.into_parts();
+    let bytes = axum::body::to_bytes(body, crate::MAX_REQUEST_BODY)
+        .await
+        .map_err(|_| AuthError::InvalidToken)?;
+    let token = serde_json::from_slice::<serde_json::Value>(&bytes)
+        .ok()
+        .and_then(|v| v.get("token")?.as_str().map(str::to_owned));
+
+    Ok((token, Request::from_parts(parts, bytes.into())))
+}
+
+/// PBKDF2 (RFC 8018) with HMAC-SHA256, deriving a single block
+fn pbkdf2_sha256(password: &[u8], salt: &[u8], iterations: u32) -> [u8; 32] {
+    let mut result = [0u8; 32];
+    pbkdf2::pbkdf2::<hmac::Hmac<sha2::Sha256>>(password, salt, iterations, &mut result)
+        .expect("HMAC takes keys of any length");
+    result
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    let diff = a.iter().zip(b).fold(0u8, |acc, (a, b)| acc | (a ^ b));
+    std::hint::black_box(diff) == 0
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn from_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) || !s.is_ascii() {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pbkdf2() {
+        // RFC 7914, section 11
+        assert_eq!(
+            pbkdf2_sha256(b"passwd", b"salt", 1),
+            hex_literal::hex!("55ac046e56e3089fec1691c22544b605f94185216dde0465e68b9d57c20dacbc")
+        );
+    }
+
+    #[test]
+    fn token_hash() {
+        let hash = TokenHash::new("secret", 10);
+        assert!(hash.verify("secret"));
+        assert!(!hash.verify("Security"));
+
+        let parsed: TokenHash = hash.to_string().parse().unwrap();
+        assert_eq!(parsed, hash);
+        assert_ne!(TokenHash::new("secret", 10), hash, "salt must differ");
+
+        for bad in [
+            "",
+            "sha1$1$00$00",
+            "pbkdf2-sha256$0$00$00",
+            "pbkdf2-sha256$1$0g$00",
+            "pbkdf2-sha256$1$00$00",
+        ] {
+            assert!(bad.parse::<TokenHash>().is_err(), "{bad:?} accepted");
+        }
+    }
+
+    #[test]
+    fn roles() {
+        let user = |name: &str, role, token| UserConfig {
+            name: name.into(),
+            role,
+            token_hash: TokenHash::new(token, 1),
+        };
+        let auth = Auth::new(&AuthConfig {
+            anonymous_role: Role::Viewer,
+            users: vec![
+                user("stage", Role::Performer, "stage-token"),
+                user("orga", Role::Admin, "orga-token"),
+            ],
+        });
+
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let anonymous = auth.identify(None).await.unwrap();
+            assert_eq!(anonymous.role, Role::Viewer);
+            assert_eq!(
+                anonymous.require(Role::Performer),
+                Err(AuthError::Unauthenticated(Role::Performer))
+            );
+
+            // Twice to go through the cache
+            for _ in 0..2 {
+                let stage = auth.authenticate("stage-token").await.unwrap();
+                assert_eq!(stage.user.as_deref(), Some("stage"));
+                assert!(stage.require(Role::Performer).is_ok());
+                assert_eq!(
+                    stage.require(Role::Operator),
+                    Err(AuthError::Forbidden(Role::Operator))
+                );
+            }
+
+            let orga = auth.authenticate("orga-token").await.unwrap();
+            assert!(
+                orga.require(required_role(&Method::POST, "/crab/set-logic-parameters"))
+                    .is_ok()
+            );
+            assert_eq!(
+                auth.authenticate("Security").await,
+                Err(AuthError::InvalidToken)
+            );
+        });
+
+        assert_eq!(
+            required_role(&Method::POST, "/crab/fault_reset"),
+            Role::Operator
+        );
+        assert_eq!(required_role(&Method::POST, "/graphql"), Role::Viewer);
+
+        // Reading the emotion is open to viewers, changing it is not
+        let anonymous = auth.anonymous();
+        assert_eq!(
+            anonymous.require(required_role(&Method::GET, "/crab/emotion")),
+            Ok(())
+        );
+        assert_eq!(
+            anonymous.require(required_role(&Method::POST, "/crab/emotion")),
+            Err(AuthError::Unauthenticated(Role::Performer))
+        );
+    }
+
+    #[test]
+    fn wrong_tokens() {
+        let auth = Auth::new(&AuthConfig {
+            anonymous_role: Role::Viewer,
+            users: vec![UserConfig {
+                name: "orga".into(),
+                role: Role::Admin,
+                token_hash: TokenHash::new("orga-token", 1),
+            }],
+        });
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            assert_eq!(
+                auth.authenticate("Security").await,
+                Err(AuthError::InvalidToken)
+            );
+
+            // While the slow hash runs for other tokens, new ones are turned away
+            let permits = auth
+                .checks
+                .try_acquire_many(MAX_CONCURRENT_CHECKS as u32)
+                .unwrap();
+            assert_eq!(auth.authenticate("guess").await, Err(AuthError::Busy));
+            // Known tokens, wrong or not, do not need the hash again
+            assert_eq!(
+                auth.authenticate("Security").await,
+                Err(AuthError::InvalidToken)
+            );
+            drop(permits);
+
+            // Failed tokens make room for new ones, good ones are kept
+            auth.authenticate("orga-token").await.unwrap();
+            for i in 0..MAX_CHECKED_TOKENS {
+                let _ = auth.authenticate(&format!("guess {i}")).await;
+            }
+            assert!(auth.checked.lock().unwrap().len() <= MAX_CHECKED_TOKENS);
+            let key: [u8; 32] = sha2::Sha256::digest(b"orga-token").into();
+            assert_eq!(auth.checked.lock().unwrap().get(&key), Some(&Some(0)));
+        });
+    }
+}
@@ -1,35 +1,323 @@
-/// All emotions a rustacean can feel
+/// Name of an emotion
+///
+/// The emotions a rustacean can feel are configured together with their channel patterns, so
+/// any name is accepted here and checked against the configured ones by the [`EmotionManager`].
 #[derive(
-    Debug,
-    Default,
     Clone,
-    Copy,
     PartialEq,
     Eq,
+    PartialOrd,
+    Ord,
+    Hash,
     utoipa::ToSchema,
+    serde::Serialize,
     serde::Deserialize,
-    juniper::GraphQLEnum,
+    juniper::GraphQLScalar,
 )]
-pub enum Emotion {
-    #[default]
-    Happy,
-    Sad,
-    Surprised,
-    Angered,
-    Neutral,
+#[serde(transparent)]
+#[graphql(
+    transparent,
+    description = "Name of one of the configured emotions, as listed by the `emotions` query"
+)]
+pub struct Emotion(String);
+
+impl Emotion {
+    pub fn new(name: impl Into<String>) -> Self {
+        Self(name.into())
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// Names end up in file names and the OpenAPI schema, so only allow a safe subset
+    pub fn validate(&self) -> Result<(), String> {
+        let valid = !self.0.is_empty()
+            && self.0.len() <= 64
+            && self
+                .0
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        if !valid {
+            return Err(format!(
+                "invalid emotion name {:?}, use up to 64 letters, digits, - and _",
+                self.0
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl std::fmt::Debug for Emotion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl std::fmt::Display for Emotion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
 }
 
 type Responder<T> = tokio::sync::oneshot::Sender<T>;
 
+/// Default time an emotion without a duration is held before the crab returns to idle
 pub const EMOTION_RESET_TIMER_SECS: u64 = 60;
 
-#[derive(Default, Clone, Debug)]
+/// Longest duration a single request may ask for
+pub const MAX_EMOTION_DURATION: std::time::Duration = std::time::Duration::from_secs(3600);
+
+/// Behaviour of the emotion queue, from the `[emotion]` section of the configuration
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EmotionConfig {
+    /// Emotion shown while nothing is queued
+    pub idle: Emotion,
+    /// File with the channel patterns of the emotions, the built-in ones are used when it does
+    /// not exist
+    pub patterns: std::path::PathBuf,
+    /// Time an emotion without a duration is held unless something else is queued
+    pub hold_s: f64,
+    /// Number of requests waiting behind the current one
+    pub max_queue: usize,
+}
+
+impl Default for EmotionConfig {
+    fn default() -> Self {
+        Self {
+            idle: Emotion::new("Happy"),
+            patterns: "emotions.toml".into(),
+            hold_s: EMOTION_RESET_TIMER_SECS as f64,
+            max_queue: 16,
+        }
+    }
+}
+
+impl EmotionConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if !(self.hold_s > 0. && self.hold_s <= MAX_EMOTION_DURATION.as_secs_f64()) {
+            return Err(format!(
+                "emotion.hold_s = {} must be positive and at most {} s",
+                self.hold_s,
+                MAX_EMOTION_DURATION.as_secs()
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum EmotionError {
+    /// Not one of the configured emotions
+    Unknown(Emotion),
+    QueueFull,
+    InvalidDuration(f64),
+    InvalidPriority(i64),
+    /// The emotion manager is not running
+    Unavailable,
+}
+
+impl std::fmt::Display for EmotionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EmotionError::Unknown(emotion) => write!(f, "unknown emotion {emotion:?}"),
+            EmotionError::QueueFull => write!(f, "too many emotions queued, try again later"),
+            EmotionError::InvalidDuration(d) => write!(
+                f,
+                "duration {d} s is out of range, must be positive and at most {} s",
+                MAX_EMOTION_DURATION.as_secs()
+            ),
+            EmotionError::InvalidPriority(p) => {
+                write!(f, "priority {p} is out of range, must be 0 to 255")
+            }
+            EmotionError::Unavailable => write!(f, "the crab is not listening to emotions"),
+        }
+    }
+}
+
+impl std::error::Error for EmotionError {}
+
+/// Request to show an emotion
+#[derive(Debug, Clone, PartialEq)]
+pub struct EmotionRequest {
+    pub emotion: Emotion,
+    /// Time the emotion is shown, without one it is held until something else is queued
+    pub duration: Option<std::time::Duration>,
+    /// Requests with a higher priority interrupt the current emotion, equal ones queue up
+    pub priority: u8,
+}
+
+impl EmotionRequest {
+    pub fn new(emotion: Emotion) -> Self {
+        Self {
+            emotion,
+            duration: None,
+            priority: 0,
+        }
+    }
+
+    /// Request with the duration and priority as they come from the APIs
+    pub fn from_api(
+        emotion: Emotion,
+        duration_s: Option<f64>,
+        priority: Option<i64>,
+    ) -> Result<Self, EmotionError> {
+        let duration = duration_s
+            .map(|s| {
+                std::time::Duration::try_from_secs_f64(s)
+                    .ok()
+                    .filter(|d| !d.is_zero() && *d <= MAX_EMOTION_DURATION)
+                    .ok_or(EmotionError::InvalidDuration(s))
+            })
+            .transpose()?;
+        let priority = priority
+            .map(|p| u8::try_from(p).map_err(|_| EmotionError::InvalidPriority(p)))
+            .transpose()?
+            .unwrap_or(0);
+        Ok(Self {
+            emotion,
+            duration,
+            priority,
+        })
+    }
+}
+
+#[derive(Debug)]
+struct Playing {
+    request: EmotionRequest,
+    until: std::time::Instant,
+}
+
+/// Order in which the requested emotions are shown
+///
+/// Requests are played one after another, higher priorities first.  A request with a higher
+/// priority than the current one interrupts it, the interrupted one continues afterwards with
+/// its remaining time.  Requests without a duration are held until another request of at least
+/// their priority is waiting, or for `hold_s` at most; an interrupted one is held again
+/// afterwards.  The interrupted request counts against `max_queue` like any other waiting one.
+/// When the queue drains the idle emotion is shown.
+#[derive(Debug)]
+pub struct EmotionQueue {
+    config: EmotionConfig,
+    emotions: Vec<Emotion>,
+    playing: Option<Playing>,
+    waiting: std::collections::VecDeque<EmotionRequest>,
+}
+
+impl EmotionQueue {
+    /// Queue accepting only the configured `emotions`
+    pub fn new(config: EmotionConfig, emotions: Vec<Emotion>) -> Self {
+        Self {
+            config,
+            emotions,
+            playing: None,
+            waiting: Default::default(),
+        }
+    }
+
+    /// Emotion to show right now
+    pub fn current(&self) -> Emotion {
+        self.playing
+            .as_ref()
+            .map(|p| p.request.emotion.clone())
+            .unwrap_or_else(|| self.config.idle.clone())
+    }
+
+    /// Requests waiting behind the current one, in the order they are played
+    pub fn waiting(&self) -> impl Iterator<Item = &EmotionRequest> {
+        self.waiting.iter()
+    }
+
+    pub fn push(
+        &mut self,
+        now: std::time::Instant,
+        request: EmotionRequest,
+    ) -> Result<(), EmotionError> {
+        if !self.emotions.contains(&request.emotion) {
+            return Err(EmotionError::Unknown(request.emotion));
+        }
+        self.update(now);
+
+        if let Some(playing) = &self.playing {
+            // Either the request or the interrupted one has to wait
+            if self.waiting.len() >= self.config.max_queue {
+                return Err(EmotionError::QueueFull);
+            }
+            if request.priority > playing.request.priority {
+                let interrupted = self.playing.take().unwrap();
+                // Continue where it was interrupted, before anything of the same priority
+                let remaining = interrupted.until.saturating_duration_since(now);
+                let remaining = EmotionRequest {
+                    duration: interrupted.request.duration.map(|d| remaining.min(d)),
+                    ..interrupted.request
+                };
+                self.enqueue(remaining, true);
+            }
+        }
+
+        if self.playing.is_none() {
+            self.start(now, request);
+        } else {
+            self.enqueue(request, false);
+        }
+        self.update(now);
+        Ok(())
+    }
+
+    /// Advance to `now` and return when the current emotion ends
+    pub fn update(&mut self, now: std::time::Instant) -> Option<std::time::Instant> {
+        loop {
+            let done = match &self.playing {
+                Some(p) if p.until <= now => true,
+                Some(p) if p.request.duration.is_none() => self
+                    .waiting
+                    .front()
+                    .is_some_and(|next| next.priority >= p.request.priority),
+                Some(_) => false,
+                None => !self.waiting.is_empty(),
+            };
+            if !done {
+                return self.playing.as_ref().map(|p| p.until);
+            }
+            self.playing = None;
+            if let Some(next) = self.waiting.pop_front() {
+                self.start(now, next);
+            }
+        }
+    }
+
+    fn start(&mut self, now: std::time::Instant, request: EmotionRequest) {
+        let duration = request
+            .duration
+            .unwrap_or(std::time::Duration::from_secs_f64(self.config.hold_s));
+        self.playing = Some(Playing {
+            request,
+            until: now + duration,
+        });
+    }
+
+    /// Insert behind all requests of a higher priority, and behind the ones of the same priority
+    /// unless it goes `first`
+    fn enqueue(&mut self, request: EmotionRequest, first: bool) {
+        let position = self
+            .waiting
+            .iter()
+            .position(|r| {
+                r.priority < request.priority || (first && r.priority == request.priority)
+            })
+            .unwrap_or(self.waiting.len());
+        self.waiting.insert(position, request);
+    }
+}
+
+#[derive(Clone, Debug)]
 pub struct EmotionContainer(std::sync::Arc<tokio::sync::Mutex<Emotion>>);
 
 #[allow(dead_code)]
 impl EmotionContainer {
-    pub fn new() -> Self {
-        Default::default()
+    pub fn new(emotion: Emotion) -> Self {
+        Self(std::sync::Arc::new(tokio::sync::Mutex::new(emotion)))
     }
 
     pub fn blocking_set(&mut self, emotion: Emotion) {
@@ -41,11 +329,11 @@ impl EmotionContainer {
     }
 
     pub fn blocking_get(&self) -> Emotion {
-        *self.0.blocking_lock()
+        self.0.blocking_lock().clone()
     }
 
     pub async fn get(&self) -> Emotion {
-        *self.0.lock().await
+        self.0.lock().await.clone()
     }
 }
 
@@ -56,43 +344,202 @@ pub enum EmotionCommand {
         resp: Responder<Emotion>,
     },
     Set {
-        emotion: Emotion,
-        resp: Responder<()>,
+        request: EmotionRequest,
+        resp: Responder<Result<(), EmotionError>>,
     },
 }
 
 #[derive(Debug)]
 pub struct EmotionManager {
     pub emotion: EmotionContainer,
+    queue: EmotionQueue,
     rx: tokio::sync::mpsc::Receiver<EmotionCommand>,
 }
 
 impl EmotionManager {
-    pub fn new(emotion: EmotionContainer, rx: tokio::sync::mpsc::Receiver<EmotionCommand>) -> Self {
-        Self { emotion, rx }
+    pub fn new(
+        emotion: EmotionContainer,
+        config: EmotionConfig,
+        emotions: Vec<Emotion>,
+        rx: tokio::sync::mpsc::Receiver<EmotionCommand>,
+    ) -> Self {
+        Self {
+            emotion,
+            queue: EmotionQueue::new(config, emotions),
+            rx,
+        }
     }
 
     pub fn run(mut self) -> tokio::task::JoinHandle<()> {
         tokio::task::spawn(async move {
             loop {
+                let until = self.queue.update(std::time::Instant::now());
+                self.emotion.set(self.queue.current()).await;
+                let timeout = async {
+                    match until {
+                        Some(until) => {
+                            tokio::time::sleep_until(tokio::time::Instant::from_std(until)).await
+                        }
+                        None => std::future::pending().await,
+                    }
+                };
+
                 tokio::select! {
                     val = self.rx.recv() => {
                         match val {
                             Some(EmotionCommand::Get { resp }) => {
-                                let _ = resp.send(self.emotion.get().await);
+                                let _ = resp.send(self.queue.current());
                             }
-                            Some(EmotionCommand::Set { emotion, resp }) => {
-                                self.emotion.set(emotion).await;
-                                let _ = resp.send(());
+                            Some(EmotionCommand::Set { request, resp }) => {
+                                let result = self.queue.push(std::time::Instant::now(), request);
+                                self.emotion.set(self.queue.current()).await;
+                                let _ = resp.send(result);
                             }
                             None => return,
                         }
                     },
-                    _ = tokio::time::sleep(std::time::Duration::from_secs(EMOTION_RESET_TIMER_SECS)) => {
-                        self.emotion.set(Emotion::default()).await;
-                    }
+                    _ = timeout => {}
                 }
             }
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    fn timed(emotion: Emotion, secs: u64, priority: u8) -> EmotionRequest {
+        EmotionRequest {
+            emotion,
+            duration: Some(Duration::from_secs(secs)),
+            priority,
+        }
+    }
+
+    fn emotion_queue(config: EmotionConfig) -> EmotionQueue {
+        let emotions = ["Happy", "Sad", "Surprised", "Angered", "Neutral"];
+        EmotionQueue::new(config, emotions.into_iter().map(Emotion::new).collect())
+    }
+
+    #[test]
+    fn plays_in_order() {
+        let t0 = Instant::now();
+        let s = |secs| t0 + Duration::from_secs(secs);
+        let mut queue = emotion_queue(EmotionConfig {
+            idle: Emotion::new("Neutral"),
+            ..Default::default()
+        });
+        assert_eq!(queue.update(t0), None);
+        assert_eq!(queue.current(), Emotion::new("Neutral"));
+
+        queue.push(t0, timed(Emotion::new("Happy"), 10, 0)).unwrap();
+        queue.push(t0, timed(Emotion::new("Sad"), 5, 0)).unwrap();
+        // Higher priorities skip ahead of the waiting ones
+        queue
+            .push(s(1), timed(Emotion::new("Surprised"), 5, 0))
+            .unwrap();
+        queue
+            .push(s(1), timed(Emotion::new("Angered"), 5, 1))
+            .unwrap();
+
+        // Angered interrupted Happy, which continues afterwards with its remaining 9 s
+        assert_eq!(queue.current(), Emotion::new("Angered"));
+        assert_eq!(queue.update(s(2)), Some(s(6)));
+        assert_eq!(queue.update(s(6)), Some(s(15)));
+        assert_eq!(queue.current(), Emotion::new("Happy"));
+        assert_eq!(queue.update(s(15)), Some(s(20)));
+        assert_eq!(queue.current(), Emotion::new("Sad"));
+        assert_eq!(queue.update(s(20)), Some(s(25)));
+        assert_eq!(queue.current(), Emotion::new("Surprised"));
+        assert_eq!(queue.update(s(25)), None);
+        assert_eq!(queue.current(), Emotion::new("Neutral"));
+    }
+
+    #[test]
+    fn held_until_replaced() {
+        let t0 = Instant::now();
+        let s = |secs| t0 + Duration::from_secs(secs);
+        let mut queue = emotion_queue(EmotionConfig::default());
+
+        queue
+            .push(t0, EmotionRequest::new(Emotion::new("Sad")))
+            .unwrap();
+        assert_eq!(queue.update(s(30)), Some(s(EMOTION_RESET_TIMER_SECS)));
+        queue
+            .push(s(30), EmotionRequest::new(Emotion::new("Angered")))
+            .unwrap();
+        assert_eq!(queue.current(), Emotion::new("Angered"));
+
+        // Not replaced by lower priorities, which wait for it to time out
+        queue
+            .push(
+                s(31),
+                EmotionRequest::from_api(Emotion::new("Sad"), None, Some(2)).unwrap(),
+            )
+            .unwrap();
+        queue
+            .push(s(32), timed(Emotion::new("Surprised"), 5, 1))
+            .unwrap();
+        assert_eq!(queue.current(), Emotion::new("Sad"));
+        queue.update(s(31 + EMOTION_RESET_TIMER_SECS));
+        assert_eq!(queue.current(), Emotion::new("Surprised"));
+
+        // The interrupted Angered is held again afterwards
+        queue.update(s(40 + EMOTION_RESET_TIMER_SECS));
+        assert_eq!(queue.current(), Emotion::new("Angered"));
+        queue.update(s(40 + 2 * EMOTION_RESET_TIMER_SECS));
+        assert_eq!(queue.current(), Emotion::new("Happy"));
+    }
+
+    #[test]
+    fn limits() {
+        let t0 = Instant::now();
+        let mut queue = emotion_queue(EmotionConfig {
+            max_queue: 1,
+            ..Default::default()
+        });
+        queue.push(t0, timed(Emotion::new("Sad"), 5, 0)).unwrap();
+        queue.push(t0, timed(Emotion::new("Sad"), 5, 0)).unwrap();
+        assert_eq!(
+            queue.push(t0, timed(Emotion::new("Sad"), 5, 0)),
+            Err(EmotionError::QueueFull)
+        );
+        assert_eq!(queue.waiting().count(), 1);
+        // Interrupting would queue the current one, which does not fit either
+        assert_eq!(
+            queue.push(t0, timed(Emotion::new("Angered"), 5, 1)),
+            Err(EmotionError::QueueFull)
+        );
+        assert_eq!(queue.current(), Emotion::new("Sad"));
+        assert_eq!(
+            queue.push(t0, timed(Emotion::new("Sleepy"), 5, 9)),
+            Err(EmotionError::Unknown(Emotion::new("Sleepy")))
+        );
+        assert!(Emotion::new("Wink_2").validate().is_ok());
+        for name in ["", "../wink", "so happy"] {
+            assert!(Emotion::new(name).validate().is_err());
+        }
+
+        for (duration_s, priority) in [(Some(0.), None), (Some(-1.), None), (Some(f64::NAN), None)]
+            .into_iter()
+            .chain([(Some(3601.), None), (None, Some(-1)), (None, Some(256))])
+        {
+            assert!(EmotionRequest::from_api(Emotion::new("Happy"), duration_s, priority).is_err());
+        }
+        let request =
+            EmotionRequest::from_api(Emotion::new("Happy"), Some(2.5), Some(255)).unwrap();
+        assert_eq!(request.duration, Some(Duration::from_millis(2500)));
+        assert_eq!(request.priority, 255);
+
+        for hold_s in [0., f64::NAN, f64::INFINITY, 1e300] {
+            let config = EmotionConfig {
+                hold_s,
+                ..Default::default()
+            };
+            assert!(config.validate().is_err(), "hold_s = {hold_s} accepted");
+        }
+        assert!(EmotionConfig::default().validate().is_ok());
+    }
+}
@@ -0,0 +1,376 @@
+//! Server-Sent Events stream of the crab state at `GET /crab/events`
+//!
+//! The main loop hands every new [`CrabStatus`] and [`AlarmsSnapshot`] to the [`EventBus`], which
+//! turns the changes into typed [`CrabEvent`]s.  Event IDs keep increasing across restarts, so a
+//! client reconnecting with `Last-Event-ID` gets exactly the events it missed.  When these are not
+//! available anymore, it gets the whole state as a `status` event instead.
+
+use crate::alarms::{AlarmEvent, AlarmEventKind, AlarmsSnapshot};
+use crate::status::{CrabStatus, EmotionStatus};
+use futures::StreamExt as _;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Number of events kept for clients which reconnect
+const RECENT_EVENTS: usize = 1000;
+
+/// Number of events buffered for slow clients before they have to reconnect
+const LIVE_CAPACITY: usize = 256;
+
+/// Minimum time between two pressure events
+const PRESSURE_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, PartialEq, utoipa::ToSchema, serde::Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum CrabEvent {
+    /// Whole state of the crab, sent first unless missed events are replayed
+    Status(CrabStatus),
+    Emotion(EmotionStatus),
+    Channels {
+        channels: std::collections::BTreeMap<String, bool>,
+    },
+    Fan {
+        running: bool,
+    },
+    Alarm(AlarmEvent),
+    /// Sampled at most once per second, and only when the pressure changed
+    Pressure {
+        pressure_mbar: Option<f64>,
+    },
+}
+
+impl CrabEvent {
+    /// Name of the SSE event, equal to the `type` field
+    pub fn name(&self) -> &'static str {
+        match self {
+            CrabEvent::Status(_) => "status",
+            CrabEvent::Emotion(_) => "emotion",
+            CrabEvent::Channels { .. } => "channels",
+            CrabEvent::Fan { .. } => "fan",
+            CrabEvent::Alarm(_) => "alarm",
+            CrabEvent::Pressure { .. } => "pressure",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct StampedEvent {
+    pub id: u64,
+    pub event: CrabEvent,
+}
+
+impl StampedEvent {
+    pub fn to_sse(&self) -> axum::response::sse::Event {
+        axum::response::sse::Event::default()
+            .id(self.id.to_string())
+            .event(self.event.name())
+            .json_data(&self.event)
+            .unwrap()
+    }
+}
+
+/// Turns state changes into events and distributes them to the clients
+#[derive(Debug)]
+pub struct EventBus {
+    inner: std::sync::Mutex<EventBusInner>,
+    live: tokio::sync::broadcast::Sender<Arc<StampedEvent>>,
+}
+
+#[derive(Debug)]
+struct EventBusInner {
+    next_id: u64,
+    recent: std::collections::VecDeque<Arc<StampedEvent>>,
+    status: Option<CrabStatus>,
+    alarms: Option<AlarmsSnapshot>,
+    last_pressure: Option<(Instant, Option<f64>)>,
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        // Start at the current time so the IDs keep increasing when the controller restarts
+        let boot = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default();
+        Self::starting_at(boot.as_micros() as u64)
+    }
+
+    fn starting_at(first_id: u64) -> Self {
+        Self {
+            inner: std::sync::Mutex::new(EventBusInner {
+                next_id: first_id,
+                recent: Default::default(),
+                status: None,
+                alarms: None,
+                last_pressure: None,
+            }),
+            live: tokio::sync::broadcast::Sender::new(LIVE_CAPACITY),
+        }
+    }
+
+    /// Publish the changes since the previous status
+    pub fn update_status(&self, now: Instant, status: &CrabStatus) {
+        let mut inner = self.inner.lock().unwrap();
+
+        if let Some(previous) = &inner.status {
+            let mut events = Vec::new();
+            if previous.emotion != status.emotion || previous.sleeping != status.sleeping {
+                events.push(CrabEvent::Emotion(EmotionStatus {
+                    emotion: status.emotion.clone(),
+                    sleeping: status.sleeping,
+                }));
+            }
+            if previous.channels != status.channels {
+                events.push(CrabEvent::Channels {
+                    channels: status.channels.clone(),
+                });
+            }
+            if previous.run_fan != status.run_fan {
+                events.push(CrabEvent::Fan {
+                    running: status.run_fan,
+                });
+            }
+            for event in events {
+                inner.publish(&self.live, event);
+            }
+        }
+
+        let pressure_mbar = status.pressure.pressure_mbar;
+        let sample = match inner.last_pressure {
+            Some((t, last)) => last != pressure_mbar && now - t >= PRESSURE_INTERVAL,
+            None => true,
+        };
+        if sample {
+            inner.last_pressure = Some((now, pressure_mbar));
+            inner.publish(&self.live, CrabEvent::Pressure { pressure_mbar });
+        }
+
+        if inner.status.as_ref() != Some(status) {
+            inner.status = Some(status.clone());
+        }
+    }
+
+    /// Publish the alarms which were raised, cleared or acknowledged since the previous snapshot
+    pub fn update_alarms(&self, wall_clock: std::time::SystemTime, alarms: &AlarmsSnapshot) {
+        let time = wall_clock
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64();
+        let mut inner = self.inner.lock().unwrap();
+
+        if let Some(previous) = &inner.alarms {
+            let mut events = Vec::new();
+            for alarm in alarms.alarms.iter() {
+                let Some(before) = previous.alarms.iter().find(|a| a.id == alarm.id) else {
+                    continue;
+                };
+                let kind = if alarm.active && !before.active {
+                    AlarmEventKind::Raised
+                } else if !alarm.active && before.active {
+                    AlarmEventKind::Cleared
+                } else if alarm.acknowledged && !before.acknowledged {
+                    AlarmEventKind::Acknowledged
+                } else {
+                    continue;
+                };
+                events.push(CrabEvent::Alarm(AlarmEvent {
+                    time,
+                    id: alarm.id.clone(),
+                    kind,
+                }));
+            }
+            for event in events {
+                inner.publish(&self.live, event);
+            }
+        }
+
+        inner.alarms = Some(alarms.clone());
+    }
+
+    /// Events after `last_event_id` followed by all future events
+    ///
+    /// Starts with the whole state when there is no `last_event_id` or the events after it are not
+    /// available anymore.  The stream ends when the client cannot keep up, so it reconnects.
+    pub fn subscribe(
+        &self,
+        last_event_id: Option<u64>,
+    ) -> impl futures::Stream<Item = Arc<StampedEvent>> + Send + use<> {
+        let inner = self.inner.lock().unwrap();
+        // Subscribe while holding the lock so no event falls in between the replay and the stream
+        let receiver = self.live.subscribe();
+
+        let latest = inner.next_id - 1;
+        let oldest = inner.recent.front().map(|e| e.id).unwrap_or(inner.next_id);
+        let replay: Vec<_> = match last_event_id {
+            Some(last) if last <= latest && last + 1 >= oldest => inner
+                .recent
+                .iter()
+                .filter(|e| e.id > last)
+                .cloned()
+                .collect(),
+            _ => inner
+                .status
+                .iter()
+                .map(|status| {
+                    Arc::new(StampedEvent {
+                        id: latest,
+                        event: CrabEvent::Status(status.clone()),
+                    })
+                })
+                .collect(),
+        };
+        drop(inner);
+
+        let live = futures::stream::unfold(receiver, |mut receiver| async move {
+            receiver.recv().await.ok().map(|event| (event, receiver))
+        });
+        futures::stream::iter(replay).chain(live)
+    }
+}
+
+impl EventBusInner {
+    fn publish(
+        &mut self,
+        live: &tokio::sync::broadcast::Sender<Arc<StampedEvent>>,
+        event: CrabEvent,
+    ) {
+        let event = Arc::new(StampedEvent {
+            id: self.next_id,
+            event,
+        });
+        self.next_id += 1;
+
+        if self.recent.len() == RECENT_EVENTS {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(event.clone());
+        // Nobody listening is fine
+        let _ = live.send(event);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::FutureExt as _;
+
+    fn drain(stream: &mut (impl futures::Stream<Item = Arc<StampedEvent>> + Unpin)) -> Vec<u64> {
+        let mut ids = Vec::new();
+        while let Some(Some(event)) = stream.next().now_or_never() {
+            ids.push(event.id);
+        }
+        ids
+    }
+
+    #[test]
+    fn status_changes() {
+        let bus = EventBus::starting_at(1);
+        let t0 = Instant::now();
+        let mut status = CrabStatus::default();
+        bus.update_status(t0, &status);
+
+        let mut stream = Box::pin(bus.subscribe(None));
+        let first = stream.next().now_or_never().unwrap().unwrap();
+        assert_eq!(first.event.name(), "status");
+
+        status.run_fan = true;
+        status.channels.insert("eyes".into(), true);
+        status.pressure.pressure_mbar = Some(0.3);
+        bus.update_status(t0 + Duration::from_millis(50), &status);
+        // Pressure only after the interval
+        bus.update_status(t0 + Duration::from_secs(1), &status);
+
+        let events: Vec<_> = std::iter::from_fn(|| stream.next().now_or_never().flatten())
+            .map(|e| e.event.clone())
+            .collect();
+        assert_eq!(
+            events,
+            [
+                CrabEvent::Channels {
+                    channels: status.channels.clone()
+                },
+                CrabEvent::Fan { running: true },
+                CrabEvent::Pressure {
+                    pressure_mbar: Some(0.3)
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn alarm_changes() {
+        let bus = EventBus::starting_at(1);
+        let alarm = |active, acknowledged| crate::alarms::AlarmInfo {
+            id: "emergency_stop".into(),
+            description: String::new(),
+            severity: crate::alarms::AlarmSeverity::Fault,
+            latching: true,
+            active,
+            acknowledged,
+            first_out: false,
+            raised_at: None,
+            cleared_at: None,
+            acknowledged_at: None,
+        };
+        let snapshot = |active, acknowledged| AlarmsSnapshot {
+            alarms: vec![alarm(active, acknowledged)],
+            history: Vec::new(),
+        };
+
+        let mut stream = Box::pin(bus.subscribe(None));
+        let now = std::time::SystemTime::now();
+        for (active, acknowledged) in [(false, false), (true, false), (true, true), (false, true)] {
+            bus.update_alarms(now, &snapshot(active, acknowledged));
+        }
+        let kinds: Vec<_> = std::iter::from_fn(|| stream.next().now_or_never().flatten())
+            .map(|e| match &e.event {
+                CrabEvent::Alarm(a) => a.kind,
+                e => panic!("unexpected {e:?}"),
+            })
+            .collect();
+        assert_eq!(
+            kinds,
+            [
+                AlarmEventKind::Raised,
+                AlarmEventKind::Acknowledged,
+                AlarmEventKind::Cleared
+            ]
+        );
+    }
+
+    #[test]
+    fn reconnect() {
+        let bus = EventBus::starting_at(100);
+        let t0 = Instant::now();
+        let mut status = CrabStatus::default();
+        bus.update_status(t0, &status);
+        for i in 0..3 {
+            status.run_fan = i % 2 == 0;
+            bus.update_status(t0, &status);
+        }
+        // Pressure event 100, then fan events 101 to 103
+
+        let mut stream = Box::pin(bus.subscribe(Some(101)));
+        assert_eq!(drain(&mut stream), [102, 103]);
+        status.run_fan = !status.run_fan;
+        bus.update_status(t0, &status);
+        assert_eq!(drain(&mut stream), [104]);
+
+        // Nothing missed
+        let mut stream = Box::pin(bus.subscribe(Some(104)));
+        assert!(drain(&mut stream).is_empty());
+
+        // Events from before the ones we still have, or from somewhere else
+        for last in [Some(50), Some(1000), None] {
+            let mut stream = Box::pin(bus.subscribe(last));
+            let event = stream.next().now_or_never().unwrap().unwrap();
+            assert_eq!(event.id, 104);
+            assert_eq!(event.event, CrabEvent::Status(status.clone()));
+        }
+    }
+}
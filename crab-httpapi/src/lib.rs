@@ -7,22 +7,64 @@ use axum::{
 };
 use utoipa::OpenApi;
 
+pub mod alarms;
+pub mod audit;
+pub mod auth;
 pub mod emotionmanager;
+pub mod events;
+pub mod mirror;
+pub mod parameters;
+pub mod plant;
+pub mod sentiment;
+pub mod sequences;
+pub mod status;
+use alarms::AlarmsSnapshot;
 use emotionmanager::Emotion;
+use parameters::{LogicParameters, LogicParametersUpdate, PressureLimitsUpdate};
+use plant::PlantUpdate;
 
 const BIND_ADDR: &str = "0.0.0.0:8080";
 
+/// Largest request body the middlewares buffer, the default limit of the axum extractors
+const MAX_REQUEST_BODY: usize = 2 * 1024 * 1024;
+
 #[derive(utoipa::OpenApi)]
-#[openapi(info(
-    title = "Crab Emotion API",
-    version = "0.1.0",
-    description = "Make the crab feel things"
-))]
+#[openapi(
+    info(
+        title = "Crab Emotion API",
+        version = "0.1.0",
+        description = "Make the crab feel things"
+    ),
+    modifiers(&BearerAuth),
+    security(("bearer" = []))
+)]
 struct ApiDoc;
 
+/// Tokens are sent as `Authorization: Bearer` header, see [`auth`]
+struct BearerAuth;
+
+impl utoipa::Modify for BearerAuth {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        use utoipa::openapi::security::{HttpAuthScheme, HttpBuilder, SecurityScheme};
+
+        openapi
+            .components
+            .get_or_insert_with(Default::default)
+            .add_security_scheme(
+                "bearer",
+                SecurityScheme::Http(HttpBuilder::new().scheme(HttpAuthScheme::Bearer).build()),
+            );
+    }
+}
+
 #[derive(Debug, utoipa::ToSchema, serde::Deserialize)]
 struct ApiEmotionMessage {
     emotion: Emotion,
+    /// Seconds to show the emotion, without it the emotion is held until the next one is queued
+    duration_s: Option<f64>,
+    /// Higher priorities interrupt the current emotion, equal ones queue up behind it
+    #[schema(minimum = 0, maximum = 255)]
+    priority: Option<i64>,
 }
 
 impl std::fmt::Display for ApiEmotionMessage {
@@ -36,52 +78,109 @@ impl std::fmt::Display for ApiEmotionMessage {
     summary = "Crab Emotion API",
     request_body = ApiEmotionMessage,
     responses(
-        (status = 200, description = "Success!", body = ())
+        (status = 200, description = "Success!", body = ()),
+        (status = 400, description = "Unknown emotion, or duration or priority are out of range", body = String),
+        (status = 429, description = "Too many emotions are queued", body = String),
 ))]
 async fn post_emotion(
     State(state): State<AppState>,
     Json(payload): Json<ApiEmotionMessage>,
-) -> impl IntoResponse {
-    match send_emotion_to_crab(state.emotion_ch_tx.clone(), payload.emotion).await {
-        Ok(status) => status,
-        Err(e) => e,
-    }
+) -> Result<StatusCode, (StatusCode, String)> {
+    let request = emotionmanager::EmotionRequest::from_api(
+        payload.emotion,
+        payload.duration_s,
+        payload.priority,
+    )
+    .map_err(emotion_error_response)?;
+    send_emotion_to_crab(state.emotion_ch_tx.clone(), request)
+        .await
+        .map_err(emotion_error_response)?;
+    Ok(StatusCode::OK)
+}
+
+fn emotion_error_response(e: emotionmanager::EmotionError) -> (StatusCode, String) {
+    use emotionmanager::EmotionError;
+
+    let status = match e {
+        EmotionError::QueueFull => StatusCode::TOO_MANY_REQUESTS,
+        EmotionError::Unknown(_)
+        | EmotionError::InvalidDuration(_)
+        | EmotionError::InvalidPriority(_) => StatusCode::BAD_REQUEST,
+        EmotionError::Unavailable => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    (status, e.to_string())
 }
 
-async fn send_emotion_to_crab(
+/// Queue an emotion and wait until the emotion manager took it
+pub async fn send_emotion_to_crab(
     emotion_ch_tx: tokio::sync::mpsc::Sender<emotionmanager::EmotionCommand>,
-    emotion: Emotion,
-) -> Result<StatusCode, StatusCode> {
+    request: emotionmanager::EmotionRequest,
+) -> Result<(), emotionmanager::EmotionError> {
     let (tx, rx) = tokio::sync::oneshot::channel();
     let set_emotion_result = emotion_ch_tx
-        .send(emotionmanager::EmotionCommand::Set { emotion, resp: tx })
+        .send(emotionmanager::EmotionCommand::Set { request, resp: tx })
         .await;
 
-    if let Err(_) = set_emotion_result {
+    if set_emotion_result.is_err() {
         println!("Error sending emotion to crab");
-        return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        return Err(emotionmanager::EmotionError::Unavailable);
     }
 
-    // Wait for the emotion to be set
-    if let Err(_) = rx.await {
-        println!("Error waiting for emotion to be set");
-        return Err(StatusCode::INTERNAL_SERVER_ERROR);
+    // Wait for the emotion to be queued
+    match rx.await {
+        Ok(result) => result,
+        Err(_) => {
+            println!("Error waiting for emotion to be set");
+            Err(emotionmanager::EmotionError::Unavailable)
+        }
     }
+}
 
-    Ok(StatusCode::OK)
+#[utoipa::path(get,
+    path = "/crab/emotions",
+    summary = "Get the names of all configured emotions",
+    responses(
+        (status = 200, description = "Success!", body = Vec<Emotion>),
+))]
+async fn get_emotions(State(state): State<AppState>) -> Json<Vec<Emotion>> {
+    Json(state.emotions.to_vec())
 }
 
-async fn text_to_emotion(text: &str) -> Emotion {
-    let text = text.to_lowercase();
-    if text.contains("rust") || text.contains("rs") {
-        Emotion::Happy
-    } else if text.contains("golang") || text.contains("go") {
-        Emotion::Angered
-    } else if text.contains("cobol") {
-        Emotion::Surprised
-    } else {
-        Emotion::Neutral
-    }
+#[utoipa::path(get,
+    path = "/crab/emotion",
+    summary = "Get the current emotion of the crab",
+    responses(
+        (status = 200, description = "Success!", body = status::EmotionStatus),
+))]
+async fn get_emotion(State(state): State<AppState>) -> Json<status::EmotionStatus> {
+    let status = state.status.borrow();
+    Json(status::EmotionStatus {
+        emotion: status.emotion.clone(),
+        sleeping: status.sleeping,
+    })
+}
+
+/// Longest message the crab says, in syllables
+pub const MAX_TALK_SYLLABLES: i32 = 100;
+
+/// Rough number of syllables in `text`, counting the groups of vowels of every word
+fn syllables(text: &str) -> i32 {
+    let is_vowel = |c: char| "aeiouyäöü".contains(c.to_ascii_lowercase());
+    let count: usize = text
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(|word| {
+            let mut groups = 0;
+            let mut last = false;
+            for c in word.chars() {
+                let vowel = is_vowel(c);
+                groups += usize::from(vowel && !last);
+                last = vowel;
+            }
+            groups.max(1)
+        })
+        .sum();
+    count.clamp(1, MAX_TALK_SYLLABLES as usize) as i32
 }
 
 #[derive(utoipa::ToSchema, serde::Deserialize)]
@@ -94,30 +193,34 @@ struct ApiTalkMessage {
     summary = "Talk to the crab!",
     request_body = ApiTalkMessage,
     responses(
-        (status = 200, description = "Success!", body = ())
+        (status = 200, description = "The emotion the crab felt", body = sentiment::Sentiment),
+        (status = 429, description = "Too many emotions are queued", body = String),
 ))]
 async fn post_crab_talk(
     State(state): State<AppState>,
     Json(payload): Json<ApiTalkMessage>,
-) -> impl IntoResponse {
-    // TODO: Figure out what makes crabs feel things
-
+) -> Result<Json<sentiment::Sentiment>, (StatusCode, String)> {
     let text = payload.message;
-    let emotion = text_to_emotion(&text).await;
-
-    match send_emotion_to_crab(state.emotion_ch_tx.clone(), emotion).await {
-        Ok(status) => status,
-        Err(e) => e,
-    }
+    let sentiment = state.sentiment.analyze(&text).await.map_err(|e| {
+        log::error!("{e}");
+        (StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+    })?;
+
+    let request = emotionmanager::EmotionRequest::new(sentiment.emotion.clone());
+    send_emotion_to_crab(state.emotion_ch_tx.clone(), request)
+        .await
+        .map_err(emotion_error_response)?;
+    state.talk(syllables(&text));
+    Ok(Json(sentiment))
 }
 
 #[derive(utoipa::ToSchema, serde::Deserialize)]
-pub struct ApiPressureLimitsMessage {
-    pub token: String,
-    pub low_low: Option<f64>,
-    pub low: Option<f64>,
-    pub high: Option<f64>,
-    pub high_high: Option<f64>,
+struct ApiPressureLimitsMessage {
+    /// Deprecated, send the token as `Authorization: Bearer` header instead
+    #[allow(dead_code, reason = "checked by the auth middleware")]
+    token: Option<String>,
+    #[serde(flatten)]
+    limits: PressureLimitsUpdate,
 }
 
 #[utoipa::path(post,
@@ -126,107 +229,383 @@ pub struct ApiPressureLimitsMessage {
     request_body = ApiPressureLimitsMessage,
     responses(
         (status = 200, description = "Success!", body = ()),
-        (status = 403, description = "Invalid token was sent", body = ()),
+        (status = 400, description = "Limits are not in ascending order", body = String),
+        (status = 401, description = "Missing or invalid token", body = String),
+        (status = 403, description = "Role of the user is not allowed to do this", body = String),
     ),
 )]
 async fn post_crab_set_pressure_limits(
     State(state): State<AppState>,
     Json(payload): Json<ApiPressureLimitsMessage>,
-) -> impl IntoResponse {
-    use sha1::Digest;
-
-    let mut hasher = sha1::Sha1::new();
-    hasher.update(payload.token.as_bytes());
-    let res = hasher.finalize();
-
-    // "Security"
-    if res[..] != hex_literal::hex!("49203b5f12f55a6fe51a042b53a67d035f7971bb") {
-        return Err(StatusCode::FORBIDDEN);
+) -> Result<StatusCode, (StatusCode, String)> {
+    // Reject bad values right away, the logic checks them again before applying them
+    let limits = payload.limits.apply(&state.pressure_limits.borrow());
+    if let Err(e) = limits.validate() {
+        return Err((StatusCode::BAD_REQUEST, e));
     }
 
-    match state.pressure_limits_tx.send(payload).await {
+    match state.pressure_limits_tx.send(payload.limits).await {
         Ok(_) => Ok(StatusCode::OK),
-        Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR),
+        Err(_) => Err((StatusCode::INTERNAL_SERVER_ERROR, String::new())),
     }
 }
 
+#[utoipa::path(get,
+    path = "/crab/logic-parameters",
+    summary = "Get the timing parameters of the crab logic",
+    responses(
+        (status = 200, description = "Success!", body = LogicParameters),
+))]
+async fn get_crab_logic_parameters(State(state): State<AppState>) -> Json<LogicParameters> {
+    Json(state.logic_parameters.borrow().clone())
+}
+
 #[derive(utoipa::ToSchema, serde::Deserialize)]
-struct ApiTokenMessage {
-    token: String,
+struct ApiLogicParametersMessage {
+    /// Deprecated, send the token as `Authorization: Bearer` header instead
+    #[allow(dead_code, reason = "checked by the auth middleware")]
+    token: Option<String>,
+    #[serde(flatten)]
+    parameters: LogicParametersUpdate,
 }
 
 #[utoipa::path(post,
-    path = "/crab/inflate",
-    summary = "Forcefully inflate the crab!",
-    request_body = ApiTokenMessage,
+    path = "/crab/set-logic-parameters",
+    summary = "Change timing parameters of the crab logic",
+    request_body = ApiLogicParametersMessage,
     responses(
         (status = 200, description = "Success!", body = ()),
-        (status = 403, description = "Invalid token was sent", body = ()),
+        (status = 400, description = "Parameters are out of range", body = String),
+        (status = 401, description = "Missing or invalid token", body = String),
+        (status = 403, description = "Role of the user is not allowed to do this", body = String),
     ),
 )]
-async fn post_crab_inflate(
+async fn post_crab_set_logic_parameters(
     State(state): State<AppState>,
-    Json(payload): Json<ApiTokenMessage>,
-) -> impl IntoResponse {
-    use sha1::Digest;
-
-    let mut hasher = sha1::Sha1::new();
-    hasher.update(payload.token.as_bytes());
-    let res = hasher.finalize();
+    Json(payload): Json<ApiLogicParametersMessage>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    // Reject bad values right away, the logic checks them again before applying them
+    let parameters = payload.parameters.apply(&state.logic_parameters.borrow());
+    if let Err(e) = parameters.validate() {
+        return Err((StatusCode::BAD_REQUEST, e));
+    }
 
-    // "Security"
-    if res[..] != hex_literal::hex!("49203b5f12f55a6fe51a042b53a67d035f7971bb") {
-        return Err(StatusCode::FORBIDDEN);
+    match state.logic_parameters_tx.send(payload.parameters).await {
+        Ok(_) => Ok(StatusCode::OK),
+        Err(_) => Err((StatusCode::INTERNAL_SERVER_ERROR, String::new())),
     }
+}
 
-    state
-        .trigger_fan
-        .store(true, std::sync::atomic::Ordering::SeqCst);
+#[utoipa::path(post,
+    path = "/crab/plant",
+    summary = "Change the simulated crab while it is running",
+    request_body = PlantUpdate,
+    responses(
+        (status = 200, description = "Success!", body = ()),
+        (status = 400, description = "Leak rate is out of range", body = String),
+        (status = 401, description = "Missing or invalid token", body = String),
+        (status = 403, description = "Role of the user is not allowed to do this", body = String),
+        (status = 409, description = "The crab is not simulated by the plant model", body = String),
+    ),
+)]
+async fn post_crab_plant(
+    State(state): State<AppState>,
+    Json(payload): Json<PlantUpdate>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let Some(plant_tx) = &state.plant_tx else {
+        return Err((
+            StatusCode::CONFLICT,
+            "the plant model is not enabled".to_string(),
+        ));
+    };
+    payload
+        .validate()
+        .map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+
+    match plant_tx.send(payload).await {
+        Ok(_) => Ok(StatusCode::OK),
+        Err(_) => Err((StatusCode::INTERNAL_SERVER_ERROR, String::new())),
+    }
+}
 
-    Ok(StatusCode::OK)
+#[utoipa::path(post,
+    path = "/crab/inflate",
+    summary = "Forcefully inflate the crab!",
+    responses(
+        (status = 200, description = "Success!", body = ()),
+        (status = 401, description = "Missing or invalid token", body = String),
+        (status = 403, description = "Role of the user is not allowed to do this", body = String),
+    ),
+)]
+async fn post_crab_inflate(State(state): State<AppState>) -> impl IntoResponse {
+    state.trigger_fan();
 }
 
 #[utoipa::path(post,
     path = "/crab/sleep",
     summary = "Put the crab to sleep.",
-    request_body = ApiTokenMessage,
     responses(
         (status = 200, description = "Success!", body = ()),
-        (status = 403, description = "Invalid token was sent", body = ()),
+        (status = 401, description = "Missing or invalid token", body = String),
+        (status = 403, description = "Role of the user is not allowed to do this", body = String),
     ),
 )]
-async fn post_crab_sleep(
+async fn post_crab_sleep(State(state): State<AppState>) -> impl IntoResponse {
+    state.trigger_sleep();
+}
+
+#[utoipa::path(post,
+    path = "/crab/fault_reset",
+    summary = "Reset faults of the crab controller",
+    responses(
+        (status = 200, description = "Success!", body = ()),
+        (status = 401, description = "Missing or invalid token", body = String),
+        (status = 403, description = "Role of the user is not allowed to do this", body = String),
+))]
+async fn post_crab_fault_reset(State(state): State<AppState>) -> impl IntoResponse {
+    state.reset_fault();
+}
+
+#[utoipa::path(get,
+    path = "/crab/status",
+    summary = "Get the current state of the crab",
+    responses(
+        (status = 200, description = "Success!", body = status::CrabStatus),
+))]
+async fn get_crab_status(State(state): State<AppState>) -> Json<status::CrabStatus> {
+    Json(state.status.borrow().clone())
+}
+
+#[utoipa::path(get,
+    path = "/crab/pressure",
+    summary = "Get the air pressure of the crab and its limits",
+    responses(
+        (status = 200, description = "Success!", body = status::PressureStatus),
+))]
+async fn get_crab_pressure(State(state): State<AppState>) -> Json<status::PressureStatus> {
+    Json(state.status.borrow().pressure.clone())
+}
+
+#[utoipa::path(get,
+    path = "/crab/channels",
+    summary = "Get the state of every light channel of the crab",
+    responses(
+        (status = 200, description = "Success!", body = std::collections::BTreeMap<String, bool>),
+))]
+async fn get_crab_channels(
     State(state): State<AppState>,
-    Json(payload): Json<ApiTokenMessage>,
+) -> Json<std::collections::BTreeMap<String, bool>> {
+    Json(state.status.borrow().channels.clone())
+}
+
+#[utoipa::path(get,
+    path = "/crab/events",
+    summary = "Stream changes of the crab state as Server-Sent Events",
+    description = "Each event is named like its `type`.  Send the `Last-Event-ID` header when \
+        reconnecting to get the events which were missed in between.",
+    params(("Last-Event-ID" = Option<u64>, Header, description = "ID of the last event received")),
+    responses(
+        (status = 200, description = "Success!", body = events::CrabEvent, content_type = "text/event-stream"),
+))]
+async fn get_crab_events(
+    State(state): State<AppState>,
+    headers: axum::http::HeaderMap,
 ) -> impl IntoResponse {
-    use sha1::Digest;
+    use futures::StreamExt as _;
+
+    let last_event_id = headers
+        .get("last-event-id")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse().ok());
+    let stream = state
+        .events
+        .subscribe(last_event_id)
+        .map(|event| Ok::<_, std::convert::Infallible>(event.to_sse()));
+
+    axum::response::sse::Sse::new(stream).keep_alive(Default::default())
+}
 
-    let mut hasher = sha1::Sha1::new();
-    hasher.update(payload.token.as_bytes());
-    let res = hasher.finalize();
+#[utoipa::path(get,
+    path = "/crab/alarms",
+    summary = "Get all alarms of the crab and the recent alarm history",
+    responses(
+        (status = 200, description = "Success!", body = AlarmsSnapshot),
+))]
+async fn get_crab_alarms(State(state): State<AppState>) -> Json<AlarmsSnapshot> {
+    Json(state.alarms.borrow().clone())
+}
+
+#[derive(Debug, utoipa::ToSchema, serde::Deserialize)]
+pub struct ApiAcknowledgeAlarmsMessage {
+    /// Alarm to acknowledge, all active alarms when not given
+    pub id: Option<String>,
+}
 
-    // "Security"
-    if res[..] != hex_literal::hex!("49203b5f12f55a6fe51a042b53a67d035f7971bb") {
-        return Err(StatusCode::FORBIDDEN);
+#[utoipa::path(post,
+    path = "/crab/acknowledge-alarms",
+    summary = "Acknowledge active alarms of the crab",
+    request_body = ApiAcknowledgeAlarmsMessage,
+    responses(
+        (status = 200, description = "Success!", body = ()),
+        (status = 401, description = "Missing or invalid token", body = String),
+        (status = 403, description = "Role of the user is not allowed to do this", body = String),
+        (status = 404, description = "No alarm with this ID exists", body = ()),
+    ),
+)]
+async fn post_crab_acknowledge_alarms(
+    State(state): State<AppState>,
+    Json(payload): Json<ApiAcknowledgeAlarmsMessage>,
+) -> impl IntoResponse {
+    if let Some(id) = &payload.id
+        && !state.alarms.borrow().contains(id)
+    {
+        return Err(StatusCode::NOT_FOUND);
     }
 
-    state
-        .trigger_sleep
-        .store(true, std::sync::atomic::Ordering::SeqCst);
+    match state.alarm_ack_tx.send(payload).await {
+        Ok(_) => Ok(StatusCode::OK),
+        Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR),
+    }
+}
+
+#[utoipa::path(get,
+    path = "/crab/audit",
+    summary = "Get the most recent control actions taken through the APIs",
+    params(audit::AuditQuery),
+    responses(
+        (status = 200, description = "Success!", body = Vec<audit::AuditEntry>),
+        (status = 401, description = "Missing or invalid token", body = String),
+        (status = 403, description = "Role of the user is not allowed to do this", body = String),
+))]
+async fn get_crab_audit(
+    State(state): State<AppState>,
+    axum::extract::Query(query): axum::extract::Query<audit::AuditQuery>,
+) -> Json<Vec<audit::AuditEntry>> {
+    Json(state.audit.query(&query))
+}
+
+#[utoipa::path(get,
+    path = "/crab/sequences",
+    summary = "Get all sequences and the one being played",
+    responses(
+        (status = 200, description = "Success!", body = sequences::SequencesInfo),
+))]
+async fn get_crab_sequences(
+    State(state): State<AppState>,
+) -> Result<Json<sequences::SequencesInfo>, (StatusCode, String)> {
+    let info = send_sequence_command(&state, |resp| sequences::SequenceCommand::List { resp })
+        .await
+        .map_err(sequence_error_response)?;
+    Ok(Json(info))
+}
 
+#[derive(utoipa::ToSchema, serde::Deserialize)]
+struct ApiUploadSequenceMessage {
+    /// Up to 64 letters, digits, `-` and `_`, replaces the sequence of the same name
+    name: String,
+    #[serde(flatten)]
+    sequence: sequences::Sequence,
+}
+
+#[utoipa::path(post,
+    path = "/crab/sequences/upload",
+    summary = "Store a sequence under its name",
+    request_body = ApiUploadSequenceMessage,
+    responses(
+        (status = 200, description = "Success!", body = ()),
+        (status = 400, description = "Invalid name, steps or unknown channels", body = String),
+        (status = 401, description = "Missing or invalid token", body = String),
+        (status = 403, description = "Role of the user is not allowed to do this", body = String),
+    ),
+)]
+async fn post_crab_upload_sequence(
+    State(state): State<AppState>,
+    Json(payload): Json<ApiUploadSequenceMessage>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    sequences::validate_name(&payload.name).map_err(sequence_error_response)?;
+    send_sequence_command(&state, |resp| sequences::SequenceCommand::Upload {
+        name: payload.name,
+        sequence: payload.sequence,
+        resp,
+    })
+    .await
+    .and_then(|result| result)
+    .map_err(sequence_error_response)?;
     Ok(StatusCode::OK)
 }
 
+#[derive(utoipa::ToSchema, serde::Deserialize)]
+struct ApiStartSequenceMessage {
+    name: String,
+}
+
 #[utoipa::path(post,
-    path = "/crab/fault_reset",
-    summary = "Reset faults of the crab controller",
+    path = "/crab/sequences/start",
+    summary = "Play a sequence, replacing the one being played",
+    request_body = ApiStartSequenceMessage,
     responses(
-        (status = 200, description = "Success!", body = ())
-))]
-async fn post_crab_fault_reset(State(state): State<AppState>) -> impl IntoResponse {
+        (status = 200, description = "Success!", body = ()),
+        (status = 401, description = "Missing or invalid token", body = String),
+        (status = 403, description = "Role of the user is not allowed to do this", body = String),
+        (status = 404, description = "No sequence with this name", body = String),
+    ),
+)]
+async fn post_crab_start_sequence(
+    State(state): State<AppState>,
+    Json(payload): Json<ApiStartSequenceMessage>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    send_sequence_command(&state, |resp| sequences::SequenceCommand::Start {
+        name: payload.name,
+        resp,
+    })
+    .await
+    .and_then(|result| result)
+    .map_err(sequence_error_response)?;
+    Ok(StatusCode::OK)
+}
+
+#[utoipa::path(post,
+    path = "/crab/sequences/stop",
+    summary = "Stop the sequence being played",
+    responses(
+        (status = 200, description = "Success!", body = ()),
+        (status = 401, description = "Missing or invalid token", body = String),
+        (status = 403, description = "Role of the user is not allowed to do this", body = String),
+    ),
+)]
+async fn post_crab_stop_sequence(
+    State(state): State<AppState>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    send_sequence_command(&state, |resp| sequences::SequenceCommand::Stop { resp })
+        .await
+        .and_then(|result| result)
+        .map_err(sequence_error_response)?;
+    Ok(StatusCode::OK)
+}
+
+/// Hand a command to the main loop and wait for its answer
+async fn send_sequence_command<T>(
+    state: &AppState,
+    command: impl FnOnce(tokio::sync::oneshot::Sender<T>) -> sequences::SequenceCommand,
+) -> Result<T, sequences::SequenceError> {
+    let (tx, rx) = tokio::sync::oneshot::channel();
     state
-        .fault_reset
-        .store(true, std::sync::atomic::Ordering::SeqCst)
+        .sequence_tx
+        .send(command(tx))
+        .await
+        .map_err(|_| sequences::SequenceError::Unavailable)?;
+    rx.await.map_err(|_| sequences::SequenceError::Unavailable)
+}
+
+fn sequence_error_response(e: sequences::SequenceError) -> (StatusCode, String) {
+    use sequences::SequenceError;
+
+    let status = match e {
+        SequenceError::NotFound(_) => StatusCode::NOT_FOUND,
+        SequenceError::Save(_) | SequenceError::Unavailable => StatusCode::INTERNAL_SERVER_ERROR,
+        _ => StatusCode::BAD_REQUEST,
+    };
+    (status, e.to_string())
 }
 
 async fn root(State(_): State<AppState>) -> impl IntoResponse {
@@ -249,37 +628,109 @@ fn setup_metrics_recorder() -> metrics_exporter_prometheus::PrometheusHandle {
     recorder_handle
 }
 
-fn app() -> axum::Router<AppState> {
-    let (router, api): (axum::Router<AppState>, utoipa::openapi::OpenApi) =
+fn app(emotions: &[Emotion]) -> axum::Router<AppState> {
+    let (router, mut api): (axum::Router<AppState>, utoipa::openapi::OpenApi) =
         utoipa_axum::router::OpenApiRouter::with_openapi(ApiDoc::openapi())
-            .routes(utoipa_axum::routes!(post_emotion))
+            .routes(utoipa_axum::routes!(post_emotion, get_emotion))
+            .routes(utoipa_axum::routes!(get_emotions))
             .routes(utoipa_axum::routes!(post_crab_talk))
             .routes(utoipa_axum::routes!(post_crab_inflate))
             .routes(utoipa_axum::routes!(post_crab_sleep))
             .routes(utoipa_axum::routes!(post_crab_fault_reset))
             .routes(utoipa_axum::routes!(post_crab_set_pressure_limits))
+            .routes(utoipa_axum::routes!(get_crab_logic_parameters))
+            .routes(utoipa_axum::routes!(post_crab_set_logic_parameters))
+            .routes(utoipa_axum::routes!(post_crab_plant))
+            .routes(utoipa_axum::routes!(get_crab_status))
+            .routes(utoipa_axum::routes!(get_crab_pressure))
+            .routes(utoipa_axum::routes!(get_crab_channels))
+            .routes(utoipa_axum::routes!(get_crab_events))
+            .routes(utoipa_axum::routes!(get_crab_alarms))
+            .routes(utoipa_axum::routes!(post_crab_acknowledge_alarms))
+            .routes(utoipa_axum::routes!(get_crab_audit))
+            .routes(utoipa_axum::routes!(get_crab_sequences))
+            .routes(utoipa_axum::routes!(post_crab_upload_sequence))
+            .routes(utoipa_axum::routes!(post_crab_start_sequence))
+            .routes(utoipa_axum::routes!(post_crab_stop_sequence))
             .split_for_parts();
 
-    let router = router.route("/", get(root)).merge(
+    // The emotions are configured, so list them in the schema instead of any string
+    if let Some(components) = api.components.as_mut() {
+        components.schemas.insert(
+            "Emotion".to_string(),
+            utoipa::openapi::schema::ObjectBuilder::new()
+                .schema_type(utoipa::openapi::schema::Type::String)
+                .enum_values(Some(emotions.iter().map(Emotion::as_str)))
+                .description(Some("Name of one of the configured emotions"))
+                .into(),
+        );
+    }
+
+    let router = router.route("/", get(root)).merge(mirror::router()).merge(
         utoipa_swagger_ui::SwaggerUi::new("/swagger-ui").url("/api-docs/openapi.json", api.clone()),
     );
 
     let recorder_handle = setup_metrics_recorder();
-    let router = router.route(
+    router.route(
         "/metrics",
         get(move || std::future::ready(recorder_handle.render())),
-    );
-
-    router
+    )
 }
 
 #[derive(Clone)]
 pub struct AppState {
+    pub auth: std::sync::Arc<auth::Auth>,
+    pub audit: std::sync::Arc<audit::AuditLog>,
     pub emotion_ch_tx: tokio::sync::mpsc::Sender<emotionmanager::EmotionCommand>,
+    /// Names of the configured emotions
+    pub emotions: std::sync::Arc<[Emotion]>,
+    /// Derives the emotion of the messages sent to `/crab/talk`
+    pub sentiment: std::sync::Arc<dyn sentiment::SentimentAnalyzer>,
     pub fault_reset: std::sync::Arc<std::sync::atomic::AtomicBool>,
     pub trigger_fan: std::sync::Arc<std::sync::atomic::AtomicBool>,
     pub trigger_sleep: std::sync::Arc<std::sync::atomic::AtomicBool>,
-    pub pressure_limits_tx: tokio::sync::mpsc::Sender<ApiPressureLimitsMessage>,
+    /// Syllables the crab is told to say, 0 when nothing new was said
+    pub talk: std::sync::Arc<std::sync::atomic::AtomicI32>,
+    /// Pressure limits currently used by the logic
+    pub pressure_limits: tokio::sync::watch::Receiver<parameters::PressureLimits>,
+    pub pressure_limits_tx: tokio::sync::mpsc::Sender<PressureLimitsUpdate>,
+    /// Parameters currently used by the logic
+    pub logic_parameters: tokio::sync::watch::Receiver<LogicParameters>,
+    pub logic_parameters_tx: tokio::sync::mpsc::Sender<LogicParametersUpdate>,
+    /// Changes of the plant model, `None` when the real crab is controlled
+    pub plant_tx: Option<tokio::sync::mpsc::Sender<PlantUpdate>>,
+    /// State of the crab as of the last change
+    pub status: tokio::sync::watch::Receiver<status::CrabStatus>,
+    /// Changes of the state for `GET /crab/events`
+    pub events: std::sync::Arc<events::EventBus>,
+    /// Alarms as of the last change
+    pub alarms: tokio::sync::watch::Receiver<AlarmsSnapshot>,
+    pub alarm_ack_tx: tokio::sync::mpsc::Sender<ApiAcknowledgeAlarmsMessage>,
+    pub sequence_tx: tokio::sync::mpsc::Sender<sequences::SequenceCommand>,
+}
+
+/// Operator actions, shared by the HTTP handlers and the GraphQL mutations
+impl AppState {
+    pub fn trigger_fan(&self) {
+        self.trigger_fan
+            .store(true, std::sync::atomic::Ordering::SeqCst);
+    }
+
+    pub fn trigger_sleep(&self) {
+        self.trigger_sleep
+            .store(true, std::sync::atomic::Ordering::SeqCst);
+    }
+
+    /// Make the crab move its mouth for `syllables`
+    pub fn talk(&self, syllables: i32) {
+        self.talk
+            .store(syllables, std::sync::atomic::Ordering::SeqCst);
+    }
+
+    pub fn reset_fault(&self) {
+        self.fault_reset
+            .store(true, std::sync::atomic::Ordering::SeqCst);
+    }
 }
 
 #[tokio::main(flavor = "current_thread")]
@@ -288,16 +739,40 @@ pub async fn run_http_server(
     emotionmanager: emotionmanager::EmotionManager,
     graphql_router: Option<axum::Router<AppState>>,
 ) {
-    let mut router = app();
+    let mut router = app(&state.emotions);
     if let Some(graphql_router) = graphql_router {
         router = router.merge(graphql_router);
     }
-    let router = router.with_state(state);
+    let router = router
+        .layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            auth::authenticate,
+        ))
+        .layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            audit::record,
+        ))
+        .with_state(state);
 
     let listener = tokio::net::TcpListener::bind(BIND_ADDR).await.unwrap();
 
     let em = emotionmanager.run();
 
-    axum::serve(listener, router).await.unwrap();
+    let service = router.into_make_service_with_connect_info::<std::net::SocketAddr>();
+    axum::serve(listener, service).await.unwrap();
     em.await.unwrap();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn count_syllables() {
+        assert_eq!(syllables("Hello crab!"), 3);
+        assert_eq!(syllables("Rust is beautiful"), 5);
+        assert_eq!(syllables("rhythm"), 1);
+        assert_eq!(syllables("..."), 1);
+        assert_eq!(syllables(&"ba ".repeat(500)), MAX_TALK_SYLLABLES);
+    }
+}
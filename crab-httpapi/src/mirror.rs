@@ -0,0 +1,131 @@
+//! Live mirror of the crab lights for the web page at `/`
+//!
+//! The page stacks the same layer images as the egui visualization and shows each one while any
+//! of its channels is on, following the `channels` events from `GET /crab/events`.
+
+use axum::{
+    Json,
+    extract::Path,
+    http::{StatusCode, header},
+    response::IntoResponse,
+};
+
+pub struct Layer {
+    /// File name below `/vis/`
+    pub name: &'static str,
+    /// Channels lighting this layer
+    pub channels: &'static [&'static str],
+    png: &'static [u8],
+}
+
+/// All layers from bottom to top
+pub const LAYERS: &[Layer] = &[
+    Layer {
+        name: "bottom.png",
+        channels: &["bottom_front", "bottom_back"],
+        png: include_bytes!("../../vis/bottom.png"),
+    },
+    Layer {
+        name: "spikes_left.png",
+        channels: &["spikes_left"],
+        png: include_bytes!("../../vis/spikes_left.png"),
+    },
+    Layer {
+        name: "spikes_mid.png",
+        channels: &["spikes_mid"],
+        png: include_bytes!("../../vis/spikes_mid.png"),
+    },
+    Layer {
+        name: "spikes_right.png",
+        channels: &["spikes_right"],
+        png: include_bytes!("../../vis/spikes_right.png"),
+    },
+    Layer {
+        name: "eyes.png",
+        channels: &["eyes"],
+        png: include_bytes!("../../vis/eyes.png"),
+    },
+    Layer {
+        name: "pupil_top.png",
+        channels: &["pupil_top"],
+        png: include_bytes!("../../vis/pupil_top.png"),
+    },
+    Layer {
+        name: "pupil_down.png",
+        channels: &["pupil_down"],
+        png: include_bytes!("../../vis/pupil_down.png"),
+    },
+    Layer {
+        name: "mouth_top.png",
+        channels: &["mouth_top"],
+        png: include_bytes!("../../vis/mouth_top.png"),
+    },
+    Layer {
+        name: "mouth_mid.png",
+        channels: &["mouth_mid"],
+        png: include_bytes!("../../vis/mouth_mid.png"),
+    },
+    Layer {
+        name: "mouth_bottom.png",
+        channels: &["mouth_bottom"],
+        png: include_bytes!("../../vis/mouth_bottom.png"),
+    },
+];
+
+#[derive(serde::Serialize)]
+struct LayerInfo {
+    url: String,
+    channels: &'static [&'static str],
+}
+
+async fn get_layers() -> Json<Vec<LayerInfo>> {
+    Json(
+        LAYERS
+            .iter()
+            .map(|layer| LayerInfo {
+                url: format!("/vis/{}", layer.name),
+                channels: layer.channels,
+            })
+            .collect(),
+    )
+}
+
+async fn get_layer(Path(name): Path<String>) -> impl IntoResponse {
+    match LAYERS.iter().find(|layer| layer.name == name) {
+        Some(layer) => Ok((
+            [
+                (header::CONTENT_TYPE, "image/png"),
+                (header::CACHE_CONTROL, "public, max-age=3600"),
+            ],
+            layer.png,
+        )),
+        None => Err(StatusCode::NOT_FOUND),
+    }
+}
+
+pub fn router<S: Clone + Send + Sync + 'static>() -> axum::Router<S> {
+    use axum::routing::get;
+
+    axum::Router::new()
+        // More specific than the images, so it takes precedence
+        .route("/vis/layers.json", get(get_layers))
+        .route("/vis/{name}", get(get_layer))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn layers() {
+        for (i, layer) in LAYERS.iter().enumerate() {
+            assert!(
+                layer.png.starts_with(b"\x89PNG\r\n\x1a\n"),
+                "{} is no PNG",
+                layer.name
+            );
+            assert!(!layer.channels.is_empty());
+            assert!(LAYERS[..i].iter().all(|l| l.name != layer.name));
+        }
+    }
+}
@@ -0,0 +1,195 @@
+/// Tunable timings of the crab logic, all in seconds
+#[derive(
+    Debug,
+    Clone,
+    PartialEq,
+    utoipa::ToSchema,
+    serde::Serialize,
+    serde::Deserialize,
+    juniper::GraphQLObject,
+)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogicParameters {
+    /// Time between two blinks
+    pub blink_interval_s: f64,
+    pub blink_duration_s: f64,
+    /// Time the mouth stays open after an emotion change and between closing it
+    pub mouth_open_s: f64,
+    pub mouth_closed_s: f64,
+    /// Length of one syllable while talking, half of it with the mouth open
+    pub talk_syllable_s: f64,
+    /// Time the pressure must stay above HIGHHIGH before the alarm latches
+    pub high_high_delay_s: f64,
+    /// Maximum fan runtime before the logic faults
+    pub fan_max_runtime_s: f64,
+    /// Minimum time between stopping and restarting the fan
+    pub fan_cooldown_s: f64,
+    /// Time after the fan stopped until the logic refills the crab on its own
+    pub deflation_interval_s: f64,
+}
+
+impl Default for LogicParameters {
+    fn default() -> Self {
+        Self {
+            blink_interval_s: 3.,
+            blink_duration_s: 0.3,
+            mouth_open_s: 10.,
+            mouth_closed_s: 2.,
+            talk_syllable_s: 0.4,
+            high_high_delay_s: 0.5,
+            fan_max_runtime_s: 60.,
+            fan_cooldown_s: 10.,
+            deflation_interval_s: 30. * 60.,
+        }
+    }
+}
+
+impl LogicParameters {
+    pub fn validate(&self) -> Result<(), String> {
+        let Self {
+            blink_interval_s,
+            blink_duration_s,
+            mouth_open_s,
+            mouth_closed_s,
+            talk_syllable_s,
+            high_high_delay_s,
+            fan_max_runtime_s,
+            fan_cooldown_s,
+            deflation_interval_s,
+        } = self;
+        for (name, value) in [
+            ("blink_interval_s", blink_interval_s),
+            ("blink_duration_s", blink_duration_s),
+            ("mouth_open_s", mouth_open_s),
+            ("mouth_closed_s", mouth_closed_s),
+            ("talk_syllable_s", talk_syllable_s),
+            ("high_high_delay_s", high_high_delay_s),
+            ("fan_max_runtime_s", fan_max_runtime_s),
+            ("fan_cooldown_s", fan_cooldown_s),
+            ("deflation_interval_s", deflation_interval_s),
+        ] {
+            // Anything beyond a day is certainly a typo
+            if !(value.is_finite() && (0. ..=86400.).contains(value)) {
+                return Err(format!("{name} = {value} is out of range"));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Partial change of the logic parameters, unset fields are left as they are
+#[derive(
+    Debug,
+    Default,
+    Clone,
+    utoipa::ToSchema,
+    serde::Serialize,
+    serde::Deserialize,
+    juniper::GraphQLInputObject,
+)]
+#[serde(deny_unknown_fields)]
+pub struct LogicParametersUpdate {
+    pub blink_interval_s: Option<f64>,
+    pub blink_duration_s: Option<f64>,
+    pub mouth_open_s: Option<f64>,
+    pub mouth_closed_s: Option<f64>,
+    pub talk_syllable_s: Option<f64>,
+    pub high_high_delay_s: Option<f64>,
+    pub fan_max_runtime_s: Option<f64>,
+    pub fan_cooldown_s: Option<f64>,
+    pub deflation_interval_s: Option<f64>,
+}
+
+impl LogicParametersUpdate {
+    /// Parameters with this update applied
+    pub fn apply(&self, parameters: &LogicParameters) -> LogicParameters {
+        LogicParameters {
+            blink_interval_s: self.blink_interval_s.unwrap_or(parameters.blink_interval_s),
+            blink_duration_s: self.blink_duration_s.unwrap_or(parameters.blink_duration_s),
+            mouth_open_s: self.mouth_open_s.unwrap_or(parameters.mouth_open_s),
+            mouth_closed_s: self.mouth_closed_s.unwrap_or(parameters.mouth_closed_s),
+            talk_syllable_s: self.talk_syllable_s.unwrap_or(parameters.talk_syllable_s),
+            high_high_delay_s: self
+                .high_high_delay_s
+                .unwrap_or(parameters.high_high_delay_s),
+            fan_max_runtime_s: self
+                .fan_max_runtime_s
+                .unwrap_or(parameters.fan_max_runtime_s),
+            fan_cooldown_s: self.fan_cooldown_s.unwrap_or(parameters.fan_cooldown_s),
+            deflation_interval_s: self
+                .deflation_interval_s
+                .unwrap_or(parameters.deflation_interval_s),
+        }
+    }
+}
+
+/// Partial change of the pressure limits in millibar, unset limits are left as they are
+#[derive(
+    Debug,
+    Default,
+    Clone,
+    utoipa::ToSchema,
+    serde::Serialize,
+    serde::Deserialize,
+    juniper::GraphQLInputObject,
+)]
+pub struct PressureLimitsUpdate {
+    pub low_low: Option<f64>,
+    pub low: Option<f64>,
+    pub high: Option<f64>,
+    pub high_high: Option<f64>,
+}
+
+impl PressureLimitsUpdate {
+    /// Apply the update to `limits`
+    pub fn apply(&self, limits: &PressureLimits) -> PressureLimits {
+        PressureLimits {
+            low_low: self.low_low.unwrap_or(limits.low_low),
+            low: self.low.unwrap_or(limits.low),
+            high: self.high.unwrap_or(limits.high),
+            high_high: self.high_high.unwrap_or(limits.high_high),
+        }
+    }
+}
+
+/// Limits of the crab air pressure in millibar
+#[derive(
+    Debug,
+    Clone,
+    PartialEq,
+    utoipa::ToSchema,
+    serde::Serialize,
+    serde::Deserialize,
+    juniper::GraphQLObject,
+)]
+#[serde(default, deny_unknown_fields)]
+pub struct PressureLimits {
+    pub low_low: f64,
+    pub low: f64,
+    pub high: f64,
+    pub high_high: f64,
+}
+
+impl Default for PressureLimits {
+    fn default() -> Self {
+        Self {
+            low_low: 0.02,
+            low: 0.2,
+            high: 0.45,
+            high_high: 0.6,
+        }
+    }
+}
+
+impl PressureLimits {
+    pub fn validate(&self) -> Result<(), String> {
+        let limits = [self.low_low, self.low, self.high, self.high_high];
+        if limits.iter().any(|l| !l.is_finite()) || !limits.is_sorted_by(|a, b| a < b) {
+            return Err(format!(
+                "pressure limits must satisfy LOWLOW < LOW < HIGH < HIGHHIGH, got {} < {} < {} < {}",
+                self.low_low, self.low, self.high, self.high_high
+            ));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,35 @@
+//! Runtime changes of the plant model, the simulated crab
+//!
+//! The model itself lives in the main loop, which receives the [`PlantUpdate`]s sent to
+//! `POST /crab/plant` and the `setPlant` mutation.
+
+/// Change of the simulated crab to rehearse scenarios, unset fields are left as they are
+#[derive(
+    Debug,
+    Default,
+    Clone,
+    utoipa::ToSchema,
+    serde::Serialize,
+    serde::Deserialize,
+    juniper::GraphQLInputObject,
+)]
+#[serde(deny_unknown_fields)]
+pub struct PlantUpdate {
+    /// Leakage in liters per second per millibar of overpressure
+    pub leak_lps_per_mbar: Option<f64>,
+    pub dc_ok: Option<bool>,
+    pub estop_ok: Option<bool>,
+    /// Simulate a disconnected pressure sensor
+    pub sensor_fault: Option<bool>,
+}
+
+impl PlantUpdate {
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(leak) = self.leak_lps_per_mbar
+            && !(leak.is_finite() && leak >= 0.)
+        {
+            return Err(format!("leak rate {leak} l/s/mbar must not be negative"));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,549 @@
+//! Sentiment analysis of the messages sent to `/crab/talk`
+//!
+//! A [`SentimentAnalyzer`] derives the emotion the crab feels about a message, together with a
+//! confidence between 0 and 1.  The built-in [`Lexicon`] scores the words of the message against
+//! word lists and keyword rules.  Alternatively the [`HttpAnalyzer`] asks a locally hosted model,
+//! falling back to the lexicon when the model is unreachable or answers nonsense.
+
+use crate::emotionmanager::Emotion;
+use futures::future::BoxFuture;
+
+/// Words turning the sentiment of the following words in the same clause around
+const NEGATIONS: &[&str] = &[
+    "not",
+    "no",
+    "never",
+    "nothing",
+    "neither",
+    "nor",
+    "without",
+    "don't",
+    "dont",
+    "doesn't",
+    "didn't",
+    "isn't",
+    "aren't",
+    "wasn't",
+    "weren't",
+    "can't",
+    "cannot",
+    "won't",
+    "wouldn't",
+    "shouldn't",
+    "hardly",
+];
+
+/// Number of words after a negation whose sentiment is turned around
+const NEGATION_SCOPE: usize = 3;
+
+const POSITIVE: &[&str] = &[
+    "love",
+    "loved",
+    "lovely",
+    "like",
+    "likes",
+    "liked",
+    "good",
+    "great",
+    "nice",
+    "cool",
+    "awesome",
+    "amazing",
+    "wonderful",
+    "excellent",
+    "fantastic",
+    "beautiful",
+    "best",
+    "better",
+    "happy",
+    "glad",
+    "fun",
+    "enjoy",
+    "enjoyed",
+    "thanks",
+    "thank",
+    "brilliant",
+    "perfect",
+    "cute",
+    "yay",
+    "wow",
+    "fast",
+    "safe",
+    "favorite",
+    "favourite",
+];
+
+const NEGATIVE: &[&str] = &[
+    "hate",
+    "hated",
+    "bad",
+    "worse",
+    "worst",
+    "terrible",
+    "awful",
+    "horrible",
+    "ugly",
+    "sad",
+    "angry",
+    "boring",
+    "annoying",
+    "broken",
+    "sucks",
+    "stupid",
+    "slow",
+    "crash",
+    "crashed",
+    "bug",
+    "bugs",
+    "disappointed",
+    "disappointing",
+    "unsafe",
+    "segfault",
+    "dislike",
+];
+
+/// Weight of a keyword rule against a single sentiment word
+const RULE_WEIGHT: f64 = 2.;
+
+/// Emotion derived from a message
+#[derive(Debug, Clone, PartialEq, utoipa::ToSchema, serde::Serialize, serde::Deserialize)]
+pub struct Sentiment {
+    pub emotion: Emotion,
+    /// How sure the analyzer is about the emotion, from 0 to 1
+    pub confidence: f64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SentimentError {
+    /// The backend could not be reached
+    Request(String),
+    /// The backend answered with something that is not a sentiment
+    Response(String),
+    Timeout,
+}
+
+impl std::fmt::Display for SentimentError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SentimentError::Request(e) => write!(f, "sentiment request failed: {e}"),
+            SentimentError::Response(e) => write!(f, "bad sentiment response: {e}"),
+            SentimentError::Timeout => write!(f, "sentiment backend timed out"),
+        }
+    }
+}
+
+impl std::error::Error for SentimentError {}
+
+pub trait SentimentAnalyzer: Send + Sync {
+    fn analyze<'a>(&'a self, text: &'a str) -> BoxFuture<'a, Result<Sentiment, SentimentError>>;
+}
+
+/// Emotion shown when a keyword appears in a message
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct KeywordRule {
+    /// Whole word, matched case-insensitively
+    pub keyword: String,
+    pub emotion: Emotion,
+}
+
+/// Model answering `POST {"text": ...}` with `{"emotion": ..., "confidence": ...}`
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HttpBackendConfig {
+    /// Plain `http://` URL, the model is expected to run next to the crab
+    pub url: String,
+    #[serde(default = "default_timeout_s")]
+    pub timeout_s: f64,
+}
+
+fn default_timeout_s() -> f64 {
+    2.
+}
+
+/// Analysis of the talk messages, from the `[sentiment]` section of the configuration
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SentimentConfig {
+    /// Emotion of messages with mostly positive words
+    pub positive: Emotion,
+    /// Emotion of messages with mostly negative words
+    pub negative: Emotion,
+    /// Emotion of messages the lexicon has no opinion on
+    pub neutral: Emotion,
+    pub rules: Vec<KeywordRule>,
+    /// Ask a model instead of the lexicon
+    pub http: Option<HttpBackendConfig>,
+}
+
+impl Default for SentimentConfig {
+    fn default() -> Self {
+        let rule = |keyword: &str, emotion| KeywordRule {
+            keyword: keyword.to_string(),
+            emotion: Emotion::new(emotion),
+        };
+        Self {
+            positive: Emotion::new("Happy"),
+            negative: Emotion::new("Sad"),
+            neutral: Emotion::new("Neutral"),
+            rules: vec![
+                rule("rust", "Happy"),
+                rule("rustacean", "Happy"),
+                rule("ferris", "Happy"),
+                rule("golang", "Angered"),
+                rule("cobol", "Surprised"),
+            ],
+            http: None,
+        }
+    }
+}
+
+impl SentimentConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(rule) = self
+            .rules
+            .iter()
+            .find(|rule| words(&rule.keyword).count() != 1)
+        {
+            return Err(format!(
+                "sentiment keyword {:?} must be a single word",
+                rule.keyword
+            ));
+        }
+        if let Some(http) = &self.http {
+            HttpAnalyzer::parse_url(&http.url)?;
+            if !(http.timeout_s.is_finite() && http.timeout_s > 0.) {
+                return Err(format!(
+                    "sentiment.http.timeout_s must be positive, got {}",
+                    http.timeout_s
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// All emotions the lexicon can answer with, to be checked against the configured ones
+    pub fn emotions(&self) -> impl Iterator<Item = &Emotion> {
+        [&self.positive, &self.negative, &self.neutral]
+            .into_iter()
+            .chain(self.rules.iter().map(|rule| &rule.emotion))
+    }
+}
+
+/// Build the analyzer selected by the configuration
+///
+/// `emotions` are the configured emotions, answers of a model with others are rejected.
+pub fn analyzer(
+    config: &SentimentConfig,
+    emotions: Vec<Emotion>,
+) -> std::sync::Arc<dyn SentimentAnalyzer> {
+    let lexicon = Lexicon::new(config);
+    match &config.http {
+        Some(http) => std::sync::Arc::new(HttpAnalyzer::new(http, emotions, lexicon)),
+        None => std::sync::Arc::new(lexicon),
+    }
+}
+
+/// Lowercase words of `text`, keeping apostrophes inside of words
+fn words(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !(c.is_alphanumeric() || c == '\'' || c == '’'))
+        .map(|word| {
+            word.trim_matches(['\'', '’'])
+                .replace('’', "'")
+                .to_lowercase()
+        })
+        .filter(|word| !word.is_empty())
+}
+
+/// Scores messages by counting positive, negative and keyword words
+#[derive(Debug, Clone)]
+pub struct Lexicon {
+    positive: Emotion,
+    negative: Emotion,
+    neutral: Emotion,
+    rules: std::collections::HashMap<String, Emotion>,
+}
+
+impl Default for Lexicon {
+    fn default() -> Self {
+        Self::new(&SentimentConfig::default())
+    }
+}
+
+impl Lexicon {
+    pub fn new(config: &SentimentConfig) -> Self {
+        Self {
+            positive: config.positive.clone(),
+            negative: config.negative.clone(),
+            neutral: config.neutral.clone(),
+            rules: config
+                .rules
+                .iter()
+                .map(|rule| (rule.keyword.to_lowercase(), rule.emotion.clone()))
+                .collect(),
+        }
+    }
+
+    pub fn score(&self, text: &str) -> Sentiment {
+        let mut votes = std::collections::BTreeMap::<&Emotion, f64>::new();
+        let mut polarity = 0.;
+        // Negations reach until the end of the clause at most
+        for clause in text.split(['.', ',', ';', ':', '!', '?']) {
+            let mut negated = 0;
+            for word in words(clause) {
+                let word = word.as_str();
+                if NEGATIONS.contains(&word) {
+                    negated = NEGATION_SCOPE;
+                    continue;
+                }
+                let sign = if negated > 0 { -1. } else { 1. };
+                negated = negated.saturating_sub(1);
+                if POSITIVE.contains(&word) {
+                    polarity += sign;
+                } else if NEGATIVE.contains(&word) {
+                    polarity -= sign;
+                } else if let Some(emotion) = self.rules.get(word)
+                    && sign > 0.
+                {
+                    *votes.entry(emotion).or_default() += RULE_WEIGHT;
+                }
+            }
+        }
+        if polarity > 0. {
+            *votes.entry(&self.positive).or_default() += polarity;
+        } else if polarity < 0. {
+            *votes.entry(&self.negative).or_default() -= polarity;
+        }
+
+        let total: f64 = votes.values().sum();
+        let Some((emotion, best)) = votes.into_iter().max_by(|(_, a), (_, b)| a.total_cmp(b))
+        else {
+            return Sentiment {
+                emotion: self.neutral.clone(),
+                confidence: 0.,
+            };
+        };
+        // Share of the evidence for the emotion, discounted while there is little of it
+        Sentiment {
+            emotion: emotion.clone(),
+            confidence: best / total * (1. - 0.5f64.powf(total)),
+        }
+    }
+}
+
+impl SentimentAnalyzer for Lexicon {
+    fn analyze<'a>(&'a self, text: &'a str) -> BoxFuture<'a, Result<Sentiment, SentimentError>> {
+        Box::pin(std::future::ready(Ok(self.score(text))))
+    }
+}
+
+/// Asks a model over HTTP, using the [`Lexicon`] when that fails
+pub struct HttpAnalyzer {
+    uri: axum::http::Uri,
+    timeout: std::time::Duration,
+    emotions: Vec<Emotion>,
+    fallback: Lexicon,
+}
+
+impl HttpAnalyzer {
+    pub fn new(config: &HttpBackendConfig, emotions: Vec<Emotion>, fallback: Lexicon) -> Self {
+        Self {
+            uri: Self::parse_url(&config.url).expect("validated with the configuration"),
+            timeout: std::time::Duration::from_secs_f64(config.timeout_s),
+            emotions,
+            fallback,
+        }
+    }
+
+    fn parse_url(url: &str) -> Result<axum::http::Uri, String> {
+        let uri: axum::http::Uri = url
+            .parse()
+            .map_err(|e| format!("bad sentiment.http.url {url:?}: {e}"))?;
+        if uri.scheme_str() != Some("http") || uri.host().is_none() {
+            return Err(format!(
+                "bad sentiment.http.url {url:?}, only http://host[:port]/path is supported"
+            ));
+        }
+        Ok(uri)
+    }
+
+    async fn request(&self, text: &str) -> Result<Sentiment, SentimentError> {
+        use http_body_util::BodyExt;
+
+        let request_error = |e: &dyn std::fmt::Display| SentimentError::Request(e.to_string());
+        let host = self.uri.host().unwrap_or_default();
+        let port = self.uri.port_u16().unwrap_or(80);
+        let stream = tokio::net::TcpStream::connect((host, port))
+            .await
+            .map_err(|e| request_error(&e))?;
+        let (mut sender, connection) =
+            hyper::client::conn::http1::handshake(hyper_util::rt::TokioIo::new(stream))
+                .await
+                .map_err(|e| request_error(&e))?;
+        tokio::spawn(connection);
+
+        let body = serde_json::json!({ "text": text }).to_string();
+        let request =
+            axum::http::Request::post(self.uri.path_and_query().map_or("/", |p| p.as_str()))
+                .header(
+                    axum::http::header::HOST,
+                    self.uri.authority().unwrap().as_str(),
+                )
+                .header(axum::http::header::CONTENT_TYPE, "application/json")
+                .body(http_body_util::Full::new(axum::body::Bytes::from(body)))
+                .map_err(|e| request_error(&e))?;
+        let response = sender
+            .send_request(request)
+            .await
+            .map_err(|e| request_error(&e))?;
+        if !response.status().is_success() {
+            return Err(SentimentError::Response(format!(
+                "status {}",
+                response.status()
+            )));
+        }
+        let body = response
+            .into_body()
+            .collect()
+            .await
+            .map_err(|e| request_error(&e))?
+            .to_bytes();
+
+        let sentiment: Sentiment =
+            serde_json::from_slice(&body).map_err(|e| SentimentError::Response(e.to_string()))?;
+        if !self.emotions.contains(&sentiment.emotion) {
+            return Err(SentimentError::Response(format!(
+                "unknown emotion {}",
+                sentiment.emotion
+            )));
+        }
+        if !(0. ..=1.).contains(&sentiment.confidence) {
+            return Err(SentimentError::Response(format!(
+                "confidence {} is not between 0 and 1",
+                sentiment.confidence
+            )));
+        }
+        Ok(sentiment)
+    }
+}
+
+impl SentimentAnalyzer for HttpAnalyzer {
+    fn analyze<'a>(&'a self, text: &'a str) -> BoxFuture<'a, Result<Sentiment, SentimentError>> {
+        Box::pin(async move {
+            let result = tokio::time::timeout(self.timeout, self.request(text))
+                .await
+                .unwrap_or(Err(SentimentError::Timeout));
+            result.or_else(|e| {
+                log::warn!("{e}, falling back to the lexicon");
+                Ok(self.fallback.score(text))
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn emotion(lexicon: &Lexicon, text: &str) -> String {
+        lexicon.score(text).emotion.as_str().to_string()
+    }
+
+    #[test]
+    fn lexicon() {
+        let lexicon = Lexicon::default();
+
+        // Only whole words count
+        assert_eq!(emotion(&lexicon, "These words are good"), "Happy");
+        assert_eq!(emotion(&lexicon, "Let's go trust the process"), "Neutral");
+        assert_eq!(emotion(&lexicon, "I write Rust"), "Happy");
+        assert_eq!(emotion(&lexicon, "Golang!"), "Angered");
+        assert_eq!(emotion(&lexicon, "The mainframe runs COBOL"), "Surprised");
+
+        // Negations turn the following words around until the end of the clause
+        assert_eq!(emotion(&lexicon, "This is not good"), "Sad");
+        assert_eq!(emotion(&lexicon, "I don’t hate you"), "Happy");
+        assert_eq!(emotion(&lexicon, "Not bad, it is great"), "Happy");
+        assert_eq!(emotion(&lexicon, "No, this is terrible"), "Sad");
+        assert_eq!(emotion(&lexicon, "I don't write golang"), "Neutral");
+
+        let neutral = lexicon.score("Hello crab");
+        assert_eq!(neutral.confidence, 0.);
+        let weak = lexicon.score("nice");
+        let strong = lexicon.score("nice, I love it, it is great");
+        assert!(weak.confidence < strong.confidence, "{weak:?} {strong:?}");
+        assert!(strong.confidence < 1.);
+        let mixed = lexicon.score("I love rust but golang is great too");
+        assert_eq!(mixed.emotion.as_str(), "Happy");
+        assert!(mixed.confidence < strong.confidence, "{mixed:?}");
+    }
+
+    #[test]
+    fn config() {
+        let mut config = SentimentConfig {
+            rules: vec![KeywordRule {
+                keyword: "Ferris".to_string(),
+                emotion: Emotion::new("Wink"),
+            }],
+            ..Default::default()
+        };
+        assert!(config.validate().is_ok());
+        assert_eq!(emotion(&Lexicon::new(&config), "hi ferris"), "Wink");
+        assert!(config.emotions().any(|e| e.as_str() == "Wink"));
+
+        config.rules[0].keyword = "two words".to_string();
+        assert!(config.validate().is_err());
+        config.rules.clear();
+
+        for (url, ok) in [
+            ("http://localhost:8000/sentiment", true),
+            ("https://example.com/", false),
+            ("localhost:8000", false),
+        ] {
+            config.http = Some(HttpBackendConfig {
+                url: url.to_string(),
+                timeout_s: 1.,
+            });
+            assert_eq!(config.validate().is_ok(), ok, "{url}");
+        }
+    }
+
+    #[test]
+    fn http_backend() {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let model = axum::Router::new().route(
+                "/sentiment",
+                axum::routing::post(|body: axum::Json<serde_json::Value>| async move {
+                    let text = body["text"].as_str().unwrap_or_default();
+                    let emotion = if text.contains("wink") { "Wink" } else { "Sad" };
+                    axum::Json(serde_json::json!({ "emotion": emotion, "confidence": 0.8 }))
+                }),
+            );
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            tokio::spawn(async move { axum::serve(listener, model).await.unwrap() });
+
+            let config = SentimentConfig {
+                http: Some(HttpBackendConfig {
+                    url: format!("http://{addr}/sentiment"),
+                    timeout_s: 5.,
+                }),
+                ..Default::default()
+            };
+            let emotions = ["Happy", "Sad", "Neutral"].map(Emotion::new).to_vec();
+            let analyzer = analyzer(&config, emotions);
+
+            let sentiment = analyzer.analyze("I love rust").await.unwrap();
+            assert_eq!(sentiment.emotion.as_str(), "Sad");
+            assert_eq!(sentiment.confidence, 0.8);
+
+            // Unknown emotions from the model fall back to the lexicon
+            let sentiment = analyzer.analyze("I love rust, wink").await.unwrap();
+            assert_eq!(sentiment.emotion.as_str(), "Happy");
+        });
+    }
+}
@@ -0,0 +1,251 @@
+//! Emotion sequences, short shows played back by the crab
+//!
+//! A sequence is a list of steps, each showing an emotion and/or switching individual channels
+//! for some time.  Sequences are uploaded by name and started or stopped through the REST API.
+//! The main loop owns the sequences and answers the [`SequenceCommand`]s, since only it knows
+//! the channels of the crab.
+
+use crate::emotionmanager::Emotion;
+
+/// Longest time a single step may last
+pub const MAX_STEP_DURATION_S: f64 = 3600.;
+
+/// Maximum number of steps in a sequence
+pub const MAX_STEPS: usize = 1000;
+
+type Responder<T> = tokio::sync::oneshot::Sender<T>;
+
+#[derive(Debug, Clone, PartialEq, utoipa::ToSchema, serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Sequence {
+    pub steps: Vec<SequenceStep>,
+}
+
+#[derive(Debug, Clone, PartialEq, utoipa::ToSchema, serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SequenceStep {
+    /// Emotion shown during this step, the one of the previous step is kept when not set
+    ///
+    /// Without an emotion in the first step, the crab shows its current emotion.
+    #[serde(default)]
+    pub emotion: Option<Emotion>,
+    /// Channels switched on or off by name, taking precedence over the emotion
+    #[serde(default)]
+    pub channels: std::collections::BTreeMap<String, bool>,
+    pub duration_s: f64,
+}
+
+impl Sequence {
+    /// Check the sequence, reporting all channels for which `is_channel` is false
+    pub fn validate(
+        &self,
+        is_emotion: impl Fn(&Emotion) -> bool,
+        is_channel: impl Fn(&str) -> bool,
+    ) -> Result<(), SequenceError> {
+        if self.steps.is_empty() || self.steps.len() > MAX_STEPS {
+            return Err(SequenceError::Steps(self.steps.len()));
+        }
+        if let Some((step, s)) = self.steps.iter().enumerate().find(|(_, s)| {
+            !(s.duration_s.is_finite() && s.duration_s > 0. && s.duration_s <= MAX_STEP_DURATION_S)
+        }) {
+            return Err(SequenceError::InvalidDuration {
+                step,
+                duration_s: s.duration_s,
+            });
+        }
+        let unknown_emotion = self.steps.iter().enumerate().find_map(|(step, s)| {
+            let emotion = s.emotion.as_ref().filter(|e| !is_emotion(e))?;
+            Some((step, emotion))
+        });
+        if let Some((step, emotion)) = unknown_emotion {
+            return Err(SequenceError::UnknownEmotion {
+                step,
+                emotion: emotion.clone(),
+            });
+        }
+        let unknown: Vec<_> = self
+            .steps
+            .iter()
+            .enumerate()
+            .flat_map(|(step, s)| s.channels.keys().map(move |name| (step, name)))
+            .filter(|(_, name)| !is_channel(name))
+            .map(|(step, name)| (step, name.clone()))
+            .collect();
+        if !unknown.is_empty() {
+            return Err(SequenceError::UnknownChannels(unknown));
+        }
+        Ok(())
+    }
+}
+
+/// Names are used as file names, so only allow a safe subset
+pub fn validate_name(name: &str) -> Result<(), SequenceError> {
+    let valid = !name.is_empty()
+        && name.len() <= 64
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if !valid {
+        return Err(SequenceError::InvalidName(name.to_owned()));
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SequenceError {
+    InvalidName(String),
+    /// Number of steps is zero or beyond [`MAX_STEPS`]
+    Steps(usize),
+    InvalidDuration {
+        step: usize,
+        duration_s: f64,
+    },
+    UnknownEmotion {
+        step: usize,
+        emotion: Emotion,
+    },
+    /// Step index and name of every unknown channel
+    UnknownChannels(Vec<(usize, String)>),
+    NotFound(String),
+    /// The sequence could not be stored
+    Save(String),
+    /// The main loop is not running
+    Unavailable,
+}
+
+impl std::fmt::Display for SequenceError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SequenceError::InvalidName(name) => write!(
+                f,
+                "invalid sequence name {name:?}, use up to 64 letters, digits, - and _"
+            ),
+            SequenceError::Steps(n) => {
+                write!(f, "sequence has {n} steps, must have 1 to {MAX_STEPS}")
+            }
+            SequenceError::InvalidDuration { step, duration_s } => write!(
+                f,
+                "steps[{step}]: duration {duration_s} s is out of range, must be positive and at most {MAX_STEP_DURATION_S} s"
+            ),
+            SequenceError::UnknownEmotion { step, emotion } => {
+                write!(f, "steps[{step}]: unknown emotion {emotion:?}")
+            }
+            SequenceError::UnknownChannels(unknown) => {
+                write!(f, "unknown channels:")?;
+                for (i, (step, name)) in unknown.iter().enumerate() {
+                    let sep = if i == 0 { "" } else { "," };
+                    write!(f, "{sep} steps[{step}].{name}")?;
+                }
+                Ok(())
+            }
+            SequenceError::NotFound(name) => write!(f, "no sequence named {name:?}"),
+            SequenceError::Save(e) => write!(f, "failed saving the sequence: {e}"),
+            SequenceError::Unavailable => write!(f, "the crab is not running"),
+        }
+    }
+}
+
+impl std::error::Error for SequenceError {}
+
+/// Sequence being played
+#[derive(Debug, Clone, PartialEq, utoipa::ToSchema, serde::Serialize)]
+pub struct SequenceStatus {
+    pub name: String,
+    /// Index of the current step
+    pub step: usize,
+    pub steps: usize,
+    /// Seconds until the sequence ends
+    pub remaining_s: f64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, utoipa::ToSchema, serde::Serialize)]
+pub struct SequencesInfo {
+    /// All sequences by name
+    pub sequences: std::collections::BTreeMap<String, Sequence>,
+    pub playing: Option<SequenceStatus>,
+}
+
+#[derive(Debug)]
+pub enum SequenceCommand {
+    List {
+        resp: Responder<SequencesInfo>,
+    },
+    Upload {
+        name: String,
+        sequence: Sequence,
+        resp: Responder<Result<(), SequenceError>>,
+    },
+    Start {
+        name: String,
+        resp: Responder<Result<(), SequenceError>>,
+    },
+    Stop {
+        resp: Responder<Result<(), SequenceError>>,
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validate() {
+        let step = |channels: &[&str], duration_s| SequenceStep {
+            emotion: None,
+            channels: channels.iter().map(|c| (c.to_string(), true)).collect(),
+            duration_s,
+        };
+        let is_channel = |name: &str| ["eyes", "left_claw"].contains(&name);
+        let is_emotion = |emotion: &Emotion| emotion.as_str() == "Happy";
+
+        let show = Sequence {
+            steps: vec![step(&["eyes"], 2.), step(&["left_claw"], 0.5)],
+        };
+        assert_eq!(show.validate(is_emotion, is_channel), Ok(()));
+
+        let show = Sequence {
+            steps: vec![step(&["eyes", "tail"], 2.), step(&["claw"], 0.5)],
+        };
+        let e = show.validate(is_emotion, is_channel).unwrap_err();
+        assert_eq!(
+            e,
+            SequenceError::UnknownChannels(vec![(0, "tail".into()), (1, "claw".into())])
+        );
+        assert_eq!(
+            e.to_string(),
+            "unknown channels: steps[0].tail, steps[1].claw"
+        );
+
+        let mut show = Sequence {
+            steps: vec![step(&[], 1.), step(&[], 1.), step(&[], 1.)],
+        };
+        show.steps[0].emotion = Some(Emotion::new("Happy"));
+        show.steps[2].emotion = Some(Emotion::new("Sleepy"));
+        assert_eq!(
+            show.validate(is_emotion, is_channel),
+            Err(SequenceError::UnknownEmotion {
+                step: 2,
+                emotion: Emotion::new("Sleepy")
+            })
+        );
+
+        for duration_s in [0., -1., f64::INFINITY, 3601.] {
+            let show = Sequence {
+                steps: vec![step(&[], 1.), step(&[], duration_s)],
+            };
+            assert!(matches!(
+                show.validate(is_emotion, is_channel),
+                Err(SequenceError::InvalidDuration { step: 1, .. })
+            ));
+        }
+        assert_eq!(
+            Sequence { steps: vec![] }.validate(is_emotion, is_channel),
+            Err(SequenceError::Steps(0))
+        );
+
+        assert!(validate_name("wave-claws_2").is_ok());
+        for name in ["", "../crab", "a b", &"x".repeat(65)] {
+            assert!(validate_name(name).is_err(), "{name:?}");
+        }
+    }
+}
@@ -0,0 +1,40 @@
+//! Current state of the crab as reported through the REST API
+//!
+//! The main loop publishes a new [`CrabStatus`] whenever something changed.
+
+use crate::emotionmanager::Emotion;
+use crate::parameters::PressureLimits;
+
+#[derive(Debug, Default, Clone, PartialEq, utoipa::ToSchema, serde::Serialize)]
+pub struct CrabStatus {
+    /// `None` while the crab has not been told any emotion yet
+    pub emotion: Option<Emotion>,
+    pub sleeping: bool,
+    pub pressure: PressureStatus,
+    pub run_fan: bool,
+    /// A fault stopped the crab, see the active alarms
+    pub faulted: bool,
+    /// IDs of the active alarms
+    pub active_alarms: Vec<String>,
+    /// State of every light channel by name
+    pub channels: std::collections::BTreeMap<String, bool>,
+}
+
+/// Part of [`CrabStatus`] for `GET /crab/emotion`
+#[derive(Debug, Default, Clone, PartialEq, utoipa::ToSchema, serde::Serialize)]
+pub struct EmotionStatus {
+    pub emotion: Option<Emotion>,
+    pub sleeping: bool,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, utoipa::ToSchema, serde::Serialize)]
+pub struct PressureStatus {
+    /// Overpressure in millibar, `None` when the sensor has no valid value
+    pub pressure_mbar: Option<f64>,
+    pub limits: PressureLimits,
+    /// Pressure is at or beyond the respective limit
+    pub low_low: bool,
+    pub low: bool,
+    pub high: bool,
+    pub high_high: bool,
+}
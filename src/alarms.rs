@@ -0,0 +1,381 @@
+//! Individual alarms behind the fault indicator of the logic
+//!
+//! Every fault condition of the logic is tracked as its own alarm with a severity and timestamps
+//! for when it was raised, cleared and acknowledged.  Latching alarms stay active after their
+//! condition went away until the fault is reset.  The first fault alarm raised while no other
+//! fault was active is marked as the first-out alarm, as it most likely caused the others.
+
+use std::collections::VecDeque;
+use std::time::Instant;
+
+pub use crab_httpapi::alarms::{
+    AlarmEvent, AlarmEventKind, AlarmInfo, AlarmSeverity, AlarmsSnapshot,
+};
+
+/// Number of alarm events kept in the history
+const HISTORY_LENGTH: usize = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AlarmId {
+    Initializing,
+    EmergencyStop,
+    DcSupply,
+    FieldbusOffline,
+    FieldbusDiagnostic,
+    PressureSensor,
+    PressureHighHigh,
+    FanOvertime,
+    PressureLowLow,
+}
+
+impl AlarmId {
+    pub const ALL: &'static [AlarmId] = &[
+        AlarmId::Initializing,
+        AlarmId::EmergencyStop,
+        AlarmId::DcSupply,
+        AlarmId::FieldbusOffline,
+        AlarmId::FieldbusDiagnostic,
+        AlarmId::PressureSensor,
+        AlarmId::PressureHighHigh,
+        AlarmId::FanOvertime,
+        AlarmId::PressureLowLow,
+    ];
+
+    /// Identifier used in the APIs and metrics
+    pub fn name(self) -> &'static str {
+        match self {
+            AlarmId::Initializing => "initializing",
+            AlarmId::EmergencyStop => "emergency_stop",
+            AlarmId::DcSupply => "dc_supply",
+            AlarmId::FieldbusOffline => "fieldbus_offline",
+            AlarmId::FieldbusDiagnostic => "fieldbus_diagnostic",
+            AlarmId::PressureSensor => "pressure_sensor",
+            AlarmId::PressureHighHigh => "pressure_high_high",
+            AlarmId::FanOvertime => "fan_overtime",
+            AlarmId::PressureLowLow => "pressure_low_low",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.iter().copied().find(|id| id.name() == name)
+    }
+
+    pub fn description(self) -> &'static str {
+        match self {
+            AlarmId::Initializing => "Controller was (re)started",
+            AlarmId::EmergencyStop => "Emergency stop is pressed",
+            AlarmId::DcSupply => "24V DC supply is not OK",
+            AlarmId::FieldbusOffline => "Fieldbus or a peripheral is offline",
+            AlarmId::FieldbusDiagnostic => "A fieldbus peripheral reports a fault",
+            AlarmId::PressureSensor => "Pressure sensor is disconnected",
+            AlarmId::PressureHighHigh => "Pressure exceeded the HIGHHIGH limit",
+            AlarmId::FanOvertime => "Fan exceeded its maximum runtime",
+            AlarmId::PressureLowLow => "Pressure is below the LOWLOW limit",
+        }
+    }
+
+    pub fn severity(self) -> AlarmSeverity {
+        match self {
+            AlarmId::PressureLowLow => AlarmSeverity::Warning,
+            _ => AlarmSeverity::Fault,
+        }
+    }
+
+    /// Faults need to be reset by the operator, warnings go away on their own
+    pub fn latching(self) -> bool {
+        self.severity() == AlarmSeverity::Fault
+    }
+
+    fn index(self) -> usize {
+        Self::ALL.iter().position(|id| *id == self).unwrap()
+    }
+}
+
+impl std::fmt::Display for AlarmId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+struct AlarmState {
+    /// Whether the condition of the alarm is currently present
+    condition: bool,
+    active: bool,
+    acknowledged: bool,
+    raised_at: Option<Instant>,
+    cleared_at: Option<Instant>,
+    acknowledged_at: Option<Instant>,
+}
+
+#[derive(Debug, Clone)]
+pub struct AlarmManager {
+    states: Vec<AlarmState>,
+    first_out: Option<AlarmId>,
+    history: VecDeque<(Instant, AlarmId, AlarmEventKind)>,
+    /// Incremented on every change, to detect when the alarms need to be published
+    revision: u64,
+}
+
+impl Default for AlarmManager {
+    fn default() -> Self {
+        Self {
+            states: vec![AlarmState::default(); AlarmId::ALL.len()],
+            first_out: None,
+            history: VecDeque::with_capacity(HISTORY_LENGTH),
+            revision: 0,
+        }
+    }
+}
+
+impl AlarmManager {
+    /// Evaluate the condition of an alarm
+    pub fn update(&mut self, now: Instant, id: AlarmId, condition: bool) {
+        let state = &mut self.states[id.index()];
+        state.condition = condition;
+        if condition && !state.active {
+            self.raise(now, id);
+        } else if !condition && state.active && !id.latching() {
+            self.clear(now, id);
+        }
+
+        metrics::gauge!("crab_alarm_active", "alarm" => id.name())
+            .set(f64::from(self.is_active(id)));
+    }
+
+    /// Clear all latched alarms whose condition went away
+    pub fn reset(&mut self, now: Instant) {
+        for &id in AlarmId::ALL {
+            let state = &self.states[id.index()];
+            if state.active && !state.condition {
+                self.clear(now, id);
+            }
+        }
+    }
+
+    /// Acknowledge one or all active alarms, returning how many were acknowledged
+    pub fn acknowledge(&mut self, now: Instant, id: Option<AlarmId>) -> usize {
+        let mut count = 0;
+        for &alarm in AlarmId::ALL {
+            let state = &mut self.states[alarm.index()];
+            if id.is_some_and(|id| id != alarm) || !state.active || state.acknowledged {
+                continue;
+            }
+            state.acknowledged = true;
+            state.acknowledged_at = Some(now);
+            log::info!("Alarm acknowledged: {alarm}");
+            self.push_event(now, alarm, AlarmEventKind::Acknowledged);
+            count += 1;
+        }
+        count
+    }
+
+    pub fn is_active(&self, id: AlarmId) -> bool {
+        self.states[id.index()].active
+    }
+
+    /// Whether any alarm with fault severity is active
+    pub fn faulted(&self) -> bool {
+        self.active()
+            .any(|id| id.severity() == AlarmSeverity::Fault)
+    }
+
+    pub fn active(&self) -> impl Iterator<Item = AlarmId> + '_ {
+        AlarmId::ALL
+            .iter()
+            .copied()
+            .filter(|id| self.is_active(*id))
+    }
+
+    pub fn first_out(&self) -> Option<AlarmId> {
+        self.first_out
+    }
+
+    pub fn revision(&self) -> u64 {
+        self.revision
+    }
+
+    fn raise(&mut self, now: Instant, id: AlarmId) {
+        if id.severity() == AlarmSeverity::Fault && !self.faulted() {
+            self.first_out = Some(id);
+        }
+
+        let state = &mut self.states[id.index()];
+        state.active = true;
+        state.acknowledged = false;
+        state.raised_at = Some(now);
+        state.cleared_at = None;
+        state.acknowledged_at = None;
+
+        log::warn!("Alarm raised: {id} ({})", id.description());
+        metrics::counter!("crab_alarms_raised_total", "alarm" => id.name()).increment(1);
+        metrics::describe_counter!(
+            "crab_alarms_raised_total",
+            "Number of times each alarm was raised."
+        );
+        self.push_event(now, id, AlarmEventKind::Raised);
+    }
+
+    fn clear(&mut self, now: Instant, id: AlarmId) {
+        let state = &mut self.states[id.index()];
+        state.active = false;
+        state.cleared_at = Some(now);
+        if !self.faulted() {
+            self.first_out = None;
+        }
+
+        log::info!("Alarm cleared: {id}");
+        self.push_event(now, id, AlarmEventKind::Cleared);
+    }
+
+    fn push_event(&mut self, now: Instant, id: AlarmId, kind: AlarmEventKind) {
+        if self.history.len() == HISTORY_LENGTH {
+            self.history.pop_front();
+        }
+        self.history.push_back((now, id, kind));
+        self.revision += 1;
+    }
+
+    /// Convert the alarms for the APIs
+    ///
+    /// `now` and `wall_clock` are the same moment, to convert the timestamps into wall clock time.
+    pub fn snapshot(&self, now: Instant, wall_clock: std::time::SystemTime) -> AlarmsSnapshot {
+        let timestamp = |t: Instant| {
+            wall_clock
+                .checked_sub(now.saturating_duration_since(t))
+                .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+                .map(|t| t.as_secs_f64())
+                .unwrap_or_default()
+        };
+
+        let alarms = AlarmId::ALL
+            .iter()
+            .map(|&id| {
+                let state = &self.states[id.index()];
+                AlarmInfo {
+                    id: id.name().to_string(),
+                    description: id.description().to_string(),
+                    severity: id.severity(),
+                    latching: id.latching(),
+                    active: state.active,
+                    acknowledged: state.acknowledged,
+                    first_out: self.first_out == Some(id),
+                    raised_at: state.raised_at.map(timestamp),
+                    cleared_at: state.cleared_at.map(timestamp),
+                    acknowledged_at: state.acknowledged_at.map(timestamp),
+                }
+            })
+            .collect();
+
+        let history = self
+            .history
+            .iter()
+            .map(|&(t, id, kind)| AlarmEvent {
+                time: timestamp(t),
+                id: id.name().to_string(),
+                kind,
+            })
+            .collect();
+
+        AlarmsSnapshot { alarms, history }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn latching_and_first_out() {
+        let mut alarms = AlarmManager::default();
+        let t0 = Instant::now();
+
+        alarms.update(t0, AlarmId::EmergencyStop, true);
+        alarms.update(t0, AlarmId::DcSupply, true);
+        assert!(alarms.faulted());
+        assert_eq!(alarms.first_out(), Some(AlarmId::EmergencyStop));
+
+        // Latched after the condition went away
+        let t1 = t0 + Duration::from_secs(1);
+        alarms.update(t1, AlarmId::EmergencyStop, false);
+        alarms.update(t1, AlarmId::DcSupply, false);
+        assert!(alarms.is_active(AlarmId::EmergencyStop));
+
+        // Reset only clears alarms whose condition is gone
+        alarms.update(t1, AlarmId::DcSupply, true);
+        alarms.reset(t1);
+        assert!(!alarms.is_active(AlarmId::EmergencyStop));
+        assert!(alarms.is_active(AlarmId::DcSupply));
+        assert_eq!(alarms.first_out(), Some(AlarmId::EmergencyStop));
+
+        alarms.update(t1, AlarmId::DcSupply, false);
+        alarms.reset(t1);
+        assert!(!alarms.faulted());
+        assert_eq!(alarms.first_out(), None);
+    }
+
+    #[test]
+    fn warnings() {
+        let mut alarms = AlarmManager::default();
+        let t0 = Instant::now();
+
+        alarms.update(t0, AlarmId::PressureLowLow, true);
+        assert!(alarms.is_active(AlarmId::PressureLowLow));
+        assert!(!alarms.faulted());
+        assert_eq!(alarms.first_out(), None);
+
+        alarms.update(t0, AlarmId::PressureLowLow, false);
+        assert!(!alarms.is_active(AlarmId::PressureLowLow));
+    }
+
+    #[test]
+    fn acknowledge_and_history() {
+        let mut alarms = AlarmManager::default();
+        let t0 = Instant::now();
+        let wall_clock = std::time::SystemTime::now();
+
+        alarms.update(t0, AlarmId::FanOvertime, true);
+        alarms.update(t0, AlarmId::PressureLowLow, true);
+        let t1 = t0 + Duration::from_secs(2);
+        assert_eq!(alarms.acknowledge(t1, Some(AlarmId::FanOvertime)), 1);
+        assert_eq!(alarms.acknowledge(t1, None), 1);
+        assert_eq!(alarms.acknowledge(t1, None), 0);
+
+        let snapshot = alarms.snapshot(t1, wall_clock);
+        let info = snapshot
+            .alarms
+            .iter()
+            .find(|a| a.id == "fan_overtime")
+            .unwrap();
+        assert!(info.active && info.acknowledged && info.first_out);
+        let raised =
+            wall_clock.duration_since(std::time::UNIX_EPOCH).unwrap() - Duration::from_secs(2);
+        assert!((info.raised_at.unwrap() - raised.as_secs_f64()).abs() < 1e-3);
+
+        let kinds: Vec<_> = snapshot.history.iter().map(|e| e.kind).collect();
+        assert_eq!(
+            kinds,
+            [
+                AlarmEventKind::Raised,
+                AlarmEventKind::Raised,
+                AlarmEventKind::Acknowledged,
+                AlarmEventKind::Acknowledged
+            ]
+        );
+
+        // A new raise needs a new acknowledgement
+        alarms.update(t1, AlarmId::PressureLowLow, false);
+        alarms.update(t1, AlarmId::PressureLowLow, true);
+        assert_eq!(alarms.acknowledge(t1, None), 1);
+
+        for _ in 0..HISTORY_LENGTH {
+            alarms.update(t1, AlarmId::PressureLowLow, false);
+            alarms.update(t1, AlarmId::PressureLowLow, true);
+        }
+        assert_eq!(
+            alarms.snapshot(t1, wall_clock).history.len(),
+            HISTORY_LENGTH
+        );
+    }
+}
@@ -0,0 +1,233 @@
+//! Runtime configuration of the crab control center
+//!
+//! The configuration is read from `crab.toml` in the working directory.  A different file can be
+//! selected with `--config <path>` or the `CRAB_CONFIG` environment variable.  All settings have
+//! defaults matching the event rig, so a missing file is not an error.
+//!
+//! Individual settings can be overridden from the command line or the environment, the command
+//! line taking precedence:
+//!
+//! | CLI              | Environment           | Setting                    |
+//! |------------------|-----------------------|----------------------------|
+//! | `--bus-device`   | `CRAB_BUS_DEVICE`     | `fieldbus.bus_device`      |
+//! | `--baudrate`     | `CRAB_BAUDRATE`       | `fieldbus.baudrate`        |
+//! | `--master-address` | `CRAB_MASTER_ADDRESS` | `fieldbus.master_address` |
+//! | `--simulate-fieldbus` | `CRAB_SIMULATE_FIELDBUS` | `fieldbus.simulate`   |
+//! | `--iomap`        | `CRAB_IOMAP`          | `iomap.path`               |
+//! | `--plant`        | `CRAB_PLANT`          | `plant.enabled`            |
+//! | `--plant-leak-rate` | `CRAB_PLANT_LEAK_RATE` | `plant.leak_lps_per_mbar` |
+//! | `--record`       | `CRAB_RECORD`         | `recording.record`         |
+//! | `--replay`       | `CRAB_REPLAY`         | `recording.replay`         |
+//! | `--replay-speed` | `CRAB_REPLAY_SPEED`   | `recording.replay_speed`   |
+//! | `--settings`     | `CRAB_SETTINGS`       | `settings.path`            |
+//! | `--history`      | `CRAB_HISTORY`        | `history.path`             |
+//! | `--audit`        | `CRAB_AUDIT`          | `audit.path`               |
+//! | `--sequences`    | `CRAB_SEQUENCES`      | `sequences.path`           |
+//! | `--emotions`     | `CRAB_EMOTIONS`       | `emotion.patterns`         |
+//!
+//! Token hashes for the `[auth]` section are printed by `--hash-token`, which reads the token from
+//! standard input.
+
+const DEFAULT_CONFIG_PATH: &str = "crab.toml";
+
+#[derive(Debug, Default, Clone, serde::Deserialize)]
+#[serde(default)]
+pub struct Config {
+    #[cfg(feature = "fieldbus")]
+    pub fieldbus: crate::fieldbus::FieldbusConfig,
+    #[cfg(feature = "fieldbus")]
+    pub iomap: crate::iomap::IoMapConfig,
+    pub plant: crate::plant::PlantConfig,
+    pub recording: crate::recording::RecordingConfig,
+    pub settings: crate::settings::SettingsConfig,
+    pub history: crate::history::HistoryConfig,
+    pub auth: crab_httpapi::auth::AuthConfig,
+    pub audit: crab_httpapi::audit::AuditConfig,
+    pub emotion: crab_httpapi::emotionmanager::EmotionConfig,
+    pub sequences: crate::sequences::SequencesConfig,
+    pub sentiment: crab_httpapi::sentiment::SentimentConfig,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(std::path::PathBuf, std::io::Error),
+    Parse(toml::de::Error),
+    Invalid(String),
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::Io(path, e) => write!(f, "failed reading {}: {e}", path.display()),
+            ConfigError::Parse(e) => write!(f, "failed parsing configuration: {e}"),
+            ConfigError::Invalid(reason) => write!(f, "invalid configuration: {reason}"),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Overrides for individual settings, collected from the command line and environment
+#[derive(Debug, Default)]
+struct Overrides {
+    config_path: Option<std::path::PathBuf>,
+    values: Vec<(&'static str, String)>,
+}
+
+impl Overrides {
+    const KEYS: &'static [(&'static str, &'static str, &'static str)] = &[
+        ("--bus-device", "CRAB_BUS_DEVICE", "fieldbus.bus_device"),
+        ("--baudrate", "CRAB_BAUDRATE", "fieldbus.baudrate"),
+        (
+            "--master-address",
+            "CRAB_MASTER_ADDRESS",
+            "fieldbus.master_address",
+        ),
+        (
+            "--simulate-fieldbus",
+            "CRAB_SIMULATE_FIELDBUS",
+            "fieldbus.simulate",
+        ),
+        ("--iomap", "CRAB_IOMAP", "iomap.path"),
+        ("--plant", "CRAB_PLANT", "plant.enabled"),
+        (
+            "--plant-leak-rate",
+            "CRAB_PLANT_LEAK_RATE",
+            "plant.leak_lps_per_mbar",
+        ),
+        ("--record", "CRAB_RECORD", "recording.record"),
+        ("--replay", "CRAB_REPLAY", "recording.replay"),
+        (
+            "--replay-speed",
+            "CRAB_REPLAY_SPEED",
+            "recording.replay_speed",
+        ),
+        ("--settings", "CRAB_SETTINGS", "settings.path"),
+        ("--history", "CRAB_HISTORY", "history.path"),
+        ("--audit", "CRAB_AUDIT", "audit.path"),
+        ("--sequences", "CRAB_SEQUENCES", "sequences.path"),
+        ("--emotions", "CRAB_EMOTIONS", "emotion.patterns"),
+    ];
+
+    fn collect() -> Result<Self, ConfigError> {
+        let mut overrides = Overrides {
+            config_path: std::env::var_os("CRAB_CONFIG").map(Into::into),
+            values: Vec::new(),
+        };
+
+        for (_, env, key) in Self::KEYS {
+            if let Ok(value) = std::env::var(env) {
+                overrides.values.push((key, value));
+            }
+        }
+
+        let mut args = std::env::args().skip(1);
+        while let Some(arg) = args.next() {
+            let mut value = || {
+                args.next()
+                    .ok_or_else(|| ConfigError::Invalid(format!("missing value for {arg}")))
+            };
+            if arg == "--config" {
+                overrides.config_path = Some(value()?.into());
+            } else if let Some((_, _, key)) = Self::KEYS.iter().find(|(a, _, _)| *a == arg) {
+                overrides.values.push((key, value()?));
+            } else {
+                return Err(ConfigError::Invalid(format!("unknown argument {arg}")));
+            }
+        }
+
+        Ok(overrides)
+    }
+}
+
+impl Config {
+    /// Load the configuration, applying command line and environment overrides
+    pub fn load() -> Result<Self, ConfigError> {
+        let overrides = Overrides::collect()?;
+
+        let path = overrides
+            .config_path
+            .clone()
+            .unwrap_or_else(|| DEFAULT_CONFIG_PATH.into());
+        let mut config = match std::fs::read_to_string(&path) {
+            Ok(s) => toml::from_str(&s).map_err(ConfigError::Parse)?,
+            Err(e)
+                if e.kind() == std::io::ErrorKind::NotFound && overrides.config_path.is_none() =>
+            {
+                log::info!("No configuration file found, using defaults.");
+                Config::default()
+            }
+            Err(e) => return Err(ConfigError::Io(path, e)),
+        };
+
+        for (key, value) in overrides.values.iter() {
+            config.set(key, value)?;
+        }
+
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    fn set(&mut self, key: &str, value: &str) -> Result<(), ConfigError> {
+        match key {
+            #[cfg(feature = "fieldbus")]
+            "fieldbus.bus_device" => self.fieldbus.bus_device = value.to_owned(),
+            #[cfg(feature = "fieldbus")]
+            "fieldbus.baudrate" => self.fieldbus.baudrate = parse_value(key, value)?,
+            #[cfg(feature = "fieldbus")]
+            "fieldbus.master_address" => self.fieldbus.master_address = parse_value(key, value)?,
+            #[cfg(feature = "fieldbus")]
+            "fieldbus.simulate" => self.fieldbus.simulate = parse_value(key, value)?,
+            #[cfg(feature = "fieldbus")]
+            "iomap.path" => self.iomap.path = value.into(),
+            "plant.enabled" => self.plant.enabled = parse_value(key, value)?,
+            "plant.leak_lps_per_mbar" => self.plant.leak_lps_per_mbar = parse_value(key, value)?,
+            "recording.record" => self.recording.record = Some(value.into()),
+            "recording.replay" => self.recording.replay = Some(value.into()),
+            "recording.replay_speed" => self.recording.replay_speed = parse_value(key, value)?,
+            "settings.path" => self.settings.path = value.into(),
+            "history.path" => self.history.path = Some(value.into()),
+            "audit.path" => self.audit.path = Some(value.into()),
+            "sequences.path" => self.sequences.path = Some(value.into()),
+            "emotion.patterns" => self.emotion.patterns = value.into(),
+            _ => log::warn!("Ignoring override {key}={value}, not supported by this build."),
+        }
+
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        #[cfg(feature = "fieldbus")]
+        self.fieldbus.validate().map_err(ConfigError::Invalid)?;
+        self.plant.validate().map_err(ConfigError::Invalid)?;
+        self.recording.validate().map_err(ConfigError::Invalid)?;
+        self.history.validate().map_err(ConfigError::Invalid)?;
+        self.auth.validate().map_err(ConfigError::Invalid)?;
+        self.emotion.validate().map_err(ConfigError::Invalid)?;
+        self.sentiment.validate().map_err(ConfigError::Invalid)?;
+
+        Ok(())
+    }
+}
+
+fn parse_value<T>(key: &str, value: &str) -> Result<T, ConfigError>
+where
+    T: std::str::FromStr,
+    T::Err: std::fmt::Display,
+{
+    value
+        .parse()
+        .map_err(|e| ConfigError::Invalid(format!("bad value \"{value}\" for {key}: {e}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn example_shows_defaults() {
+        let example: Config = toml::from_str(include_str!("../crab.example.toml")).unwrap();
+        assert_eq!(example.sentiment, Default::default());
+    }
+}
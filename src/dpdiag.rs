@@ -0,0 +1,376 @@
+//! Decoding of PROFIBUS DP peripheral diagnostics
+//!
+//! The DP master hands us the standard diagnostics flags and the raw extended diagnostics
+//! blocks.  The extended diagnostics are decoded according to IEC 61158-6-3, with the
+//! device-related block interpreted as the error code/argument pair reported by the
+//! WAGO 750-343 fieldbus coupler.
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "graphql", derive(juniper::GraphQLObject))]
+pub struct StationDiagnostics {
+    /// Name of the peripheral from the configuration
+    pub peripheral: String,
+    pub address: i32,
+    /// Standard diagnostics flags as reported by the DP master
+    pub flags: DiagnosticFlags,
+    /// Slots of modules which report a diagnosis (identifier-related diagnostics)
+    pub modules: Vec<i32>,
+    pub channels: Vec<ChannelDiagnosis>,
+    pub device: Option<DeviceDiagnosis>,
+}
+
+/// Station status bits of the standard diagnostics (IEC 61158-6-3)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "graphql", derive(juniper::GraphQLObject))]
+pub struct DiagnosticFlags {
+    /// The peripheral is not ready for data exchange
+    pub station_not_ready: bool,
+    /// The configuration does not match the plugged modules
+    pub configuration_fault: bool,
+    /// Extended diagnostics blocks are available
+    pub extended_diagnostics: bool,
+    /// The peripheral does not support a requested function
+    pub not_supported: bool,
+    /// The parameters were rejected
+    pub parameter_fault: bool,
+    /// The peripheral needs to be parameterized again
+    pub parameter_required: bool,
+    /// The peripheral cannot provide valid data until its diagnostics are resolved
+    pub static_diagnostics: bool,
+    pub watchdog_on: bool,
+    pub freeze_mode: bool,
+    pub sync_mode: bool,
+}
+
+impl DiagnosticFlags {
+    /// Decode the two station status bytes, as found in profirust's `DiagnosticFlags`
+    pub fn from_bits(bits: u16) -> Self {
+        let bit = |n: u32| bits & (1 << n) != 0;
+        Self {
+            station_not_ready: bit(1),
+            configuration_fault: bit(2),
+            extended_diagnostics: bit(3),
+            not_supported: bit(4),
+            parameter_fault: bit(6),
+            parameter_required: bit(8),
+            static_diagnostics: bit(9),
+            watchdog_on: bit(11),
+            freeze_mode: bit(12),
+            sync_mode: bit(13),
+        }
+    }
+
+    /// All flags by name, for logging and the metrics
+    pub fn iter(&self) -> impl Iterator<Item = (&'static str, bool)> {
+        [
+            ("station_not_ready", self.station_not_ready),
+            ("configuration_fault", self.configuration_fault),
+            ("extended_diagnostics", self.extended_diagnostics),
+            ("not_supported", self.not_supported),
+            ("parameter_fault", self.parameter_fault),
+            ("parameter_required", self.parameter_required),
+            ("static_diagnostics", self.static_diagnostics),
+            ("watchdog_on", self.watchdog_on),
+            ("freeze_mode", self.freeze_mode),
+            ("sync_mode", self.sync_mode),
+        ]
+        .into_iter()
+    }
+
+    /// Whether the flags keep the peripheral from exchanging valid data
+    pub fn is_fault(&self) -> bool {
+        self.station_not_ready
+            || self.configuration_fault
+            || self.not_supported
+            || self.parameter_fault
+            || self.parameter_required
+            || self.static_diagnostics
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "graphql", derive(juniper::GraphQLObject))]
+pub struct ChannelDiagnosis {
+    /// Slot of the module, counting from 0 for the coupler itself
+    pub slot: i32,
+    pub channel: i32,
+    pub direction: ChannelDirection,
+    pub error: ChannelError,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "graphql", derive(juniper::GraphQLEnum))]
+pub enum ChannelDirection {
+    Unknown,
+    Input,
+    Output,
+    InputOutput,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "graphql", derive(juniper::GraphQLEnum))]
+pub enum ChannelError {
+    ShortCircuit,
+    Undervoltage,
+    Overvoltage,
+    Overload,
+    Overtemperature,
+    WireBreak,
+    UpperLimitExceeded,
+    LowerLimitExceeded,
+    GeneralError,
+    Reserved,
+    ManufacturerSpecific,
+}
+
+/// Error code and argument from the device-related diagnostics of the WAGO 750-343
+///
+/// The codes correspond to the blink codes of the I/O LED described in the coupler manual.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "graphql", derive(juniper::GraphQLObject))]
+pub struct DeviceDiagnosis {
+    pub code: i32,
+    pub argument: i32,
+    pub description: String,
+}
+
+impl DeviceDiagnosis {
+    fn from_code(code: u8, argument: u8) -> Self {
+        let description = match code {
+            1 => "Hardware and configuration error",
+            2 => "Error in the terminal configuration",
+            3 => "K-Bus protocol error",
+            4 => "K-Bus physical error",
+            5 => "K-Bus initialization error",
+            6 => "Node configuration error",
+            7 => "Unsupported I/O module",
+            8 => "Controller configuration error",
+            _ => "Unknown error",
+        };
+        Self {
+            code: code.into(),
+            argument: argument.into(),
+            description: description.to_string(),
+        }
+    }
+
+    /// Whether this diagnosis indicates a failure of the internal K-Bus
+    pub fn is_kbus_fault(&self) -> bool {
+        matches!(self.code, 3..=5)
+    }
+}
+
+impl ChannelError {
+    fn from_code(code: u8) -> Self {
+        match code {
+            1 => ChannelError::ShortCircuit,
+            2 => ChannelError::Undervoltage,
+            3 => ChannelError::Overvoltage,
+            4 => ChannelError::Overload,
+            5 => ChannelError::Overtemperature,
+            6 => ChannelError::WireBreak,
+            7 => ChannelError::UpperLimitExceeded,
+            8 => ChannelError::LowerLimitExceeded,
+            9 => ChannelError::GeneralError,
+            16..=31 => ChannelError::ManufacturerSpecific,
+            _ => ChannelError::Reserved,
+        }
+    }
+}
+
+impl StationDiagnostics {
+    /// Decode the extended diagnostics blocks of a peripheral
+    pub fn decode(peripheral: &str, address: u8, flags: DiagnosticFlags, ext_diag: &[u8]) -> Self {
+        let mut diag = StationDiagnostics {
+            peripheral: peripheral.to_string(),
+            address: address.into(),
+            flags,
+            modules: Vec::new(),
+            channels: Vec::new(),
+            device: None,
+        };
+
+        let mut rest = ext_diag;
+        while let Some(&header) = rest.first() {
+            let (block, remainder) = match header >> 6 {
+                // Channel-related diagnostics are always 3 bytes long
+                0b10 => match rest.split_at_checked(3) {
+                    Some(split) => split,
+                    None => break,
+                },
+                _ => match rest.split_at_checked(usize::from(header & 0x3f)) {
+                    Some(split) if !split.0.is_empty() => split,
+                    _ => break,
+                },
+            };
+            rest = remainder;
+
+            match header >> 6 {
+                0b00 => {
+                    if let [_, code, argument, ..] = *block
+                        && code != 0
+                    {
+                        diag.device = Some(DeviceDiagnosis::from_code(code, argument));
+                    }
+                }
+                0b01 => {
+                    for (i, byte) in block[1..].iter().enumerate() {
+                        for bit in 0..8 {
+                            if byte & (1 << bit) != 0 {
+                                diag.modules.push((i * 8 + bit) as i32);
+                            }
+                        }
+                    }
+                }
+                0b10 => diag.channels.push(ChannelDiagnosis {
+                    slot: (block[0] & 0x3f).into(),
+                    channel: (block[1] & 0x3f).into(),
+                    direction: match block[1] >> 6 {
+                        0b01 => ChannelDirection::Input,
+                        0b10 => ChannelDirection::Output,
+                        0b11 => ChannelDirection::InputOutput,
+                        _ => ChannelDirection::Unknown,
+                    },
+                    error: ChannelError::from_code(block[2] & 0x1f),
+                }),
+                _ => {
+                    log::warn!(
+                        "Peripheral \"{peripheral}\": Unknown diagnostics block header {header:#04x}."
+                    );
+                }
+            }
+        }
+
+        if !rest.is_empty() {
+            log::warn!(
+                "Peripheral \"{peripheral}\": Malformed extended diagnostics {:02x?}.",
+                ext_diag
+            );
+        }
+
+        diag
+    }
+
+    /// Whether the diagnostics indicate a fault that affects the crab's I/O
+    pub fn is_fault(&self) -> bool {
+        self.flags.is_fault()
+            || !self.channels.is_empty()
+            || self
+                .device
+                .as_ref()
+                .map(|d| d.is_kbus_fault())
+                .unwrap_or(false)
+    }
+
+    /// Log the diagnostics in a structured way
+    pub fn log(&self) {
+        let Self {
+            peripheral,
+            address,
+            ..
+        } = self;
+        if !self.is_fault() && self.modules.is_empty() && self.device.is_none() {
+            log::info!("Peripheral \"{peripheral}\" (#{address}): Diagnostics cleared.");
+            return;
+        }
+        let flags: Vec<_> = self
+            .flags
+            .iter()
+            .filter_map(|(name, set)| set.then_some(name))
+            .collect();
+        log::warn!(
+            "Peripheral \"{peripheral}\" (#{address}): Diagnostics {}",
+            flags.join(", ")
+        );
+        if let Some(device) = &self.device {
+            log::warn!(
+                "Peripheral \"{peripheral}\" (#{address}): {} (code {}, argument {})",
+                device.description,
+                device.code,
+                device.argument
+            );
+        }
+        for slot in self.modules.iter() {
+            log::warn!(
+                "Peripheral \"{peripheral}\" (#{address}): Module in slot {slot} reports a diagnosis"
+            );
+        }
+        for channel in self.channels.iter() {
+            log::warn!(
+                "Peripheral \"{peripheral}\" (#{address}): Slot {} channel {} ({:?}): {:?}",
+                channel.slot,
+                channel.channel,
+                channel.direction,
+                channel.error
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_wire_break() {
+        // Identifier-related block (slot 7), channel-related block (slot 7, input channel 0,
+        // 16-bit, wire break)
+        let ext_diag = [0x42, 0x80, 0x87, 0x40, 0xa6];
+        let diag = StationDiagnostics::decode("io", 8, DiagnosticFlags::default(), &ext_diag);
+
+        assert_eq!(diag.modules, vec![7]);
+        assert_eq!(
+            diag.channels,
+            vec![ChannelDiagnosis {
+                slot: 7,
+                channel: 0,
+                direction: ChannelDirection::Input,
+                error: ChannelError::WireBreak,
+            }]
+        );
+        assert!(diag.device.is_none());
+        assert!(diag.is_fault());
+    }
+
+    #[test]
+    fn decode_kbus_fault() {
+        let ext_diag = [0x03, 0x04, 0x05];
+        let diag = StationDiagnostics::decode("io", 8, DiagnosticFlags::default(), &ext_diag);
+
+        let device = diag.device.as_ref().unwrap();
+        assert_eq!(device.code, 4);
+        assert_eq!(device.argument, 5);
+        assert!(diag.is_fault());
+    }
+
+    #[test]
+    fn decode_malformed() {
+        // Truncated channel block must not panic
+        let diag = StationDiagnostics::decode("io", 8, DiagnosticFlags::default(), &[0x87, 0x40]);
+        assert!(diag.channels.is_empty());
+
+        // Zero-length block must not loop forever
+        let diag = StationDiagnostics::decode("io", 8, DiagnosticFlags::default(), &[0x00, 0x00]);
+        assert!(!diag.is_fault());
+
+        let diag = StationDiagnostics::decode("io", 8, DiagnosticFlags::default(), &[]);
+        assert!(!diag.is_fault());
+    }
+
+    #[test]
+    fn flags_fault() {
+        // Station status 1: configuration fault and extended diagnostics, status 2: watchdog on
+        let flags = DiagnosticFlags::from_bits(0x080c);
+        assert!(flags.configuration_fault);
+        assert!(flags.extended_diagnostics);
+        assert!(flags.watchdog_on);
+        assert!(!flags.parameter_fault);
+
+        let diag = StationDiagnostics::decode("io", 8, flags, &[]);
+        assert!(diag.is_fault());
+
+        let flags = DiagnosticFlags::from_bits(0x0808);
+        let diag = StationDiagnostics::decode("io", 8, flags, &[]);
+        assert!(!diag.is_fault());
+    }
+}
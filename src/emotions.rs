@@ -0,0 +1,118 @@
+//! Channel patterns of the emotions
+//!
+//! Each emotion switches some channels on or off on top of the base pattern of the logic.  The
+//! emotions are read from a TOML file with one table per emotion, so new ones can be added
+//! without touching the logic.  The `emotions.toml` of the repository is built in as default.
+
+use crate::logic::{Channels, Emotion};
+
+const BUILTIN: &str = include_str!("../emotions.toml");
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Emotions {
+    patterns: std::collections::BTreeMap<Emotion, std::collections::BTreeMap<String, bool>>,
+}
+
+impl Default for Emotions {
+    fn default() -> Self {
+        Self::parse(BUILTIN).expect("built-in emotions are valid")
+    }
+}
+
+impl Emotions {
+    /// Load the emotions, falling back to the built-in ones if the file does not exist
+    pub fn load(path: &std::path::Path) -> Result<Self, String> {
+        match std::fs::read_to_string(path) {
+            Ok(s) => Self::parse(&s).map_err(|e| format!("bad emotions {}: {e}", path.display())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                log::info!(
+                    "No emotions found at {}, using the built-in ones.",
+                    path.display()
+                );
+                Ok(Self::default())
+            }
+            Err(e) => Err(format!("failed reading {}: {e}", path.display())),
+        }
+    }
+
+    fn parse(s: &str) -> Result<Self, String> {
+        let emotions = Self {
+            patterns: toml::from_str(s).map_err(|e| e.to_string())?,
+        };
+        if emotions.patterns.is_empty() {
+            return Err("no emotions defined".to_string());
+        }
+        for (emotion, pattern) in emotions.patterns.iter() {
+            emotion.validate()?;
+            let mut channels = Channels::default();
+            if let Some(name) = pattern.keys().find(|name| channels.get_mut(name).is_none()) {
+                return Err(format!("emotion {emotion} sets unknown channel {name:?}"));
+            }
+        }
+        Ok(emotions)
+    }
+
+    pub fn contains(&self, emotion: &Emotion) -> bool {
+        self.patterns.contains_key(emotion)
+    }
+
+    /// Names of all emotions
+    pub fn names(&self) -> impl Iterator<Item = &Emotion> {
+        self.patterns.keys()
+    }
+
+    /// Switch the channels of `emotion`, unknown emotions leave the channels as they are
+    pub fn apply(&self, emotion: &Emotion, channels: &mut Channels) {
+        let Some(pattern) = self.patterns.get(emotion) else {
+            return;
+        };
+        for (name, &on) in pattern.iter() {
+            if let Some(channel) = channels.get_mut(name) {
+                *channel = on;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let emotions = Emotions::default();
+        let names: Vec<_> = emotions.names().map(Emotion::as_str).collect();
+        assert_eq!(names, ["Angered", "Happy", "Neutral", "Sad", "Surprised"]);
+
+        let emotions = Emotions::parse(
+            r#"
+            [Wink]
+            pupil_top = false
+            eyes = true
+            "#,
+        )
+        .unwrap();
+        let mut channels = Channels {
+            pupil_top: true,
+            ..Default::default()
+        };
+        emotions.apply(&Emotion::new("Wink"), &mut channels);
+        assert!(!channels.pupil_top);
+        assert!(channels.eyes);
+        emotions.apply(&Emotion::new("Happy"), &mut channels);
+        assert!(channels.eyes);
+
+        for (s, error) in [
+            ("", "no emotions defined"),
+            (
+                "[Wink]\ntail = true",
+                "emotion Wink sets unknown channel \"tail\"",
+            ),
+            ("[Wink]\neyes = 1", "invalid type"),
+            ("[\"so happy\"]\neyes = true", "invalid emotion name"),
+        ] {
+            let e = Emotions::parse(s).unwrap_err();
+            assert!(e.contains(error), "{e}");
+        }
+    }
+}
@@ -1,3 +1,4 @@
+use std::collections::BTreeMap;
 use std::sync::{Arc, Mutex};
 
 use profirust::dp;
@@ -6,42 +7,352 @@ use profirust::phy;
 
 pub use dp::OperatingState;
 
-// I/O Station Parameters
-const IO_STATION_ADDRESS: u8 = 8;
+use crate::dpdiag::{DiagnosticFlags, StationDiagnostics};
+pub use crate::iomap::{PII_SIZE, PIQ_SIZE};
+
+/// Bus parameters, loaded from the `[fieldbus]` section of the configuration
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FieldbusConfig {
+    pub master_address: u8,
+    pub bus_device: String,
+    /// Baudrate in bit/s
+    pub baudrate: u64,
+    pub slot_bits: u16,
+    pub watchdog_timeout_ms: u64,
+    pub sleep_time_ms: u64,
+    /// Simulate the peripherals on a pseudo-terminal instead of using `bus_device`
+    pub simulate: bool,
+    #[serde(rename = "peripheral")]
+    pub peripherals: Vec<PeripheralConfig>,
+}
+
+impl Default for FieldbusConfig {
+    fn default() -> Self {
+        Self {
+            master_address: 3,
+            bus_device: "/dev/ttyUSB0".to_string(),
+            baudrate: 19200,
+            // We use a rather large T_slot time because USB-RS485 converters
+            // can induce large delays at times.
+            slot_bits: 576,
+            watchdog_timeout_ms: 500,
+            sleep_time_ms: 10,
+            simulate: false,
+            peripherals: vec![PeripheralConfig::wago_750_343()],
+        }
+    }
+}
+
+impl FieldbusConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        parse_baudrate(self.baudrate)?;
+        if self.master_address > 125 {
+            return Err(format!(
+                "master address {} is not a valid station address",
+                self.master_address
+            ));
+        }
+        if self.simulate && !cfg!(feature = "simulator") {
+            return Err("fieldbus simulation requires the \"simulator\" feature".to_string());
+        }
+
+        for (i, peripheral) in self.peripherals.iter().enumerate() {
+            peripheral.validate(self.master_address)?;
+            peripheral.max_tsdr(self.baudrate)?;
+
+            for other in self.peripherals[..i].iter() {
+                if other.address == peripheral.address {
+                    return Err(format!(
+                        "peripherals \"{}\" and \"{}\" use the same address {}",
+                        other.name, peripheral.name, peripheral.address
+                    ));
+                }
+                if ranges_overlap(other.pii_range(), peripheral.pii_range()) {
+                    return Err(format!(
+                        "PII areas of peripherals \"{}\" and \"{}\" overlap",
+                        other.name, peripheral.name
+                    ));
+                }
+                if ranges_overlap(other.piq_range(), peripheral.piq_range()) {
+                    return Err(format!(
+                        "PIQ areas of peripherals \"{}\" and \"{}\" overlap",
+                        other.name, peripheral.name
+                    ));
+                }
+                if other.liveness_bit == peripheral.liveness_bit {
+                    return Err(format!(
+                        "peripherals \"{}\" and \"{}\" use the same liveness bit",
+                        other.name, peripheral.name
+                    ));
+                }
+            }
+
+            // Liveness bits must not collide with the input data of any peripheral
+            let liveness_byte = peripheral.liveness_bit.0;
+            if let Some(other) = self
+                .peripherals
+                .iter()
+                .find(|p| p.pii_range().contains(&liveness_byte))
+            {
+                return Err(format!(
+                    "liveness bit of peripheral \"{}\" overlaps the PII area of \"{}\"",
+                    peripheral.name, other.name
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn bus_parameters(&self) -> (fdl::ParametersBuilder, std::time::Duration) {
+        let baudrate = parse_baudrate(self.baudrate).unwrap();
+        let mut parameters = fdl::ParametersBuilder::new(self.master_address, baudrate);
+        parameters.slot_bits(self.slot_bits).watchdog_timeout(
+            profirust::time::Duration::from_millis(self.watchdog_timeout_ms),
+        );
+
+        let sleep_time = std::time::Duration::from_millis(self.sleep_time_ms);
+
+        (parameters, sleep_time)
+    }
+}
+
+fn ranges_overlap(a: std::ops::Range<usize>, b: std::ops::Range<usize>) -> bool {
+    a.start < b.end && b.start < a.end
+}
+
+fn parse_baudrate(baudrate: u64) -> Result<profirust::Baudrate, String> {
+    Ok(match baudrate {
+        9600 => profirust::Baudrate::B9600,
+        19200 => profirust::Baudrate::B19200,
+        93750 => profirust::Baudrate::B93750,
+        187500 => profirust::Baudrate::B187500,
+        500000 => profirust::Baudrate::B500000,
+        1500000 => profirust::Baudrate::B1500000,
+        3000000 => profirust::Baudrate::B3000000,
+        6000000 => profirust::Baudrate::B6000000,
+        12000000 => profirust::Baudrate::B12000000,
+        b => return Err(format!("unsupported baudrate {b}")),
+    })
+}
+
+/// Parameters of a DP peripheral, usually generated by `gsdtool` from the GSD file
+///
+/// Everything that identifies the station and places its data in the process images is
+/// required, only the generic options have defaults.
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PeripheralConfig {
+    pub name: String,
+    pub address: u8,
+    pub ident_number: u16,
+    #[serde(default)]
+    pub user_parameters: Vec<u8>,
+    pub config: Vec<u8>,
+    /// Maximum T_sdr (in bits) for each supported baudrate, as listed in the GSD file
+    pub max_tsdr: BTreeMap<String, u16>,
+    #[serde(default)]
+    pub fail_safe: bool,
+    pub input_size: usize,
+    pub output_size: usize,
+    #[serde(default = "default_diag_size")]
+    pub diag_size: usize,
+    /// Offset of the peripheral's input data in the global PII
+    pub pii_offset: usize,
+    /// Offset of the peripheral's output data in the global PIQ
+    pub piq_offset: usize,
+    /// Bit in the global PII (byte, bit) which indicates that the peripheral is running
+    pub liveness_bit: (usize, u8),
+}
+
+fn default_diag_size() -> usize {
+    64
+}
+
+impl PeripheralConfig {
+    /// The crab's own station, the default contents of the peripheral list
+    pub fn wago_750_343() -> Self {
+        // Options generated by `gsdtool` using "wagob757.gsd"
+        Self {
+            name: "WAGO 750-343".to_string(),
+            address: 8,
+            ident_number: 0xb757,
+
+            // Global Parameters:
+            //   - DP-Watchdog-Base...............: 10 ms
+            //   - Restart on K-Bus Failure.......: POWER ON RESET
+            //   - Device Diagnosis...............: enabled
+            //   - Process Data Representation....: MOTOROLA (MSB-LSB)
+            //   - Response to PROFIBUS DP Failure: Output image is cleared
+            //   - Response to K-Bus Failure......: PROFIBUS communication stops
+            //
+            // Selected Modules:
+            //   [0] 750-343 No PI Channel
+            //   [1] 750-504  4 DO/24 V DC/0.5 A
+            //       - Terminal is physically....: plugged
+            //       - Substitude Value Channel 1: 0
+            //       - Substitude Value Channel 2: 0
+            //       - Substitude Value Channel 3: 0
+            //       - Substitude Value Channel 4: 0
+            //   [2] 750-504  4 DO/24 V DC/0.5 A
+            //       - Terminal is physically....: plugged
+            //       - Substitude Value Channel 1: 0
+            //       - Substitude Value Channel 2: 0
+            //       - Substitude Value Channel 3: 0
+            //       - Substitude Value Channel 4: 0
+            //   [3] 750-504  4 DO/24 V DC/0.5 A
+            //       - Terminal is physically....: plugged
+            //       - Substitude Value Channel 1: 0
+            //       - Substitude Value Channel 2: 0
+            //       - Substitude Value Channel 3: 0
+            //       - Substitude Value Channel 4: 0
+            //   [4] 750-504  4 DO/24 V DC/0.5 A
+            //       - Terminal is physically....: plugged
+            //       - Substitude Value Channel 1: 0
+            //       - Substitude Value Channel 2: 0
+            //       - Substitude Value Channel 3: 0
+            //       - Substitude Value Channel 4: 0
+            //   [5] 750-504  4 DO/24 V DC/0.5 A
+            //       - Terminal is physically....: plugged
+            //       - Substitude Value Channel 1: 0
+            //       - Substitude Value Channel 2: 0
+            //       - Substitude Value Channel 3: 0
+            //       - Substitude Value Channel 4: 0
+            //   [6] 750-402  4 DI/24 V DC/3.0 ms
+            //       - Terminal is physically: plugged
+            //   [7] 750-466  2 AI/4-20 mA/SE
+            //       - Terminal is physically: plugged
+            //       - Diagnosis Channel 1...: enabled
+            //       - Diagnosis Channel 2...: disabled
+            user_parameters: vec![
+                0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x00, 0xc3, 0x00, 0x00, 0x00, 0x00,
+                0x01, 0x00, 0x00, 0x00, 0x00, 0x80, 0x2b, 0x00, 0x21, 0x02, 0x00, 0x21, 0x02, 0x00,
+                0x21, 0x02, 0x00, 0x21, 0x02, 0x00, 0x21, 0x02, 0x00, 0x21, 0x01, 0x00, 0x24, 0x50,
+                0x11, 0x06,
+            ],
+            config: vec![0x00, 0x20, 0x20, 0x20, 0x20, 0x20, 0x10, 0x51],
+
+            max_tsdr: [
+                (9600, 60),
+                (19200, 60),
+                (93750, 60),
+                (187500, 60),
+                (500000, 100),
+                (1500000, 150),
+                (3000000, 250),
+                (6000000, 350),
+                (12000000, 550),
+            ]
+            .into_iter()
+            .map(|(b, t)| (b.to_string(), t))
+            .collect(),
+
+            fail_safe: true,
+            input_size: 5,
+            output_size: 5,
+            diag_size: 64,
+            pii_offset: 1,
+            piq_offset: 0,
+            liveness_bit: (0, 0),
+        }
+    }
 
-// Bus Parameters
-const MASTER_ADDRESS: u8 = 3;
-const BUS_DEVICE: &'static str = "/dev/ttyUSB0";
-const BAUDRATE: profirust::Baudrate = profirust::Baudrate::B19200;
+    fn validate(&self, master_address: u8) -> Result<(), String> {
+        if self.address > 125 {
+            return Err(format!(
+                "peripheral \"{}\" has invalid station address {}",
+                self.name, self.address
+            ));
+        }
+        if self.address == master_address {
+            return Err(format!(
+                "peripheral \"{}\" uses the master address {}",
+                self.name, self.address
+            ));
+        }
+        if self.user_parameters.len() > 237 {
+            return Err(format!(
+                "peripheral \"{}\" has too many user parameters",
+                self.name
+            ));
+        }
+        if self.input_size > 244 || self.output_size > 244 {
+            return Err(format!(
+                "peripheral \"{}\" exceeds the maximum of 244 bytes of I/O data",
+                self.name
+            ));
+        }
+        if self.pii_range().end > PII_SIZE {
+            return Err(format!(
+                "input data of peripheral \"{}\" does not fit into the PII (size {PII_SIZE})",
+                self.name
+            ));
+        }
+        if self.piq_range().end > PIQ_SIZE {
+            return Err(format!(
+                "output data of peripheral \"{}\" does not fit into the PIQ (size {PIQ_SIZE})",
+                self.name
+            ));
+        }
+        if self.liveness_bit.0 >= PII_SIZE || self.liveness_bit.1 > 7 {
+            return Err(format!(
+                "liveness bit {:?} of peripheral \"{}\" is outside the PII",
+                self.liveness_bit, self.name
+            ));
+        }
+        Ok(())
+    }
 
-// Sizes for the controller-side global input and output process images
-pub const PIQ_SIZE: usize = 256;
-pub const PII_SIZE: usize = 256;
+    fn pii_range(&self) -> std::ops::Range<usize> {
+        self.pii_offset..self.pii_offset + self.input_size
+    }
 
-fn bus_parameters() -> (fdl::ParametersBuilder, std::time::Duration) {
-    let mut parameters = fdl::ParametersBuilder::new(MASTER_ADDRESS, BAUDRATE);
-    parameters
-        // We use a rather large T_slot time because USB-RS485 converters
-        // can induce large delays at times.
-        .slot_bits(576)
-        .watchdog_timeout(profirust::time::Duration::from_millis(500));
+    fn piq_range(&self) -> std::ops::Range<usize> {
+        self.piq_offset..self.piq_offset + self.output_size
+    }
 
-    let sleep_time = std::time::Duration::from_millis(10);
+    /// Look up max_tsdr for the given baudrate, failing if the peripheral does not support it
+    fn max_tsdr(&self, baudrate: u64) -> Result<u16, String> {
+        self.max_tsdr
+            .get(&baudrate.to_string())
+            .copied()
+            .ok_or_else(|| {
+                format!(
+                    "peripheral \"{}\" does not support baudrate {baudrate}",
+                    self.name
+                )
+            })
+    }
 
-    (parameters, sleep_time)
+    fn options(&self, baudrate: u64) -> dp::PeripheralOptions<'_> {
+        dp::PeripheralOptions {
+            ident_number: self.ident_number,
+            user_parameters: Some(&self.user_parameters[..]),
+            config: Some(&self.config[..]),
+            max_tsdr: self.max_tsdr(baudrate).unwrap(),
+            fail_safe: self.fail_safe,
+            ..Default::default()
+        }
+    }
 }
 
 #[derive(Debug, Default)]
 pub struct Fieldbus {
     inner: Arc<Mutex<FieldbusInner>>,
+    #[cfg(feature = "simulator")]
+    #[allow(dead_code)]
+    simulator: Option<crate::simulator::SimulatorHandle>,
 }
 
 #[derive(Debug)]
 struct FieldbusInner {
     state: OperatingState,
     is_online: bool,
+    peripherals_running: bool,
     pii: [u8; PII_SIZE],
     piq: [u8; PIQ_SIZE],
+    diagnostics: Vec<StationDiagnostics>,
 }
 
 impl Default for FieldbusInner {
@@ -49,33 +360,77 @@ impl Default for FieldbusInner {
         Self {
             state: OperatingState::Stop,
             is_online: false,
+            peripherals_running: false,
             pii: [0u8; PII_SIZE],
             piq: [0u8; PIQ_SIZE],
+            diagnostics: Vec::new(),
         }
     }
 }
 
 impl Fieldbus {
-    pub fn new() -> Self {
+    #[cfg_attr(not(feature = "simulator"), allow(unused_mut))]
+    pub fn new(mut config: FieldbusConfig) -> Self {
+        #[cfg(feature = "simulator")]
+        let simulator = config.simulate.then(|| {
+            let simulator = match crate::simulator::Simulator::new(&config.peripherals) {
+                Ok(simulator) => simulator,
+                Err(e) => {
+                    log::error!("Failed to set up the fieldbus simulator: {e}");
+                    std::process::exit(1);
+                }
+            };
+            log::info!(
+                "Simulating {} peripheral(s) on {}.",
+                config.peripherals.len(),
+                simulator.device().display()
+            );
+            config.bus_device = simulator.device().to_string_lossy().into_owned();
+            let handle = simulator.handle();
+            std::thread::spawn(move || simulator.run());
+            handle
+        });
+
         let inner: Arc<Mutex<FieldbusInner>> = Default::default();
         std::thread::spawn({
             let inner = inner.clone();
             move || {
-                fieldbus_task(inner);
+                fieldbus_task(inner, config);
             }
         });
-        Self { inner }
+        Self {
+            inner,
+            #[cfg(feature = "simulator")]
+            simulator,
+        }
+    }
+
+    /// Access to the simulated peripherals, if the fieldbus is simulated
+    #[cfg(feature = "simulator")]
+    #[allow(dead_code)]
+    pub fn simulator(&self) -> Option<crate::simulator::SimulatorHandle> {
+        self.simulator.clone()
     }
 
     pub fn enter_state(&mut self, state: OperatingState) {
         self.inner.lock().unwrap().state = state;
     }
 
-    #[allow(unused)]
+    /// Whether the master is currently taking part in the token ring
     pub fn is_online(&self) -> bool {
         self.inner.lock().unwrap().is_online
     }
 
+    /// Whether all peripherals are currently exchanging data
+    pub fn peripherals_running(&self) -> bool {
+        self.inner.lock().unwrap().peripherals_running
+    }
+
+    /// Latest diagnostics of all peripherals which reported any
+    pub fn diagnostics(&self) -> Vec<StationDiagnostics> {
+        self.inner.lock().unwrap().diagnostics.clone()
+    }
+
     #[allow(unused)]
     pub fn update_process_images(&mut self, pii: &mut [u8; PII_SIZE], piq: &[u8; PIQ_SIZE]) {
         let mut data = self.inner.lock().unwrap();
@@ -95,117 +450,64 @@ impl Fieldbus {
 
 struct PeripheralInfo {
     pub handle: dp::PeripheralHandle,
+    pub name: String,
+    pub address: u8,
     pub pii_offset: usize,
     pub piq_offset: usize,
     pub liveness_bit: (usize, u8),
 }
 
-fn fieldbus_task(fieldbus_data: Arc<Mutex<FieldbusInner>>) {
+struct PeripheralBuffers {
+    inputs: Vec<u8>,
+    outputs: Vec<u8>,
+    diagnostics: Vec<u8>,
+}
+
+fn fieldbus_task(fieldbus_data: Arc<Mutex<FieldbusInner>>, config: FieldbusConfig) {
+    let mut buffers: Vec<PeripheralBuffers> = config
+        .peripherals
+        .iter()
+        .map(|p| PeripheralBuffers {
+            inputs: vec![0u8; p.input_size],
+            outputs: vec![0u8; p.output_size],
+            diagnostics: vec![0u8; p.diag_size],
+        })
+        .collect();
+
     let mut dp_master = dp::DpMaster::new(vec![]);
     let mut peripherals: Vec<PeripheralInfo> = Default::default();
 
-    // Options generated by `gsdtool` using "wagob757.gsd"
-    let options = profirust::dp::PeripheralOptions {
-        // "WAGO 750-343" by "WAGO Kontakttechnik GmbH"
-        ident_number: 0xb757,
-
-        // Global Parameters:
-        //   - DP-Watchdog-Base...............: 10 ms
-        //   - Restart on K-Bus Failure.......: POWER ON RESET
-        //   - Device Diagnosis...............: enabled
-        //   - Process Data Representation....: MOTOROLA (MSB-LSB)
-        //   - Response to PROFIBUS DP Failure: Output image is cleared
-        //   - Response to K-Bus Failure......: PROFIBUS communication stops
-        //
-        // Selected Modules:
-        //   [0] 750-343 No PI Channel
-        //   [1] 750-504  4 DO/24 V DC/0.5 A
-        //       - Terminal is physically....: plugged
-        //       - Substitude Value Channel 1: 0
-        //       - Substitude Value Channel 2: 0
-        //       - Substitude Value Channel 3: 0
-        //       - Substitude Value Channel 4: 0
-        //   [2] 750-504  4 DO/24 V DC/0.5 A
-        //       - Terminal is physically....: plugged
-        //       - Substitude Value Channel 1: 0
-        //       - Substitude Value Channel 2: 0
-        //       - Substitude Value Channel 3: 0
-        //       - Substitude Value Channel 4: 0
-        //   [3] 750-504  4 DO/24 V DC/0.5 A
-        //       - Terminal is physically....: plugged
-        //       - Substitude Value Channel 1: 0
-        //       - Substitude Value Channel 2: 0
-        //       - Substitude Value Channel 3: 0
-        //       - Substitude Value Channel 4: 0
-        //   [4] 750-504  4 DO/24 V DC/0.5 A
-        //       - Terminal is physically....: plugged
-        //       - Substitude Value Channel 1: 0
-        //       - Substitude Value Channel 2: 0
-        //       - Substitude Value Channel 3: 0
-        //       - Substitude Value Channel 4: 0
-        //   [5] 750-504  4 DO/24 V DC/0.5 A
-        //       - Terminal is physically....: plugged
-        //       - Substitude Value Channel 1: 0
-        //       - Substitude Value Channel 2: 0
-        //       - Substitude Value Channel 3: 0
-        //       - Substitude Value Channel 4: 0
-        //   [6] 750-402  4 DI/24 V DC/3.0 ms
-        //       - Terminal is physically: plugged
-        //   [7] 750-466  2 AI/4-20 mA/SE
-        //       - Terminal is physically: plugged
-        //       - Diagnosis Channel 1...: enabled
-        //       - Diagnosis Channel 2...: disabled
-        user_parameters: Some(&[
-            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x00, 0xc3, 0x00, 0x00, 0x00, 0x00,
-            0x01, 0x00, 0x00, 0x00, 0x00, 0x80, 0x2b, 0x00, 0x21, 0x02, 0x00, 0x21, 0x02, 0x00,
-            0x21, 0x02, 0x00, 0x21, 0x02, 0x00, 0x21, 0x02, 0x00, 0x21, 0x01, 0x00, 0x24, 0x50,
-            0x11, 0x06,
-        ]),
-        config: Some(&[0x00, 0x20, 0x20, 0x20, 0x20, 0x20, 0x10, 0x51]),
-
-        // Set max_tsdr depending on baudrate and assert
-        // that a supported baudrate is used.
-        max_tsdr: match BAUDRATE {
-            profirust::Baudrate::B9600 => 60,
-            profirust::Baudrate::B19200 => 60,
-            profirust::Baudrate::B93750 => 60,
-            profirust::Baudrate::B187500 => 60,
-            profirust::Baudrate::B500000 => 100,
-            profirust::Baudrate::B1500000 => 150,
-            profirust::Baudrate::B3000000 => 250,
-            profirust::Baudrate::B6000000 => 350,
-            profirust::Baudrate::B12000000 => 550,
-            b => panic!("Peripheral \"WAGO 750-343\" does not support baudrate {b:?}!"),
-        },
-
-        fail_safe: true,
-        ..Default::default()
-    };
-    let mut buffer_inputs = [0u8; 5];
-    let mut buffer_outputs = [0u8; 5];
-    let mut buffer_diagnostics = [0u8; 64];
-    let handle = dp_master.add(
-        dp::Peripheral::new(
-            IO_STATION_ADDRESS,
-            options,
-            &mut buffer_inputs[..],
-            &mut buffer_outputs[..],
-        )
-        .with_diag_buffer(&mut buffer_diagnostics[..]),
-    );
-
-    peripherals.push(PeripheralInfo {
-        handle,
-        pii_offset: 1,
-        piq_offset: 0,
-        liveness_bit: (0, 0),
-    });
+    for (peripheral, buffers) in config.peripherals.iter().zip(buffers.iter_mut()) {
+        log::info!(
+            "Adding peripheral \"{}\" at address {}.",
+            peripheral.name,
+            peripheral.address
+        );
+        let handle = dp_master.add(
+            dp::Peripheral::new(
+                peripheral.address,
+                peripheral.options(config.baudrate),
+                &mut buffers.inputs[..],
+                &mut buffers.outputs[..],
+            )
+            .with_diag_buffer(&mut buffers.diagnostics[..]),
+        );
+
+        peripherals.push(PeripheralInfo {
+            handle,
+            name: peripheral.name.clone(),
+            address: peripheral.address,
+            pii_offset: peripheral.pii_offset,
+            piq_offset: peripheral.piq_offset,
+            liveness_bit: peripheral.liveness_bit,
+        });
+    }
 
-    let (parameters, sleep_time) = bus_parameters();
+    let (parameters, sleep_time) = config.bus_parameters();
     let mut fdl = fdl::FdlActiveStation::new(parameters.build_verified(&dp_master));
 
-    log::info!("Connecting to the bus...");
-    let mut phy = phy::SerialPortPhy::new(BUS_DEVICE, fdl.parameters().baudrate);
+    log::info!("Connecting to the bus at {}...", config.bus_device);
+    let mut phy = phy::SerialPortPhy::new(&config.bus_device, fdl.parameters().baudrate);
 
     fdl.set_online();
     dp_master.enter_operate();
@@ -222,6 +524,54 @@ fn fieldbus_task(fieldbus_data: Arc<Mutex<FieldbusInner>>) {
             }
 
             data.is_online = fdl.is_in_ring();
+            data.peripherals_running = peripherals
+                .iter()
+                .all(|p| dp_master.get_mut(p.handle).is_running());
+
+            if let Some((handle, dp::PeripheralEvent::Diagnostics)) = events.peripheral {
+                let peripheral_info = peripherals.iter().find(|p| p.handle == handle).unwrap();
+                if let Some(diag) = dp_master.get_mut(handle).last_diagnostics() {
+                    let diag = StationDiagnostics::decode(
+                        &peripheral_info.name,
+                        peripheral_info.address,
+                        DiagnosticFlags::from_bits(diag.flags.bits()),
+                        diag.extended_diagnostics
+                            .raw_diag_buffer()
+                            .unwrap_or_default(),
+                    );
+                    diag.log();
+
+                    let name = peripheral_info.name.clone();
+                    metrics::counter!("crab_fieldbus_diagnostics_total", "peripheral" => name.clone())
+                        .increment(1);
+                    metrics::describe_counter!(
+                        "crab_fieldbus_diagnostics_total",
+                        "Number of diagnostics reported by each DP peripheral."
+                    );
+                    metrics::gauge!("crab_fieldbus_diagnostic_fault", "peripheral" => name.clone())
+                        .set(f64::from(diag.is_fault()));
+                    metrics::describe_gauge!(
+                        "crab_fieldbus_diagnostic_fault",
+                        "Whether a DP peripheral currently reports a fault in its diagnostics."
+                    );
+                    for (flag, set) in diag.flags.iter() {
+                        metrics::gauge!(
+                            "crab_fieldbus_diagnostic_flag",
+                            "peripheral" => name.clone(),
+                            "flag" => flag
+                        )
+                        .set(f64::from(set));
+                    }
+                    metrics::describe_gauge!(
+                        "crab_fieldbus_diagnostic_flag",
+                        "Standard diagnostics flags last reported by each DP peripheral."
+                    );
+
+                    data.diagnostics
+                        .retain(|d| d.peripheral != peripheral_info.name);
+                    data.diagnostics.push(diag);
+                }
+            }
 
             if events.cycle_completed {
                 for peripheral_info in peripherals.iter() {
@@ -250,3 +600,87 @@ fn fieldbus_task(fieldbus_data: Arc<Mutex<FieldbusInner>>) {
         std::thread::sleep(sleep_time);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn peripheral_layout() {
+        let mut config = FieldbusConfig::default();
+        config.validate().unwrap();
+
+        let mut second = PeripheralConfig {
+            name: "Legs".to_string(),
+            address: 9,
+            pii_offset: 6,
+            piq_offset: 5,
+            liveness_bit: (0, 1),
+            ..PeripheralConfig::wago_750_343()
+        };
+        config.peripherals.push(second.clone());
+        config.validate().unwrap();
+
+        second.pii_offset = 5;
+        config.peripherals[1] = second.clone();
+        assert!(config.validate().is_err(), "overlapping PII not detected");
+
+        second.pii_offset = 6;
+        second.piq_offset = 4;
+        config.peripherals[1] = second.clone();
+        assert!(config.validate().is_err(), "overlapping PIQ not detected");
+
+        second.piq_offset = PIQ_SIZE - 4;
+        config.peripherals[1] = second.clone();
+        assert!(config.validate().is_err(), "PIQ overflow not detected");
+
+        second.piq_offset = 5;
+        second.liveness_bit = (0, 0);
+        config.peripherals[1] = second.clone();
+        assert!(
+            config.validate().is_err(),
+            "shared liveness bit not detected"
+        );
+
+        second.liveness_bit = (7, 0);
+        config.peripherals[1] = second.clone();
+        assert!(
+            config.validate().is_err(),
+            "liveness bit inside PII area not detected"
+        );
+    }
+    #[test]
+    fn peripheral_fields_required() {
+        let peripheral: PeripheralConfig = toml::from_str(
+            r#"
+            name = "Legs"
+            address = 9
+            ident_number = 0xb757
+            config = [0x20]
+            max_tsdr = { 19200 = 60 }
+            input_size = 1
+            output_size = 1
+            pii_offset = 8
+            piq_offset = 8
+            liveness_bit = [0, 1]
+            "#,
+        )
+        .unwrap();
+        assert!(peripheral.user_parameters.is_empty());
+        assert!(!peripheral.fail_safe);
+        assert_eq!(peripheral.diag_size, 64);
+
+        let missing_ident = r#"
+            name = "Legs"
+            address = 9
+            config = [0x20]
+            max_tsdr = { 19200 = 60 }
+            input_size = 1
+            output_size = 1
+            pii_offset = 8
+            piq_offset = 8
+            liveness_bit = [0, 1]
+        "#;
+        assert!(toml::from_str::<PeripheralConfig>(missing_ident).is_err());
+    }
+}
@@ -1,3 +1,4 @@
+use crab_httpapi::auth::{Identity, Role};
 use futures::StreamExt as _;
 use juniper_axum::{extract::JuniperRequest, response::JuniperResponse};
 use std::{pin::Pin, sync::Arc, time::Duration};
@@ -13,9 +14,89 @@ cfg_if::cfg_if! {
     }
 }
 
-#[derive(Default, Debug, Clone)]
+/// Number of cycles buffered for slow subscribers, about three seconds
+const UPDATES_CAPACITY: usize = 64;
+
+#[derive(Clone)]
 pub struct Context {
     pub inner: std::sync::Arc<tokio::sync::RwLock<ContextInner>>,
+    /// Channels into the main loop, shared with the HTTP API
+    pub app: crab_httpapi::AppState,
+    pub history: crate::history::SharedHistory,
+    /// User of the request, anonymous unless set with [`Context::for_request`]
+    pub identity: Identity,
+    pub client: Option<std::net::SocketAddr>,
+    updates: tokio::sync::broadcast::Sender<Arc<CycleUpdate>>,
+}
+
+impl Context {
+    pub fn new(app: crab_httpapi::AppState, history: crate::history::SharedHistory) -> Self {
+        Self {
+            inner: Default::default(),
+            identity: app.auth.anonymous(),
+            client: None,
+            app,
+            history,
+            updates: tokio::sync::broadcast::Sender::new(UPDATES_CAPACITY),
+        }
+    }
+
+    /// Context for a request made by `identity` from `client`
+    pub fn for_request(&self, identity: Identity, client: Option<std::net::SocketAddr>) -> Self {
+        Self {
+            identity,
+            client,
+            ..self.clone()
+        }
+    }
+
+    /// Run `mutation` if the request may act as `role` and record it in the audit log
+    ///
+    /// When a `token` is given, it is checked instead of the identity of the request.
+    async fn mutate<T>(
+        &self,
+        name: &str,
+        role: Role,
+        token: Option<String>,
+        parameters: serde_json::Value,
+        mutation: impl Future<Output = juniper::FieldResult<T>>,
+    ) -> juniper::FieldResult<T> {
+        let identity = match token {
+            Some(token) => self.app.auth.authenticate(&token).await,
+            None => Ok(self.identity.clone()),
+        };
+        let result = match &identity {
+            Ok(identity) => match identity.require(role) {
+                Ok(()) => mutation.await,
+                Err(e) => Err(e.into()),
+            },
+            Err(e) => Err(e.into()),
+        };
+
+        self.app.audit.record(crab_httpapi::audit::AuditEntry::new(
+            self.client,
+            identity.as_ref().ok(),
+            format!("mutation {name}"),
+            parameters,
+            result
+                .as_ref()
+                .map(|_| ())
+                .map_err(|e| e.message().to_owned()),
+        ));
+        result
+    }
+
+    /// Notify the subscriptions about a finished cycle of the logic
+    pub fn publish(&self, now: std::time::Instant, logic: &crate::logic::Logic) {
+        if self.updates.receiver_count() == 0 {
+            return;
+        }
+        let _ = self.updates.send(Arc::new(CycleUpdate {
+            now,
+            wall_clock: std::time::SystemTime::now(),
+            logic: logic.clone(),
+        }));
+    }
 }
 
 #[derive(Debug)]
@@ -23,6 +104,7 @@ pub struct ContextInner {
     pub logic_image: crate::logic::Logic,
     pub pii: [u8; PII_SIZE],
     pub piq: [u8; PIQ_SIZE],
+    pub fieldbus_diagnostics: Vec<crate::dpdiag::StationDiagnostics>,
     pub now: std::time::Instant,
 }
 
@@ -32,6 +114,7 @@ impl Default for ContextInner {
             logic_image: Default::default(),
             pii: [0u8; PII_SIZE],
             piq: [0u8; PIQ_SIZE],
+            fieldbus_diagnostics: Vec::new(),
             now: std::time::Instant::now(),
         }
     }
@@ -39,10 +122,10 @@ impl Default for ContextInner {
 
 impl juniper::Context for Context {}
 
-pub type Schema = juniper::RootNode<'static, Query, juniper::EmptyMutation<Context>, Subscription>;
+pub type Schema = juniper::RootNode<'static, Query, Mutation, Subscription>;
 
 pub fn schema() -> Schema {
-    Schema::new(Query, juniper::EmptyMutation::new(), Subscription)
+    Schema::new(Query, Mutation, Subscription)
 }
 
 pub struct Query;
@@ -61,6 +144,45 @@ impl Query {
         context.inner.read().await.logic_image.clone()
     }
 
+    /// Names of the configured emotions, accepted by `setEmotion`
+    fn emotions(context: &Context) -> Vec<crate::logic::Emotion> {
+        context.app.emotions.to_vec()
+    }
+
+    /// All alarms and the recent alarm history
+    fn alarms(context: &Context) -> crate::alarms::AlarmsSnapshot {
+        context.app.alarms.borrow().clone()
+    }
+
+    /// Recorded values of a signal, optionally reduced to `downsample` points
+    ///
+    /// `from` and `to` are seconds since the UNIX epoch.
+    fn history(
+        context: &Context,
+        signal: String,
+        from: Option<f64>,
+        to: Option<f64>,
+        downsample: Option<i32>,
+    ) -> juniper::FieldResult<Vec<crate::history::HistoryPoint>> {
+        let downsample = downsample.map(usize::try_from).transpose()?;
+        let points = context.history.lock().unwrap().query(
+            &signal,
+            from.unwrap_or(f64::NEG_INFINITY),
+            to.unwrap_or(f64::INFINITY),
+            downsample,
+        )?;
+        Ok(points)
+    }
+
+    /// Names of the signals in the history
+    fn history_signals() -> Vec<&'static str> {
+        crate::history::signals().collect()
+    }
+
+    async fn fieldbus_diagnostics(context: &Context) -> Vec<crate::dpdiag::StationDiagnostics> {
+        context.inner.read().await.fieldbus_diagnostics.clone()
+    }
+
     async fn hardware_inputs<'a>(context: &'a Context) -> ProcessImage<'a> {
         ProcessImage {
             process_image: tokio::sync::RwLockReadGuard::map(context.inner.read().await, |c| {
@@ -78,6 +200,166 @@ impl Query {
     }
 }
 
+pub struct Mutation;
+
+#[juniper::graphql_object(Context = Context)]
+impl Mutation {
+    /// Queue an emotion, see `POST /crab/emotion` for the duration and priority
+    async fn set_emotion(
+        context: &Context,
+        token: Option<String>,
+        emotion: crate::logic::Emotion,
+        duration_s: Option<f64>,
+        priority: Option<i32>,
+    ) -> juniper::FieldResult<bool> {
+        let parameters = serde_json::json!({
+            "emotion": emotion,
+            "duration_s": duration_s,
+            "priority": priority,
+        });
+        context
+            .mutate("setEmotion", Role::Performer, token, parameters, async {
+                let request = crab_httpapi::emotionmanager::EmotionRequest::from_api(
+                    emotion,
+                    duration_s,
+                    priority.map(Into::into),
+                )?;
+                crab_httpapi::send_emotion_to_crab(context.app.emotion_ch_tx.clone(), request)
+                    .await?;
+                Ok(true)
+            })
+            .await
+    }
+
+    /// Forcefully inflate the crab
+    async fn trigger_fan(context: &Context, token: Option<String>) -> juniper::FieldResult<bool> {
+        context
+            .mutate(
+                "triggerFan",
+                Role::Operator,
+                token,
+                serde_json::json!({}),
+                async {
+                    context.app.trigger_fan();
+                    Ok(true)
+                },
+            )
+            .await
+    }
+
+    async fn trigger_sleep(context: &Context, token: Option<String>) -> juniper::FieldResult<bool> {
+        context
+            .mutate(
+                "triggerSleep",
+                Role::Operator,
+                token,
+                serde_json::json!({}),
+                async {
+                    context.app.trigger_sleep();
+                    Ok(true)
+                },
+            )
+            .await
+    }
+
+    async fn reset_fault(context: &Context, token: Option<String>) -> juniper::FieldResult<bool> {
+        context
+            .mutate(
+                "resetFault",
+                Role::Operator,
+                token,
+                serde_json::json!({}),
+                async {
+                    context.app.reset_fault();
+                    Ok(true)
+                },
+            )
+            .await
+    }
+
+    /// Change the pressure limits, only the given limits are changed
+    async fn set_pressure_limits(
+        context: &Context,
+        token: Option<String>,
+        limits: crab_httpapi::parameters::PressureLimitsUpdate,
+    ) -> juniper::FieldResult<bool> {
+        let parameters = serde_json::json!({ "limits": limits });
+        context
+            .mutate("setPressureLimits", Role::Admin, token, parameters, async {
+                limits
+                    .apply(&context.app.pressure_limits.borrow())
+                    .validate()?;
+                context.app.pressure_limits_tx.send(limits).await?;
+                Ok(true)
+            })
+            .await
+    }
+
+    /// Change timing parameters of the logic, returning the resulting parameters
+    async fn set_logic_parameters(
+        context: &Context,
+        token: Option<String>,
+        parameters: crab_httpapi::parameters::LogicParametersUpdate,
+    ) -> juniper::FieldResult<crate::logic::LogicParameters> {
+        let arguments = serde_json::json!({ "parameters": parameters });
+        context
+            .mutate("setLogicParameters", Role::Admin, token, arguments, async {
+                let new_parameters = parameters.apply(&context.app.logic_parameters.borrow());
+                new_parameters.validate()?;
+                context.app.logic_parameters_tx.send(parameters).await?;
+                Ok(new_parameters)
+            })
+            .await
+    }
+
+    /// Change the simulated crab, see `POST /crab/plant`
+    async fn set_plant(
+        context: &Context,
+        token: Option<String>,
+        update: crab_httpapi::plant::PlantUpdate,
+    ) -> juniper::FieldResult<bool> {
+        let parameters = serde_json::json!({ "update": update });
+        context
+            .mutate("setPlant", Role::Operator, token, parameters, async {
+                let Some(plant_tx) = &context.app.plant_tx else {
+                    return Err("the plant model is not enabled".into());
+                };
+                update.validate()?;
+                plant_tx.send(update).await?;
+                Ok(true)
+            })
+            .await
+    }
+
+    /// Acknowledge one or, without `id`, all active alarms
+    async fn acknowledge_alarms(
+        context: &Context,
+        token: Option<String>,
+        id: Option<String>,
+    ) -> juniper::FieldResult<bool> {
+        let parameters = serde_json::json!({ "id": id });
+        context
+            .mutate(
+                "acknowledgeAlarms",
+                Role::Operator,
+                token,
+                parameters,
+                async {
+                    if let Some(id) = &id
+                        && !context.app.alarms.borrow().contains(id)
+                    {
+                        return Err(format!("unknown alarm {id:?}").into());
+                    }
+
+                    let message = crab_httpapi::ApiAcknowledgeAlarmsMessage { id };
+                    context.app.alarm_ack_tx.send(message).await?;
+                    Ok(true)
+                },
+            )
+            .await
+    }
+}
+
 pub struct ProcessImage<'a> {
     process_image: tokio::sync::RwLockReadGuard<'a, [u8]>,
 }
@@ -109,13 +391,56 @@ impl<'a> ProcessImage<'a> {
     }
 }
 
+/// State of the logic after a cycle of the main loop
+#[derive(Debug)]
+pub struct CycleUpdate {
+    pub now: std::time::Instant,
+    pub wall_clock: std::time::SystemTime,
+    pub logic: crate::logic::Logic,
+}
+
+type EventStream<'a> =
+    Pin<Box<dyn futures::stream::Stream<Item = juniper::ExecutionResult> + Send + 'a>>;
+
+/// Every cycle of the logic, skipping cycles the subscriber was too slow to receive
+fn cycles(
+    context: &Context,
+) -> impl futures::stream::Stream<Item = Arc<CycleUpdate>> + Send + 'static {
+    futures::stream::unfold(context.updates.subscribe(), |mut rx| async move {
+        loop {
+            match rx.recv().await {
+                Ok(update) => return Some((update, rx)),
+                Err(tokio::sync::broadcast::error::RecvError::Lagged(n)) => {
+                    log::debug!("GraphQL subscriber is lagging, skipped {n} cycles");
+                }
+                Err(tokio::sync::broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    })
+}
+
+/// Only pass on items which differ from the previous one
+fn changes<'a, T: PartialEq + Clone + Send + 'a>(
+    stream: impl futures::stream::Stream<Item = T> + Send + 'a,
+) -> impl futures::stream::Stream<Item = T> + Send + 'a {
+    stream
+        .scan(None, |last, item| {
+            if last.as_ref() == Some(&item) {
+                return std::future::ready(Some(None));
+            }
+            *last = Some(item.clone());
+            std::future::ready(Some(Some(item)))
+        })
+        .filter_map(std::future::ready)
+}
+
 pub struct Subscription;
 
 impl Subscription {
     async fn watch<'a>(
         period: f64,
         executor: &juniper::Executor<'_, 'a, Context>,
-    ) -> Pin<Box<dyn futures::stream::Stream<Item = juniper::ExecutionResult> + Send + 'a>> {
+    ) -> EventStream<'a> {
         let executor = executor.as_owned_executor();
         let stream = tokio_stream::wrappers::IntervalStream::new(tokio::time::interval(
             Duration::from_secs_f64(period).max(Duration::from_millis(200)),
@@ -123,19 +448,85 @@ impl Subscription {
         .then(move |_| {
             let executor = executor.clone();
             async move { executor.as_executor().resolve_async(&(), &Query).await }
-        })
-        .scan(None, |state, q| {
-            if let Some(s) = state {
-                if s == &q {
+        });
+
+        Box::pin(changes(stream))
+    }
+
+    fn outputs_changed<'a>(executor: &juniper::Executor<'_, 'a, Context>) -> EventStream<'a> {
+        let outputs = changes(cycles(executor.context()).map(|c| c.logic.outputs().clone()));
+        Self::resolve_each(outputs, executor)
+    }
+
+    fn emotion_changed<'a>(executor: &juniper::Executor<'_, 'a, Context>) -> EventStream<'a> {
+        let emotion = changes(cycles(executor.context()).map(|c| c.logic.emotion().cloned()));
+        Self::resolve_each(emotion, executor)
+    }
+
+    fn alarm_raised<'a>(executor: &juniper::Executor<'_, 'a, Context>) -> EventStream<'a> {
+        let raised = cycles(executor.context())
+            .scan(None::<Vec<crate::alarms::AlarmId>>, |last, c| {
+                let alarms = c.logic.alarms();
+                let active: Vec<_> = alarms.active().collect();
+                // Alarms which are already active when subscribing are not reported
+                let raised = match last {
+                    Some(last) => {
+                        let snapshot = alarms.snapshot(c.now, c.wall_clock);
+                        active
+                            .iter()
+                            .filter(|id| !last.contains(id))
+                            .filter_map(|id| snapshot.alarms.iter().find(|a| a.id == id.name()))
+                            .cloned()
+                            .collect()
+                    }
+                    None => Vec::new(),
+                };
+                *last = Some(active);
+                std::future::ready(Some(futures::stream::iter(raised)))
+            })
+            .flatten();
+        Self::resolve_each(raised, executor)
+    }
+
+    fn pressure<'a>(
+        sample_rate: f64,
+        executor: &juniper::Executor<'_, 'a, Context>,
+    ) -> juniper::FieldResult<EventStream<'a>> {
+        if !(sample_rate.is_finite() && sample_rate > 0.) {
+            return Err("sampleRate must be positive".into());
+        }
+        let period = Duration::from_secs_f64(1. / sample_rate);
+
+        let samples = cycles(executor.context())
+            .scan(None, move |last, c| {
+                if last.is_some_and(|last| c.now - last < period) {
                     return std::future::ready(Some(None));
                 }
-            }
-            *state = Some(q.clone());
-            std::future::ready(Some(Some(q)))
-        })
-        .filter_map(|opt| std::future::ready(opt));
+                *last = Some(c.now);
+                std::future::ready(Some(Some(c.logic.pressure_mbar())))
+            })
+            .filter_map(std::future::ready);
+        Ok(Self::resolve_each(samples, executor))
+    }
 
-        Box::pin(stream)
+    /// Resolve each item of the stream with the selection of the subscription
+    fn resolve_each<'a, T>(
+        stream: impl futures::stream::Stream<Item = T> + Send + 'a,
+        executor: &juniper::Executor<'_, 'a, Context>,
+    ) -> EventStream<'a>
+    where
+        T: juniper::GraphQLValueAsync<Context = (), TypeInfo = ()> + Send + Sync + 'a,
+    {
+        let executor = executor.as_owned_executor();
+        Box::pin(stream.then(move |item| {
+            let executor = executor.clone();
+            async move {
+                executor
+                    .as_executor()
+                    .resolve_with_ctx_async(&(), &item)
+                    .await
+            }
+        }))
     }
 }
 
@@ -151,9 +542,31 @@ impl juniper::GraphQLType for Subscription {
     where
         juniper::DefaultScalarValue: 'r,
     {
-        let fields = [registry
-            .field_convert::<Query, _, Self::Context>("watch", info)
-            .argument(registry.arg::<f64>("period", info))];
+        let fields = [
+            registry
+                .field_convert::<Query, _, Self::Context>("watch", info)
+                .argument(registry.arg::<f64>("period", info))
+                .description("Poll the whole query every `period` seconds, pushing only changes"),
+            registry
+                .field_convert::<crate::logic::LogicOutputs, _, Self::Context>(
+                    "outputsChanged",
+                    info,
+                )
+                .description("Outputs of the logic, pushed in the cycle they change"),
+            registry
+                .field_convert::<Option<crate::logic::Emotion>, _, Self::Context>(
+                    "emotionChanged",
+                    info,
+                )
+                .description("Emotion of the crab, pushed in the cycle it changes"),
+            registry
+                .field_convert::<crate::alarms::AlarmInfo, _, Self::Context>("alarmRaised", info)
+                .description("Alarms, pushed in the cycle they are raised"),
+            registry
+                .field_convert::<Option<f64>, _, Self::Context>("pressure", info)
+                .argument(registry.arg::<f64>("sampleRate", info))
+                .description("Pressure in millibar, sampled `sampleRate` times per second"),
+        ];
 
         registry
             .build_object_type::<Subscription>(info, &fields)
@@ -170,6 +583,12 @@ impl juniper::GraphQLValue for Subscription {
     }
 }
 
+fn required_arg(args: &juniper::Arguments, name: &str) -> juniper::FieldResult<f64> {
+    args.get::<f64>(name).and_then(|opt| {
+        opt.ok_or_else(|| juniper::FieldError::from(format!("Missing argument `{name}`")))
+    })
+}
+
 impl juniper::GraphQLSubscriptionValue for Subscription {
     fn resolve_field_into_stream<'s, 'i, 'fi, 'args, 'e, 'ref_e, 'res, 'f>(
         &'s self,
@@ -187,45 +606,49 @@ impl juniper::GraphQLSubscriptionValue for Subscription {
         'i: 'res,
         'e: 'res,
     {
-        match field {
-            "watch" => futures::FutureExt::boxed(async move {
-                let stream = Self::watch(
-                    args.get::<f64>("period").and_then(|opt| {
-                        opt.ok_or_else(|| juniper::FieldError::from("Missing argument `period`"))
-                    })?,
-                    &executor,
-                )
-                .await;
+        futures::FutureExt::boxed(async move {
+            let stream = match field {
+                "watch" => Self::watch(required_arg(&args, "period")?, executor).await,
+                "outputsChanged" => Self::outputs_changed(executor),
+                "emotionChanged" => Self::emotion_changed(executor),
+                "alarmRaised" => Self::alarm_raised(executor),
+                "pressure" => Self::pressure(required_arg(&args, "sampleRate")?, executor)?,
+                _ => {
+                    return Err(juniper::FieldError::from(format!(
+                        "Field `{field}` not found on type `Subscription`",
+                    )));
+                }
+            };
 
-                let ex = executor.as_owned_executor();
-                let stream = stream.then(move |val| {
-                    std::future::ready(val.map_err(|e| ex.as_executor().new_error(e)))
-                });
+            let ex = executor.as_owned_executor();
+            let stream = stream.then(move |val| {
+                std::future::ready(val.map_err(|e| ex.as_executor().new_error(e)))
+            });
 
-                Ok(juniper::Value::Scalar(stream.boxed()))
-            }),
-            _ => Box::pin(async move {
-                Err(juniper::FieldError::from(format!(
-                    "Field `{field}` not found on type `Subscription`",
-                )))
-            }),
-        }
+            Ok(juniper::Value::Scalar(stream.boxed()))
+        })
     }
 }
 
 async fn graphql(
     axum::Extension(schema): axum::Extension<Arc<Schema>>,
     axum::Extension(context): axum::Extension<Context>,
+    axum::Extension(identity): axum::Extension<Identity>,
+    axum::extract::ConnectInfo(client): axum::extract::ConnectInfo<std::net::SocketAddr>,
     JuniperRequest(req): JuniperRequest,
 ) -> JuniperResponse {
+    let context = context.for_request(identity, Some(client));
     JuniperResponse(req.execute(&*schema, &context).await)
 }
 
 async fn graphql_subscriptions(
     axum::Extension(schema): axum::Extension<Arc<Schema>>,
     axum::Extension(context): axum::Extension<Context>,
+    axum::Extension(identity): axum::Extension<Identity>,
+    axum::extract::ConnectInfo(client): axum::extract::ConnectInfo<std::net::SocketAddr>,
     ws: axum::extract::WebSocketUpgrade,
 ) -> axum::response::Response {
+    let context = context.for_request(identity, Some(client));
     ws.protocols(["graphql-transport-ws", "graphql-ws"])
         .on_upgrade(move |socket| {
             juniper_axum::subscriptions::serve_ws(
@@ -267,12 +690,220 @@ mod tests {
     fn schema() {
         let schema = juniper::RootNode::new(
             Query,
-            juniper::EmptyMutation::<()>::new(),
-            juniper::EmptySubscription::<()>::new(),
+            Mutation,
+            juniper::EmptySubscription::<Context>::new(),
         );
 
-        println!("{}", schema.as_sdl());
+        let sdl = schema.as_sdl();
+        println!("{sdl}");
+
+        assert!(sdl.contains("type Query"));
+        assert!(sdl.contains("type LogicState"));
+        assert!(sdl.contains("setLogicParameters"));
+        for mutation in [
+            "setEmotion",
+            "triggerFan",
+            "triggerSleep",
+            "resetFault",
+            "setPressureLimits",
+            "setPlant",
+        ] {
+            assert!(sdl.contains(mutation), "missing mutation {mutation}");
+        }
+        assert!(sdl.contains("type AlarmsSnapshot"));
+    }
 
-        todo!()
+    fn test_context() -> (
+        Context,
+        tokio::sync::mpsc::Receiver<crab_httpapi::ApiAcknowledgeAlarmsMessage>,
+    ) {
+        let (emotion_ch_tx, _) = tokio::sync::mpsc::channel(1);
+        let (pressure_limits_tx, _) = tokio::sync::mpsc::channel(1);
+        let (logic_parameters_tx, _) = tokio::sync::mpsc::channel(1);
+        let (alarm_ack_tx, alarm_ack_rx) = tokio::sync::mpsc::channel(1);
+        let (sequence_tx, _) = tokio::sync::mpsc::channel(1);
+        let (_, pressure_limits) = tokio::sync::watch::channel(Default::default());
+        let (_, logic_parameters) = tokio::sync::watch::channel(Default::default());
+        let (_, alarms) = tokio::sync::watch::channel(Default::default());
+        let (_, status) = tokio::sync::watch::channel(Default::default());
+        let history = crate::history::History::open(Default::default()).unwrap();
+        let auth_config = crab_httpapi::auth::AuthConfig {
+            anonymous_role: Role::Viewer,
+            users: vec![
+                crab_httpapi::auth::UserConfig {
+                    name: "orga".into(),
+                    role: Role::Operator,
+                    token_hash: crab_httpapi::auth::TokenHash::new("orga-token", 1),
+                },
+                crab_httpapi::auth::UserConfig {
+                    name: "admin".into(),
+                    role: Role::Admin,
+                    token_hash: crab_httpapi::auth::TokenHash::new("admin-token", 1),
+                },
+            ],
+        };
+        let audit_config = crab_httpapi::audit::AuditConfig { path: None };
+        let context = Context::new(
+            crab_httpapi::AppState {
+                auth: Arc::new(crab_httpapi::auth::Auth::new(&auth_config)),
+                audit: Arc::new(crab_httpapi::audit::AuditLog::open(&audit_config).unwrap()),
+                emotion_ch_tx,
+                emotions: ["Happy", "Sad"]
+                    .into_iter()
+                    .map(crate::logic::Emotion::new)
+                    .collect(),
+                sentiment: Arc::new(crab_httpapi::sentiment::Lexicon::default()),
+                fault_reset: Default::default(),
+                trigger_fan: Default::default(),
+                trigger_sleep: Default::default(),
+                talk: Default::default(),
+                pressure_limits,
+                pressure_limits_tx,
+                logic_parameters,
+                logic_parameters_tx,
+                plant_tx: None,
+                status,
+                events: Default::default(),
+                alarms,
+                alarm_ack_tx,
+                sequence_tx,
+            },
+            Arc::new(std::sync::Mutex::new(history)),
+        );
+        (context, alarm_ack_rx)
+    }
+
+    #[test]
+    fn mutations() {
+        let (context, mut alarm_ack_rx) = test_context();
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let execute_as = |context: &Context, query: &str| {
+            let (_, errors) = runtime
+                .block_on(juniper::execute(
+                    query,
+                    None,
+                    &super::schema(),
+                    &juniper::Variables::new(),
+                    context,
+                ))
+                .unwrap();
+            errors
+        };
+        let execute = |query: &str| execute_as(&context, query);
+
+        let errors = execute(r#"mutation { triggerFan(token: "wrong") }"#);
+        assert_eq!(errors.len(), 1);
+        assert!(
+            !context
+                .app
+                .trigger_fan
+                .load(std::sync::atomic::Ordering::SeqCst)
+        );
+
+        let errors = execute("mutation { acknowledgeAlarms }");
+        assert_eq!(errors.len(), 1);
+        let errors = execute(r#"mutation { acknowledgeAlarms(token: "orga-token") }"#);
+        assert!(errors.is_empty());
+        assert!(alarm_ack_rx.try_recv().unwrap().id.is_none());
+
+        // Operators must not change the limits
+        let errors = execute(
+            r#"mutation { setPressureLimits(token: "orga-token", limits: { high: 20.0 }) }"#,
+        );
+        assert_eq!(errors.len(), 1);
+        // Limits out of order are rejected instead of being dropped by the logic
+        let errors = execute(
+            r#"mutation { setPressureLimits(token: "admin-token", limits: { high: 20.0 }) }"#,
+        );
+        assert_eq!(errors.len(), 1);
+
+        // Without a token, the user of the HTTP request is checked
+        let orga = runtime
+            .block_on(context.app.auth.authenticate("orga-token"))
+            .unwrap();
+        let errors = execute_as(&context.for_request(orga, None), "mutation { triggerFan }");
+        assert!(errors.is_empty());
+        assert!(
+            context
+                .app
+                .trigger_fan
+                .load(std::sync::atomic::Ordering::SeqCst)
+        );
+        // Every attempt ends up in the audit log, without the token
+        let audit = context.app.audit.query(&Default::default());
+        let actions: Vec<_> = audit
+            .iter()
+            .map(|e| (e.action.as_str(), e.user.as_deref(), e.success))
+            .collect();
+        assert_eq!(
+            actions,
+            [
+                ("mutation triggerFan", None, false),
+                ("mutation acknowledgeAlarms", None, false),
+                ("mutation acknowledgeAlarms", Some("orga"), true),
+                ("mutation setPressureLimits", Some("orga"), false),
+                ("mutation setPressureLimits", Some("admin"), false),
+                ("mutation triggerFan", Some("orga"), true),
+            ]
+        );
+        assert_eq!(audit[3].parameters["limits"]["high"], 20.0);
+    }
+
+    #[test]
+    fn subscriptions() {
+        use futures::FutureExt as _;
+
+        let (context, _) = test_context();
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let schema = super::schema();
+        let (value, errors) = runtime
+            .block_on(juniper::resolve_into_stream(
+                "subscription { outputsChanged { channels { pupilDown } } }",
+                None,
+                &schema,
+                &juniper::Variables::new(),
+                &context,
+            ))
+            .unwrap();
+        assert!(errors.is_empty());
+        let juniper::Value::Object(fields) = value else {
+            panic!("expected an object");
+        };
+        let (_, juniper::Value::Scalar(mut stream)) = fields.into_iter().next().unwrap() else {
+            panic!("expected a stream");
+        };
+
+        // Publish every cycle until the crab blinked once, the 300 ms blink must not be missed
+        let mut h = crate::harness::Harness::new();
+        h.run_until(Duration::from_secs(10), |l| l.outputs().channels.pupil_down)
+            .unwrap();
+        for blinking in [true, false] {
+            h.run_until(Duration::from_secs(10), |l| {
+                context.publish(std::time::Instant::now(), l);
+                l.outputs().channels.pupil_down != blinking
+            })
+            .unwrap();
+        }
+
+        let mut pupil_down = Vec::new();
+        while let Some(Some(value)) = stream.next().now_or_never() {
+            let value = value.unwrap();
+            let channels = value.as_object_value().unwrap().get_field_value("channels");
+            let down = channels
+                .and_then(|c| c.as_object_value())
+                .and_then(|c| c.get_field_value("pupilDown"))
+                .and_then(|v| v.as_scalar_value::<bool>())
+                .copied()
+                .unwrap();
+            pupil_down.push(down);
+        }
+        // Other outputs change in between as well
+        pupil_down.dedup();
+        assert_eq!(pupil_down, [true, false, true]);
     }
 }
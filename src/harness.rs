@@ -0,0 +1,150 @@
+//! Deterministic simulation harness for the crab logic
+//!
+//! The harness steps [`Logic`] with a virtual clock in fixed cycles, just like the main loop
+//! does in real time, so scenarios spanning hours of operation run in milliseconds.  Inputs are
+//! scripted through the harness, optionally with the [plant model](crate::plant) closing the
+//! loop.
+
+use std::time::{Duration, Instant};
+
+use crate::logic::{Logic, LogicInputs, LogicOutputs};
+use crate::plant::Plant;
+
+/// Cycle time of the main loop
+pub const CYCLE: Duration = Duration::from_millis(50);
+
+fn pressure_fullscale(mbar: f64) -> i32 {
+    ((mbar / 250. * 65535.).round() as i32) & !7
+}
+
+pub struct Harness {
+    pub logic: Logic,
+    pub plant: Option<Plant>,
+    start: Instant,
+    now: Instant,
+}
+
+impl Harness {
+    /// Start the logic with healthy inputs and a pressure between LOW and HIGH
+    ///
+    /// The fault latched during initialization is already reset.
+    pub fn new() -> Self {
+        let start = Instant::now();
+        let mut harness = Self {
+            logic: Logic::new(),
+            plant: None,
+            start,
+            now: start,
+        };
+
+        let inputs = harness.inputs();
+        inputs.dc_ok = true;
+        inputs.estop_ok = true;
+        inputs.fieldbus_ok = true;
+        inputs.station_running = true;
+        harness.set_pressure(0.3);
+
+        harness.step();
+        harness.reset_fault();
+        assert!(
+            !harness.outputs().indicator_fault,
+            "logic faulted on startup"
+        );
+        harness
+    }
+
+    /// Start the logic with the pressure and sensors driven by a plant model
+    pub fn with_plant(plant: Plant) -> Self {
+        let mut harness = Self::new();
+        harness.plant = Some(plant);
+        harness
+    }
+
+    pub fn inputs(&mut self) -> &mut LogicInputs {
+        self.logic.inputs_mut()
+    }
+
+    pub fn outputs(&self) -> &LogicOutputs {
+        self.logic.outputs()
+    }
+
+    /// Virtual time since the harness was started
+    pub fn elapsed(&self) -> Duration {
+        self.now - self.start
+    }
+
+    /// Set the pressure sensor input, quantized like the analog input module
+    pub fn set_pressure(&mut self, mbar: f64) {
+        self.inputs().pressure_fullscale = pressure_fullscale(mbar);
+    }
+
+    /// Run a single cycle of the logic
+    pub fn step(&mut self) {
+        self.now += CYCLE;
+        if let Some(plant) = &mut self.plant {
+            plant.update(self.now, self.logic.outputs());
+            plant.write_inputs(self.logic.inputs_mut());
+        }
+        self.logic.run(self.now);
+    }
+
+    pub fn run_for(&mut self, duration: Duration) {
+        self.script(duration, |_, _| ());
+    }
+
+    /// Run for `duration`, letting `f` modify the inputs before each cycle
+    ///
+    /// `f` receives the time since the start of the script.
+    pub fn script(&mut self, duration: Duration, mut f: impl FnMut(Duration, &mut LogicInputs)) {
+        let end = self.now + duration;
+        let script_start = self.now;
+        while self.now < end {
+            f(self.now - script_start, self.logic.inputs_mut());
+            self.step();
+        }
+    }
+
+    /// Run until `f` holds, returning how long it took or `None` on timeout
+    pub fn run_until(
+        &mut self,
+        timeout: Duration,
+        mut f: impl FnMut(&Logic) -> bool,
+    ) -> Option<Duration> {
+        let start = self.now;
+        while self.now - start < timeout {
+            self.step();
+            if f(&self.logic) {
+                return Some(self.now - start);
+            }
+        }
+        None
+    }
+
+    /// Change the pressure linearly from `from` to `to` over `duration`
+    pub fn ramp_pressure(&mut self, from: f64, to: f64, duration: Duration) {
+        self.script(duration, |t, inputs| {
+            let mbar = from + (to - from) * t.as_secs_f64() / duration.as_secs_f64();
+            inputs.pressure_fullscale = pressure_fullscale(mbar);
+        });
+        self.set_pressure(to);
+    }
+
+    /// Run one cycle with an input set, like a button press from the HTTP API
+    pub fn pulse(&mut self, input: impl Fn(&mut LogicInputs) -> &mut bool) {
+        *input(self.logic.inputs_mut()) = true;
+        self.step();
+        *input(self.logic.inputs_mut()) = false;
+    }
+
+    pub fn trigger_fan(&mut self) {
+        self.pulse(|i| &mut i.trigger_fan);
+    }
+
+    pub fn trigger_sleep(&mut self) {
+        self.pulse(|i| &mut i.trigger_sleep);
+    }
+
+    pub fn reset_fault(&mut self) {
+        self.pulse(|i| &mut i.reset_fault);
+    }
+}
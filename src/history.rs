@@ -0,0 +1,476 @@
+//! Trend history of the crab
+//!
+//! The pressure, fan and fault state, the emotion and all channels are sampled into a ring buffer
+//! at a configurable resolution.  Optionally the samples are also appended to a CSV file, which
+//! is loaded again on startup so the trends survive a restart.  The history can be queried
+//! through GraphQL and exported as CSV.
+
+use std::collections::VecDeque;
+use std::io::Write as _;
+
+use crate::logic::{Channels, Emotion, Logic};
+
+/// Sampling of the trend history, from the `[history]` section of the configuration
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HistoryConfig {
+    /// Time between two samples in seconds
+    pub resolution_s: f64,
+    /// How long samples are kept in seconds
+    pub retention_s: f64,
+    /// CSV file to persist the samples in
+    pub path: Option<std::path::PathBuf>,
+}
+
+impl Default for HistoryConfig {
+    fn default() -> Self {
+        Self {
+            resolution_s: 1.,
+            retention_s: 24. * 60. * 60.,
+            path: None,
+        }
+    }
+}
+
+impl HistoryConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if !self.resolution_s.is_finite() || self.resolution_s < 0.05 {
+            return Err(format!(
+                "history resolution {} s must be at least one cycle (0.05 s)",
+                self.resolution_s
+            ));
+        }
+        if !self.retention_s.is_finite() || self.retention_s < self.resolution_s {
+            return Err(format!(
+                "history retention {} s must be at least the resolution",
+                self.retention_s
+            ));
+        }
+        if self.capacity() > 10_000_000 {
+            return Err("history retention is too long for the resolution".to_string());
+        }
+        Ok(())
+    }
+
+    fn capacity(&self) -> usize {
+        (self.retention_s / self.resolution_s).ceil() as usize
+    }
+}
+
+#[derive(Debug)]
+pub enum HistoryError {
+    Io(std::path::PathBuf, std::io::Error),
+}
+
+impl std::fmt::Display for HistoryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HistoryError::Io(path, e) => write!(f, "failed accessing {}: {e}", path.display()),
+        }
+    }
+}
+
+impl std::error::Error for HistoryError {}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sample {
+    /// Seconds since the UNIX epoch
+    pub time: f64,
+    pub pressure_mbar: Option<f64>,
+    pub run_fan: bool,
+    pub faulted: bool,
+    pub emotion: Option<Emotion>,
+    pub channels: Channels,
+}
+
+/// Signals in the history besides the channels
+const SIGNALS: &[&str] = &["pressure_mbar", "run_fan", "faulted", "emotion"];
+
+/// All signals which can be queried
+pub fn signals() -> impl Iterator<Item = &'static str> {
+    SIGNALS.iter().chain(Channels::NAMES).copied()
+}
+
+/// Value of a signal in a sample, the emotion is the only signal with a label instead of a number
+enum SignalValue {
+    Number(Option<f64>),
+    Label(Option<String>),
+}
+
+impl Sample {
+    fn from_logic(time: f64, logic: &Logic) -> Self {
+        Self {
+            time,
+            pressure_mbar: logic.pressure_mbar(),
+            run_fan: logic.outputs().run_fan,
+            faulted: logic.outputs().indicator_fault,
+            emotion: logic.emotion().cloned(),
+            channels: logic.outputs().channels.clone(),
+        }
+    }
+
+    fn value(&self, signal: &str) -> Option<SignalValue> {
+        let number = |b: bool| SignalValue::Number(Some(f64::from(b)));
+        match signal {
+            "pressure_mbar" => Some(SignalValue::Number(self.pressure_mbar)),
+            "run_fan" => Some(number(self.run_fan)),
+            "faulted" => Some(number(self.faulted)),
+            "emotion" => Some(SignalValue::Label(
+                self.emotion.as_ref().map(Emotion::to_string),
+            )),
+            channel => self.channels.get(channel).map(number),
+        }
+    }
+
+    fn csv_header() -> String {
+        signals().fold("time".to_string(), |header, signal| header + "," + signal)
+    }
+
+    fn to_csv(&self) -> String {
+        let mut line = format!("{:.3}", self.time);
+        for signal in signals() {
+            line.push(',');
+            match self.value(signal) {
+                Some(SignalValue::Number(Some(v))) => line += &v.to_string(),
+                Some(SignalValue::Label(Some(l))) => line += &l,
+                _ => (),
+            }
+        }
+        line
+    }
+
+    fn from_csv(line: &str) -> Option<Self> {
+        use serde::Deserialize as _;
+        use serde::de::IntoDeserializer as _;
+
+        let mut fields = line.split(',');
+        let mut sample = Sample {
+            time: fields.next()?.parse().ok()?,
+            pressure_mbar: match fields.next()? {
+                "" => None,
+                v => Some(v.parse().ok()?),
+            },
+            run_fan: fields.next()? == "1",
+            faulted: fields.next()? == "1",
+            emotion: match fields.next()? {
+                "" => None,
+                v => Some(
+                    Emotion::deserialize(v.into_deserializer())
+                        .map_err(|_: serde::de::value::Error| ())
+                        .ok()?,
+                ),
+            },
+            channels: Channels::default(),
+        };
+        for name in Channels::NAMES {
+            *sample.channels.get_mut(name)? = fields.next()? == "1";
+        }
+        fields.next().is_none().then_some(sample)
+    }
+}
+
+/// A point of a queried signal
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "graphql", derive(juniper::GraphQLObject))]
+pub struct HistoryPoint {
+    /// Seconds since the UNIX epoch
+    pub time: f64,
+    /// Value of numeric signals, booleans are 0 or 1 and averaged when downsampling
+    pub value: Option<f64>,
+    /// Value of the emotion signal
+    pub label: Option<String>,
+}
+
+pub type SharedHistory = std::sync::Arc<std::sync::Mutex<History>>;
+
+#[derive(Debug)]
+pub struct History {
+    config: HistoryConfig,
+    samples: VecDeque<Sample>,
+    next_sample: f64,
+    file: Option<std::io::BufWriter<std::fs::File>>,
+}
+
+impl History {
+    /// Create the history, loading the samples persisted in the configured file
+    pub fn open(config: HistoryConfig) -> Result<Self, HistoryError> {
+        let mut history = Self {
+            samples: VecDeque::with_capacity(config.capacity().min(100_000)),
+            next_sample: 0.,
+            file: None,
+            config,
+        };
+
+        if let Some(path) = history.config.path.clone() {
+            let io_err = |e| HistoryError::Io(path.clone(), e);
+            match std::fs::read_to_string(&path) {
+                Ok(contents) => {
+                    let lines = contents.lines().skip(1);
+                    let samples: Vec<_> = lines.filter_map(Sample::from_csv).collect();
+                    let oldest =
+                        unix_time(std::time::SystemTime::now()) - history.config.retention_s;
+                    for sample in samples.into_iter().filter(|s| s.time >= oldest) {
+                        history.push(sample);
+                    }
+                    log::info!(
+                        "Loaded {} history samples from {}.",
+                        history.samples.len(),
+                        path.display()
+                    );
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+                Err(e) => return Err(io_err(e)),
+            }
+
+            // Rewrite the file with only the retained samples so it does not grow forever
+            let mut tmp_path = path.clone().into_os_string();
+            tmp_path.push(".tmp");
+            let tmp_path = std::path::PathBuf::from(tmp_path);
+            let mut file =
+                std::io::BufWriter::new(std::fs::File::create(&tmp_path).map_err(io_err)?);
+            history
+                .write_csv(f64::NEG_INFINITY, f64::INFINITY, &mut file)
+                .map_err(io_err)?;
+            file.flush().map_err(io_err)?;
+            std::fs::rename(&tmp_path, &path).map_err(io_err)?;
+            history.file = Some(file);
+        }
+
+        Ok(history)
+    }
+
+    /// Take a sample of the logic, if one is due
+    pub fn record(&mut self, wall_clock: std::time::SystemTime, logic: &Logic) {
+        let time = unix_time(wall_clock);
+        // Also sample right away when the clock was set back
+        if time < self.next_sample && time >= self.next_sample - self.config.resolution_s {
+            return;
+        }
+        // Keep the samples aligned to the resolution
+        self.next_sample =
+            ((time / self.config.resolution_s).floor() + 1.) * self.config.resolution_s;
+
+        let sample = Sample::from_logic(time, logic);
+        if let Some(file) = &mut self.file {
+            let result = writeln!(file, "{}", sample.to_csv()).and_then(|_| file.flush());
+            if let Err(e) = result {
+                log::error!("Not persisting the history anymore: {e}");
+                self.file = None;
+            }
+        }
+        self.push(sample);
+    }
+
+    fn push(&mut self, sample: Sample) {
+        if self.samples.len() >= self.config.capacity() {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(sample);
+    }
+
+    fn range(&self, from: f64, to: f64) -> impl Iterator<Item = &Sample> {
+        let start = self.samples.partition_point(|s| s.time < from);
+        self.samples
+            .range(start..)
+            .take_while(move |s| s.time <= to)
+    }
+
+    /// Values of a signal between `from` and `to`, reduced to at most `downsample` points
+    pub fn query(
+        &self,
+        signal: &str,
+        from: f64,
+        to: f64,
+        downsample: Option<usize>,
+    ) -> Result<Vec<HistoryPoint>, String> {
+        if !signals().any(|s| s == signal) {
+            return Err(format!("unknown signal {signal:?}"));
+        }
+        if downsample == Some(0) {
+            return Err("downsample must be positive".to_string());
+        }
+
+        let points: Vec<_> = self
+            .range(from, to)
+            .map(|sample| {
+                let (value, label) = match sample.value(signal) {
+                    Some(SignalValue::Number(v)) => (v, None),
+                    Some(SignalValue::Label(l)) => (None, l),
+                    None => (None, None),
+                };
+                HistoryPoint {
+                    time: sample.time,
+                    value,
+                    label,
+                }
+            })
+            .collect();
+
+        match downsample {
+            Some(n) if points.len() > n => Ok(downsample_points(&points, n)),
+            _ => Ok(points),
+        }
+    }
+
+    /// Write all samples between `from` and `to` as CSV, including a header
+    pub fn write_csv(
+        &self,
+        from: f64,
+        to: f64,
+        w: &mut impl std::io::Write,
+    ) -> std::io::Result<()> {
+        writeln!(w, "{}", Sample::csv_header())?;
+        for sample in self.range(from, to) {
+            writeln!(w, "{}", sample.to_csv())?;
+        }
+        Ok(())
+    }
+}
+
+/// Reduce the points to `n` buckets of equal count
+///
+/// Numbers are averaged, labels take the last value of the bucket.
+fn downsample_points(points: &[HistoryPoint], n: usize) -> Vec<HistoryPoint> {
+    let bucket_size = points.len().div_ceil(n);
+    points
+        .chunks(bucket_size)
+        .map(|bucket| {
+            let values: Vec<f64> = bucket.iter().filter_map(|p| p.value).collect();
+            HistoryPoint {
+                time: bucket[0].time,
+                value: (!values.is_empty())
+                    .then(|| values.iter().sum::<f64>() / values.len() as f64),
+                label: bucket.last().and_then(|p| p.label.clone()),
+            }
+        })
+        .collect()
+}
+
+fn unix_time(t: std::time::SystemTime) -> f64 {
+    t.duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64()
+}
+
+#[cfg(feature = "graphql")]
+#[derive(serde::Deserialize)]
+struct CsvQuery {
+    from: Option<f64>,
+    to: Option<f64>,
+}
+
+#[cfg(feature = "graphql")]
+async fn get_history_csv(
+    axum::Extension(history): axum::Extension<SharedHistory>,
+    axum::extract::Query(query): axum::extract::Query<CsvQuery>,
+) -> impl axum::response::IntoResponse {
+    let mut csv = Vec::new();
+    history
+        .lock()
+        .unwrap()
+        .write_csv(
+            query.from.unwrap_or(f64::NEG_INFINITY),
+            query.to.unwrap_or(f64::INFINITY),
+            &mut csv,
+        )
+        .expect("writing to memory cannot fail");
+    ([(axum::http::header::CONTENT_TYPE, "text/csv")], csv)
+}
+
+/// Routes for exporting the history as CSV
+#[cfg(feature = "graphql")]
+pub fn axum_router<S: Clone + Send + Sync + 'static>(history: SharedHistory) -> axum::Router<S> {
+    axum::Router::new()
+        .route("/crab/history.csv", axum::routing::get(get_history_csv))
+        .layer(axum::Extension(history))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, SystemTime};
+
+    fn history(config: HistoryConfig) -> (History, crate::harness::Harness) {
+        (
+            History::open(config).unwrap(),
+            crate::harness::Harness::new(),
+        )
+    }
+
+    #[test]
+    fn sampling_and_queries() {
+        let (mut history, mut h) = history(HistoryConfig {
+            retention_s: 10.,
+            ..Default::default()
+        });
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        h.inputs().emotion = Some(Emotion::new("Happy"));
+
+        // Sampled once per second, no matter the cycle time
+        for i in 0..300 {
+            h.step();
+            history.record(start + crate::harness::CYCLE * i, &h.logic);
+        }
+        assert_eq!(history.samples.len(), 10, "retention not applied");
+
+        let t0 = history.samples[0].time;
+        let points = history.query("pressure_mbar", t0, t0 + 3., None).unwrap();
+        assert_eq!(points.len(), 4);
+        assert!(points.iter().all(|p| (p.value.unwrap() - 0.3).abs() < 0.05));
+
+        let points = history
+            .query("eyes", f64::NEG_INFINITY, f64::INFINITY, Some(3))
+            .unwrap();
+        assert_eq!(points.len(), 3);
+        assert_eq!(points[0].value, Some(1.));
+
+        let points = history.query("emotion", t0, t0, None).unwrap();
+        assert_eq!(points[0].label.as_deref(), Some("Happy"));
+
+        assert!(history.query("nose", t0, t0, None).is_err());
+        assert!(history.query("eyes", t0, t0, Some(0)).is_err());
+    }
+
+    #[test]
+    fn csv_roundtrip() {
+        let mut h = crate::harness::Harness::new();
+        h.logic.inputs_mut().emotion = Some(Emotion::new("Sad"));
+        h.step();
+        let sample = Sample::from_logic(1234.5, &h.logic);
+        assert_eq!(Sample::from_csv(&sample.to_csv()), Some(sample));
+        assert_eq!(
+            Sample::csv_header().split(',').count(),
+            1 + SIGNALS.len() + Channels::NAMES.len()
+        );
+        assert_eq!(Sample::from_csv("1,2,3"), None);
+    }
+
+    #[test]
+    fn persistence() {
+        let path = std::env::temp_dir().join(format!("crab-history-{}.csv", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let config = HistoryConfig {
+            path: Some(path.clone()),
+            ..Default::default()
+        };
+
+        let (mut history, mut h) = history(config.clone());
+        let now = SystemTime::now();
+        for i in 0..5 {
+            h.step();
+            history.record(now + Duration::from_secs(i), &h.logic);
+        }
+        // A sample beyond the retention is dropped when loading
+        std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(b"1.000,,0,0,,0,0,0,0,0,0,0,0,0,0,0,0,0\n")
+            .unwrap();
+        drop(history);
+
+        let history = History::open(config).unwrap();
+        assert_eq!(history.samples.len(), 5);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
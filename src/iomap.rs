@@ -0,0 +1,419 @@
+//! Mapping between the fieldbus process images and the logic inputs/outputs
+//!
+//! The I/O map is loaded from a TOML file at startup so terminals can be rewired without
+//! recompiling.  Each entry assigns a logic symbol to an address in the PII (inputs) or PIQ
+//! (outputs):
+//!
+//! ```toml
+//! [[output]]
+//! symbol = "indicator_fault"
+//! address = 4
+//! bit = 0
+//! type = "bool"
+//! invert = true
+//! tag = "-KEC1-K5 DO1"
+//! ```
+use crate::logic::{Channels, LogicInputs, LogicOutputs};
+
+// Sizes for the controller-side global input and output process images
+pub const PIQ_SIZE: usize = 256;
+pub const PII_SIZE: usize = 256;
+
+/// I/O map that is used when no map file is present
+const DEFAULT_IOMAP: &str = include_str!("../iomap.toml");
+
+/// Location of the I/O map, from the `[iomap]` section of the configuration
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct IoMapConfig {
+    pub path: std::path::PathBuf,
+}
+
+impl Default for IoMapConfig {
+    fn default() -> Self {
+        Self {
+            path: "iomap.toml".into(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DataType {
+    /// Single bit (`X`)
+    Bool,
+    /// 16-bit big-endian word (`W`)
+    Word,
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct IoMapEntry {
+    symbol: String,
+    address: usize,
+    bit: Option<u8>,
+    #[serde(rename = "type")]
+    data_type: DataType,
+    #[serde(default)]
+    invert: bool,
+    /// Equipment tag of the terminal channel, e.g. `-KEC1-K5 DO1`, named in errors
+    #[serde(default)]
+    tag: String,
+    /// Free-form note for the reader of the map
+    #[serde(default, rename = "comment")]
+    _comment: serde::de::IgnoredAny,
+}
+
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct IoMapFile {
+    #[serde(default, rename = "input")]
+    inputs: Vec<IoMapEntry>,
+    #[serde(default, rename = "output")]
+    outputs: Vec<IoMapEntry>,
+}
+
+#[derive(Debug)]
+pub enum IoMapError {
+    Io(std::path::PathBuf, std::io::Error),
+    Parse(toml::de::Error),
+    Invalid {
+        symbol: String,
+        /// Equipment tag of the entry, empty if none was given
+        tag: String,
+        reason: String,
+    },
+}
+
+impl std::fmt::Display for IoMapError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IoMapError::Io(path, e) => write!(f, "failed reading {}: {e}", path.display()),
+            IoMapError::Parse(e) => write!(f, "failed parsing I/O map: {e}"),
+            IoMapError::Invalid {
+                symbol,
+                tag,
+                reason,
+            } => {
+                write!(f, "invalid I/O map entry for \"{symbol}\"")?;
+                if !tag.is_empty() {
+                    write!(f, " ({tag})")?;
+                }
+                write!(f, ": {reason}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for IoMapError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum InputSymbol {
+    DcOk,
+    EstopOk,
+    PressureFullscale,
+}
+
+impl InputSymbol {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "dc_ok" => Some(InputSymbol::DcOk),
+            "estop_ok" => Some(InputSymbol::EstopOk),
+            "pressure_fullscale" => Some(InputSymbol::PressureFullscale),
+            _ => None,
+        }
+    }
+
+    fn data_type(self) -> DataType {
+        match self {
+            InputSymbol::DcOk | InputSymbol::EstopOk => DataType::Bool,
+            InputSymbol::PressureFullscale => DataType::Word,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum OutputSymbol {
+    Channel(&'static str),
+    IndicatorFault,
+    IndicatorRefillAir,
+    RunFan,
+}
+
+impl OutputSymbol {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "indicator_fault" => Some(OutputSymbol::IndicatorFault),
+            "indicator_refill_air" => Some(OutputSymbol::IndicatorRefillAir),
+            "run_fan" => Some(OutputSymbol::RunFan),
+            _ => {
+                let channel = name.strip_prefix("channels.")?;
+                Channels::NAMES
+                    .iter()
+                    .copied()
+                    .find(|n| *n == channel)
+                    .map(OutputSymbol::Channel)
+            }
+        }
+    }
+
+    fn data_type(self) -> DataType {
+        DataType::Bool
+    }
+
+    fn value(self, outputs: &LogicOutputs) -> bool {
+        match self {
+            OutputSymbol::Channel(name) => outputs.channels.get(name).unwrap(),
+            OutputSymbol::IndicatorFault => outputs.indicator_fault,
+            OutputSymbol::IndicatorRefillAir => outputs.indicator_refill_air,
+            OutputSymbol::RunFan => outputs.run_fan,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Location {
+    Bit {
+        address: usize,
+        bit: u8,
+        invert: bool,
+    },
+    Word {
+        address: usize,
+    },
+}
+
+impl Location {
+    fn from_entry(entry: &IoMapEntry, expected: DataType, size: usize) -> Result<Self, String> {
+        if entry.data_type != expected {
+            return Err(format!(
+                "symbol has type {expected:?}, but {:?} was given",
+                entry.data_type
+            ));
+        }
+
+        match (entry.data_type, entry.bit) {
+            (DataType::Bool, Some(bit)) => {
+                if bit > 7 {
+                    return Err(format!("bit must be 0..7, got {bit}"));
+                }
+                if entry.address >= size {
+                    return Err(format!(
+                        "address {} is outside the process image (size {size})",
+                        entry.address
+                    ));
+                }
+                Ok(Location::Bit {
+                    address: entry.address,
+                    bit,
+                    invert: entry.invert,
+                })
+            }
+            (DataType::Bool, None) => Err("bool symbols need a bit number".to_string()),
+            (DataType::Word, Some(_)) => Err("word symbols cannot have a bit number".to_string()),
+            (DataType::Word, None) => {
+                if entry.invert {
+                    return Err("word symbols cannot be inverted".to_string());
+                }
+                if !entry.address.is_multiple_of(2) {
+                    return Err(format!(
+                        "word address must be divisible by 2, got {}",
+                        entry.address
+                    ));
+                }
+                if entry.address + 2 > size {
+                    return Err(format!(
+                        "address {} is outside the process image (size {size})",
+                        entry.address
+                    ));
+                }
+                Ok(Location::Word {
+                    address: entry.address,
+                })
+            }
+        }
+    }
+
+    /// Whether two locations refer to (partially) the same bits
+    fn overlaps(&self, other: &Location) -> bool {
+        let bits = |l: &Location| match *l {
+            Location::Bit { address, bit, .. } => {
+                let b = address * 8 + usize::from(bit);
+                b..b + 1
+            }
+            Location::Word { address } => address * 8..address * 8 + 16,
+        };
+        let (a, b) = (bits(self), bits(other));
+        a.start < b.end && b.start < a.end
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct IoMap {
+    inputs: Vec<(InputSymbol, Location)>,
+    outputs: Vec<(OutputSymbol, Location)>,
+}
+
+impl IoMap {
+    /// Load the I/O map from `path`, falling back to the built-in map when the file is missing
+    pub fn load(path: &std::path::Path) -> Result<Self, IoMapError> {
+        match std::fs::read_to_string(path) {
+            Ok(s) => Self::from_toml(&s),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                log::warn!(
+                    "No I/O map found at {}, using built-in default.",
+                    path.display()
+                );
+                Self::from_toml(DEFAULT_IOMAP)
+            }
+            Err(e) => Err(IoMapError::Io(path.to_owned(), e)),
+        }
+    }
+
+    pub fn from_toml(s: &str) -> Result<Self, IoMapError> {
+        let file: IoMapFile = toml::from_str(s).map_err(IoMapError::Parse)?;
+
+        let invalid = |entry: &IoMapEntry, reason: String| IoMapError::Invalid {
+            symbol: entry.symbol.clone(),
+            tag: entry.tag.clone(),
+            reason,
+        };
+
+        let mut inputs: Vec<(InputSymbol, Location)> = Vec::new();
+        for entry in file.inputs.iter() {
+            let symbol = InputSymbol::from_name(&entry.symbol)
+                .ok_or_else(|| invalid(entry, "unknown input symbol".to_string()))?;
+            let location = Location::from_entry(entry, symbol.data_type(), PII_SIZE)
+                .map_err(|r| invalid(entry, r))?;
+            if inputs.iter().any(|(s, _)| *s == symbol) {
+                return Err(invalid(entry, "input symbol is mapped twice".to_string()));
+            }
+            inputs.push((symbol, location));
+        }
+
+        let mut outputs: Vec<(OutputSymbol, Location)> = Vec::new();
+        for entry in file.outputs.iter() {
+            let symbol = OutputSymbol::from_name(&entry.symbol)
+                .ok_or_else(|| invalid(entry, "unknown output symbol".to_string()))?;
+            let location = Location::from_entry(entry, symbol.data_type(), PIQ_SIZE)
+                .map_err(|r| invalid(entry, r))?;
+            if outputs.iter().any(|(_, l)| l.overlaps(&location)) {
+                return Err(invalid(
+                    entry,
+                    "output address is already assigned to another symbol".to_string(),
+                ));
+            }
+            outputs.push((symbol, location));
+        }
+
+        Ok(Self { inputs, outputs })
+    }
+
+    /// Read all mapped inputs from the PII into the logic inputs
+    pub fn read_inputs(&self, pii: &[u8; PII_SIZE], inputs: &mut LogicInputs) {
+        for (symbol, location) in self.inputs.iter() {
+            match (*symbol, *location) {
+                (InputSymbol::DcOk, Location::Bit { .. }) => inputs.dc_ok = read_bit(pii, location),
+                (InputSymbol::EstopOk, Location::Bit { .. }) => {
+                    inputs.estop_ok = read_bit(pii, location)
+                }
+                (InputSymbol::PressureFullscale, Location::Word { address }) => {
+                    inputs.pressure_fullscale = process_image::tag!(pii, W, address).into()
+                }
+                _ => unreachable!("data type was validated when loading"),
+            }
+        }
+    }
+
+    /// Write all mapped outputs from the logic outputs into the PIQ
+    pub fn write_outputs(&self, outputs: &LogicOutputs, piq: &mut [u8; PIQ_SIZE]) {
+        for (symbol, location) in self.outputs.iter() {
+            match *location {
+                Location::Bit {
+                    address,
+                    bit,
+                    invert,
+                } => {
+                    *process_image::tag_mut!(piq, X, address, bit) = symbol.value(outputs) ^ invert
+                }
+                Location::Word { .. } => unreachable!("data type was validated when loading"),
+            }
+        }
+    }
+}
+
+fn read_bit(pi: &[u8], location: &Location) -> bool {
+    match *location {
+        Location::Bit {
+            address,
+            bit,
+            invert,
+        } => process_image::tag!(pi, X, address, bit) ^ invert,
+        Location::Word { .. } => unreachable!(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_map() {
+        let iomap = IoMap::from_toml(DEFAULT_IOMAP).unwrap();
+
+        let mut outputs = LogicOutputs::default();
+        outputs.channels.mouth_top = true;
+        outputs.run_fan = true;
+
+        let mut piq = [0u8; PIQ_SIZE];
+        iomap.write_outputs(&outputs, &mut piq);
+        assert_eq!(piq[1], 0b1000);
+        // Fault indicator is inverted
+        assert_eq!(piq[4], 0b101);
+
+        let mut pii = [0u8; PII_SIZE];
+        pii[1] = 0b10;
+        pii[2] = 0x12;
+        pii[3] = 0x38;
+        let mut inputs = LogicInputs::default();
+        iomap.read_inputs(&pii, &mut inputs);
+        assert!(!inputs.dc_ok);
+        assert!(inputs.estop_ok);
+        assert_eq!(inputs.pressure_fullscale, 0x1238);
+    }
+
+    #[test]
+    fn invalid_maps() {
+        let check = |s: &str| match IoMap::from_toml(s) {
+            Err(IoMapError::Invalid { .. }) => (),
+            r => panic!("expected validation error, got {r:?}"),
+        };
+
+        // Unknown symbol
+        check("[[input]]\nsymbol = \"foo\"\naddress = 0\nbit = 0\ntype = \"bool\"");
+        // Wrong datatype
+        check("[[input]]\nsymbol = \"dc_ok\"\naddress = 0\ntype = \"word\"");
+        // Outside of the process image
+        check(&format!(
+            "[[output]]\nsymbol = \"run_fan\"\naddress = {PIQ_SIZE}\nbit = 0\ntype = \"bool\""
+        ));
+        // Unaligned word
+        check("[[input]]\nsymbol = \"pressure_fullscale\"\naddress = 3\ntype = \"word\"");
+        // Two outputs on the same bit
+        check(concat!(
+            "[[output]]\nsymbol = \"run_fan\"\naddress = 0\nbit = 1\ntype = \"bool\"\n",
+            "[[output]]\nsymbol = \"channels.eyes\"\naddress = 0\nbit = 1\ntype = \"bool\"\n",
+        ));
+
+        // The equipment tag points to the terminal in the error, comments are ignored
+        let e = IoMap::from_toml(concat!(
+            "[[output]]\nsymbol = \"run_fan\"\naddress = 0\nbit = 9\ntype = \"bool\"\n",
+            "tag = \"-KEC1-K5 DO1\"\ncomment = \"fan contactor\"\n",
+        ))
+        .unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "invalid I/O map entry for \"run_fan\" (-KEC1-K5 DO1): bit must be 0..7, got 9"
+        );
+    }
+}
@@ -1,9 +1,12 @@
+use crate::alarms::{AlarmId, AlarmManager};
+use crate::emotions::Emotions;
 use crate::timers;
 use timers::TimeExt;
 
 pub use crab_httpapi::emotionmanager::Emotion;
+pub use crab_httpapi::parameters::{LogicParameters, PressureLimits};
 
-#[derive(Debug, Default, Clone, PartialEq, Eq)]
+#[derive(Debug, Default, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
 #[cfg_attr(feature = "graphql", derive(juniper::GraphQLObject))]
 pub struct Channels {
     // Outline
@@ -32,27 +35,81 @@ pub struct Channels {
     // pub left_leg_back: bool,
 }
 
-#[derive(Debug, Clone)]
-#[cfg_attr(feature = "graphql", derive(juniper::GraphQLObject))]
-pub struct PressureLimits {
-    pub low_low: f64,
-    pub low: f64,
-    pub high: f64,
-    pub high_high: f64,
-}
+#[allow(dead_code)]
+impl Channels {
+    /// Names of all channels, as used in configuration files
+    pub const NAMES: &'static [&'static str] = &[
+        "bottom_front",
+        "bottom_back",
+        "spikes_left",
+        "spikes_mid",
+        "spikes_right",
+        "eyes",
+        "pupil_top",
+        "pupil_down",
+        "mouth_mid",
+        "mouth_top",
+        "mouth_bottom",
+        "right_claw",
+        "left_claw",
+    ];
+
+    /// Look up a channel by its name
+    pub fn get(&self, name: &str) -> Option<bool> {
+        match name {
+            "bottom_front" => Some(self.bottom_front),
+            "bottom_back" => Some(self.bottom_back),
+            "spikes_left" => Some(self.spikes_left),
+            "spikes_mid" => Some(self.spikes_mid),
+            "spikes_right" => Some(self.spikes_right),
+            "eyes" => Some(self.eyes),
+            "pupil_top" => Some(self.pupil_top),
+            "pupil_down" => Some(self.pupil_down),
+            "mouth_mid" => Some(self.mouth_mid),
+            "mouth_top" => Some(self.mouth_top),
+            "mouth_bottom" => Some(self.mouth_bottom),
+            "right_claw" => Some(self.right_claw),
+            "left_claw" => Some(self.left_claw),
+            _ => None,
+        }
+    }
 
-impl Default for PressureLimits {
-    fn default() -> Self {
-        Self {
-            low_low: 0.02,
-            low: 0.2,
-            high: 0.45,
-            high_high: 0.6,
+    /// Look up a channel by its name for modification
+    pub fn get_mut(&mut self, name: &str) -> Option<&mut bool> {
+        match name {
+            "bottom_front" => Some(&mut self.bottom_front),
+            "bottom_back" => Some(&mut self.bottom_back),
+            "spikes_left" => Some(&mut self.spikes_left),
+            "spikes_mid" => Some(&mut self.spikes_mid),
+            "spikes_right" => Some(&mut self.spikes_right),
+            "eyes" => Some(&mut self.eyes),
+            "pupil_top" => Some(&mut self.pupil_top),
+            "pupil_down" => Some(&mut self.pupil_down),
+            "mouth_mid" => Some(&mut self.mouth_mid),
+            "mouth_top" => Some(&mut self.mouth_top),
+            "mouth_bottom" => Some(&mut self.mouth_bottom),
+            "right_claw" => Some(&mut self.right_claw),
+            "left_claw" => Some(&mut self.left_claw),
+            _ => None,
         }
     }
 }
 
-#[derive(Debug, Default, Clone)]
+/// Shortest time between two switchings of a mouth channel
+///
+/// The 750-504 outputs drive the mouth through relays, which neither keep up with nor survive
+/// much faster toggling.
+pub const MOUTH_MIN_SWITCH: std::time::Duration = std::time::Duration::from_millis(150);
+
+/// Open mouth shapes cycled through while talking, as `(mouth_top, mouth_mid, mouth_bottom)`
+const TALK_SHAPES: [(bool, bool, bool); 3] =
+    [(true, false, true), (true, true, true), (false, true, true)];
+
+fn seconds(s: f64) -> std::time::Duration {
+    std::time::Duration::from_secs_f64(s)
+}
+
+#[derive(Debug, Default, Clone, serde::Serialize, serde::Deserialize)]
 #[cfg_attr(feature = "graphql", derive(juniper::GraphQLObject))]
 pub struct LogicInputs {
     pub emotion: Option<Emotion>,
@@ -63,9 +120,20 @@ pub struct LogicInputs {
     pub trigger_sleep: bool,
     pub reset_fault: bool,
     pub pressure_limits: PressureLimits,
+    /// A fieldbus peripheral reports a fault in its diagnostics
+    pub fieldbus_diagnostic_fault: bool,
+    /// Fieldbus master is taking part in the token ring
+    pub fieldbus_ok: bool,
+    /// All fieldbus peripherals are exchanging data
+    pub station_running: bool,
+    /// Number of syllables the crab starts saying, 0 when it is not told anything new
+    pub talk_syllables: i32,
+    /// Step of the sequence being played, overrides the emotion and individual channels
+    #[cfg_attr(feature = "graphql", graphql(ignore))]
+    pub sequence_step: Option<crab_httpapi::sequences::SequenceStep>,
 }
 
-#[derive(Debug, Default, Clone)]
+#[derive(Debug, Default, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
 #[cfg_attr(feature = "graphql", derive(juniper::GraphQLObject))]
 pub struct LogicOutputs {
     pub channels: Channels,
@@ -90,6 +158,14 @@ pub struct Logic {
     close_mouth: bool,
     t_close_mouth: timers::BaseTimer<bool>,
 
+    /// Syllables of the current message, 0 when not talking
+    talk_syllables: i32,
+    t_talk: timers::BaseTimer<bool>,
+
+    /// Last switching of `mouth_top`, `mouth_mid` and `mouth_bottom`
+    #[cfg_attr(feature = "graphql", graphql(ignore))]
+    t_mouth: [timers::BaseTimer<bool>; 3],
+
     t_highhigh_alarm: timers::TimerOn,
 
     t_fan: timers::BaseTimer<bool>,
@@ -97,12 +173,17 @@ pub struct Logic {
 
     t_info: timers::BaseTimer<bool>,
     t_emotion: timers::BaseTimer<Option<Emotion>>,
+    /// Emotion shown, from the sequence being played or the emotion manager
+    emotion: Option<Emotion>,
 
     sleeping: bool,
 
     faulted: bool,
     reset_fault_last: bool,
 
+    /// Inputs are stale because the fieldbus communication is interrupted
+    fieldbus_fault: bool,
+
     /// Pressure converted to engineering units
     ///
     /// None when no value is available
@@ -115,6 +196,14 @@ pub struct Logic {
 
     logic_initialized: bool,
 
+    parameters: LogicParameters,
+
+    #[cfg_attr(feature = "graphql", graphql(ignore))]
+    emotions: std::sync::Arc<Emotions>,
+
+    #[cfg_attr(feature = "graphql", graphql(ignore))]
+    alarms: AlarmManager,
+
     #[cfg_attr(feature = "graphql", graphql(ignore))]
     last_fan_start: Option<std::time::Instant>,
 }
@@ -124,7 +213,6 @@ impl Logic {
         Self::default()
     }
 
-    #[allow(dead_code)]
     pub fn inputs(&self) -> &LogicInputs {
         &self.inp
     }
@@ -136,15 +224,89 @@ impl Logic {
     pub fn outputs(&self) -> &LogicOutputs {
         &self.out
     }
+
+    /// Emotion shown, from the sequence being played or the emotion manager
+    pub fn emotion(&self) -> Option<&Emotion> {
+        self.emotion.as_ref()
+    }
+
+    /// Channel patterns of the emotions
+    pub fn set_emotions(&mut self, emotions: std::sync::Arc<Emotions>) {
+        self.emotions = emotions;
+    }
+
+    pub fn parameters(&self) -> &LogicParameters {
+        &self.parameters
+    }
+
+    pub fn set_parameters(&mut self, parameters: LogicParameters) {
+        self.parameters = parameters;
+    }
+
+    /// Pressure in millibar, `None` while no valid value is available
+    #[cfg_attr(not(feature = "graphql"), allow(dead_code))]
+    pub fn pressure_mbar(&self) -> Option<f64> {
+        self.pressure_mbar
+    }
+
+    pub fn alarms(&self) -> &AlarmManager {
+        &self.alarms
+    }
+
+    /// Acknowledge one or all active alarms
+    pub fn acknowledge_alarms(&mut self, now: std::time::Instant, id: Option<AlarmId>) {
+        self.alarms.acknowledge(now, id);
+    }
+}
+
+impl Logic {
+    /// Convert the state for the REST API
+    pub fn status(&self) -> crab_httpapi::status::CrabStatus {
+        let channels = &self.out.channels;
+        crab_httpapi::status::CrabStatus {
+            emotion: self.emotion.clone(),
+            sleeping: self.sleeping,
+            pressure: crab_httpapi::status::PressureStatus {
+                pressure_mbar: self.pressure_mbar,
+                limits: self.inp.pressure_limits.clone(),
+                low_low: self.pressure_low_low,
+                low: self.pressure_low,
+                high: self.pressure_high,
+                high_high: self.pressure_high_high,
+            },
+            run_fan: self.out.run_fan,
+            faulted: self.faulted,
+            active_alarms: self
+                .alarms
+                .active()
+                .map(|id| id.name().to_string())
+                .collect(),
+            channels: Channels::NAMES
+                .iter()
+                .map(|&name| (name.to_string(), channels.get(name).unwrap()))
+                .collect(),
+        }
+    }
 }
 
 impl Logic {
     pub fn run(&mut self, now: std::time::Instant) {
         self.t_blink.run(now, self.blink);
         self.t_close_mouth.run(now, self.close_mouth);
-        self.t_emotion.run(now, self.inp.emotion);
+        self.emotion = self
+            .inp
+            .sequence_step
+            .as_ref()
+            .and_then(|step| step.emotion.clone())
+            .or_else(|| self.inp.emotion.clone());
+        self.t_emotion.run(now, self.emotion.clone());
         self.t_fan.run(now, self.out.run_fan);
 
+        let last_mouth = [
+            self.out.channels.mouth_top,
+            self.out.channels.mouth_mid,
+            self.out.channels.mouth_bottom,
+        ];
         self.out.channels = Channels {
             bottom_front: true,
             bottom_back: true,
@@ -165,7 +327,7 @@ impl Logic {
         };
 
         if !self.t_emotion.timer(now, 1.millis()) {
-            log::info!("New Emotion: {:?}", self.inp.emotion);
+            log::info!("New Emotion: {:?}", self.emotion);
             self.sleeping = false;
         }
 
@@ -174,56 +336,30 @@ impl Logic {
         }
         self.sleeping |= self.inp.trigger_sleep;
 
-        match self.inp.emotion {
-            _ if self.sleeping => {
-                self.out.channels.eyes = false;
-                self.out.channels.pupil_down = true;
-                self.out.channels.pupil_top = false;
-                self.out.channels.mouth_top = false;
-                self.out.channels.mouth_mid = true;
-                self.out.channels.mouth_bottom = false;
-            }
-            Some(Emotion::Happy) => {
-                self.out.channels.pupil_down = false;
-                self.out.channels.pupil_top = true;
-                self.out.channels.mouth_top = false;
-                self.out.channels.mouth_mid = true;
-                self.out.channels.mouth_bottom = true;
-            }
-            Some(Emotion::Sad) => {
-                self.out.channels.pupil_down = true;
-                self.out.channels.pupil_top = false;
-                self.out.channels.mouth_top = true;
-                self.out.channels.mouth_mid = false;
-                self.out.channels.mouth_bottom = false;
-            }
-            Some(Emotion::Surprised) => {
-                self.out.channels.pupil_down = false;
-                self.out.channels.pupil_top = true;
-                self.out.channels.mouth_top = true;
-                self.out.channels.mouth_mid = false;
-                self.out.channels.mouth_bottom = true;
-            }
-            Some(Emotion::Angered) => {
-                self.out.channels.pupil_down = false;
-                self.out.channels.pupil_top = true;
-                self.out.channels.mouth_top = true;
-                self.out.channels.mouth_mid = true;
-                self.out.channels.mouth_bottom = false;
-            }
-            Some(Emotion::Neutral) => {
-                self.out.channels.pupil_down = false;
-                self.out.channels.pupil_top = true;
-                self.out.channels.mouth_top = false;
-                self.out.channels.mouth_mid = true;
-                self.out.channels.mouth_bottom = false;
-            }
-            None => (),
+        if self.inp.talk_syllables > 0 {
+            self.talk_syllables = self.inp.talk_syllables;
+            self.t_talk.trigger(now);
+            self.sleeping = false;
+        } else if self.sleeping {
+            self.talk_syllables = 0;
         }
 
+        if self.sleeping {
+            self.out.channels.eyes = false;
+            self.out.channels.pupil_down = true;
+            self.out.channels.pupil_top = false;
+            self.out.channels.mouth_top = false;
+            self.out.channels.mouth_mid = true;
+            self.out.channels.mouth_bottom = false;
+        } else if let Some(emotion) = &self.emotion {
+            self.emotions.apply(emotion, &mut self.out.channels);
+        }
+
+        let p = &self.parameters;
+
         self.blink = match self.blink {
-            false if self.t_blink.timer(now, 3.secs()) => true,
-            true if self.t_blink.timer(now, 300.millis()) => false,
+            false if self.t_blink.timer(now, seconds(p.blink_interval_s)) => true,
+            true if self.t_blink.timer(now, seconds(p.blink_duration_s)) => false,
             d => d,
         };
 
@@ -238,9 +374,9 @@ impl Logic {
         }
 
         self.close_mouth = match self.close_mouth {
-            _ if !self.t_emotion.timer(now, 10.secs()) => false,
-            false if self.t_close_mouth.timer(now, 10.secs()) => true,
-            true if self.t_close_mouth.timer(now, 2.secs()) => false,
+            _ if !self.t_emotion.timer(now, seconds(p.mouth_open_s)) => false,
+            false if self.t_close_mouth.timer(now, seconds(p.mouth_open_s)) => true,
+            true if self.t_close_mouth.timer(now, seconds(p.mouth_closed_s)) => false,
             d => d,
         };
 
@@ -250,12 +386,73 @@ impl Logic {
             self.out.channels.mouth_mid = true;
         }
 
+        if self.talk_syllables > 0 {
+            // Every syllable opens and closes the mouth once, never faster than the relays allow
+            let phase = seconds(p.talk_syllable_s / 2.).max(MOUTH_MIN_SWITCH);
+            let n = self.t_talk.timer_value(now).as_nanos() / phase.as_nanos();
+            if n < 2 * self.talk_syllables as u128 {
+                let (top, mid, bottom) = if n.is_multiple_of(2) {
+                    TALK_SHAPES[(n / 2) as usize % TALK_SHAPES.len()]
+                } else {
+                    (false, true, false)
+                };
+                self.out.channels.mouth_top = top;
+                self.out.channels.mouth_mid = mid;
+                self.out.channels.mouth_bottom = bottom;
+                // Show the emotion with an open mouth once done talking
+                self.close_mouth = false;
+                self.t_close_mouth.trigger(now);
+            } else {
+                self.talk_syllables = 0;
+            }
+        }
+
+        if let Some(step) = &self.inp.sequence_step {
+            for (name, &on) in step.channels.iter() {
+                if let Some(channel) = self.out.channels.get_mut(name) {
+                    *channel = on;
+                }
+            }
+        }
+
+        // Whatever asks for the change, hold each mouth relay until it may switch again
+        let channels = &mut self.out.channels;
+        let mouth = [
+            &mut channels.mouth_top,
+            &mut channels.mouth_mid,
+            &mut channels.mouth_bottom,
+        ];
+        for ((channel, last), timer) in mouth.into_iter().zip(last_mouth).zip(&mut self.t_mouth) {
+            if *channel != last && !timer.timer(now, MOUTH_MIN_SWITCH) {
+                *channel = last;
+            }
+            timer.run(now, *channel);
+        }
+
         let reset_fault_edge = self.inp.reset_fault && !self.reset_fault_last;
         self.reset_fault_last = self.inp.reset_fault;
 
+        let fieldbus_fault = !self.inp.fieldbus_ok || !self.inp.station_running;
+        if fieldbus_fault && !self.fieldbus_fault {
+            log::warn!(
+                "Fieldbus communication lost (in ring: {}, station running: {})",
+                self.inp.fieldbus_ok,
+                self.inp.station_running
+            );
+        } else if !fieldbus_fault && self.fieldbus_fault {
+            log::info!("Fieldbus communication restored.");
+        }
+        self.fieldbus_fault = fieldbus_fault;
+        metrics::gauge!("crab_fieldbus_ok").set(f64::from(!self.fieldbus_fault));
+        metrics::describe_gauge!(
+            "crab_fieldbus_ok",
+            "Whether the fieldbus is online and all peripherals are exchanging data."
+        );
+
         // Disconnected pressure sensor
         let pressure_fault = self.inp.pressure_fullscale & 7 != 0;
-        self.pressure_mbar = if !pressure_fault {
+        // Don't evaluate stale values while the fieldbus is down
+        self.pressure_mbar = if !pressure_fault && !self.fieldbus_fault {
             Some(f64::from(self.inp.pressure_fullscale) * 250. / 65535.)
         } else {
             None
@@ -267,13 +464,13 @@ impl Logic {
         );
 
         if let Some(pressure_mbar) = self.pressure_mbar {
-            // HIGHHIGH triggers after 500ms over limit
+            // HIGHHIGH triggers after some time over limit
             let high_high_alarm = self
                 .t_highhigh_alarm
                 .run(
                     now,
                     pressure_mbar >= self.inp.pressure_limits.high_high,
-                    500.millis(),
+                    seconds(p.high_high_delay_s),
                 )
                 .done;
 
@@ -287,28 +484,55 @@ impl Logic {
         }
 
         // Maximum fan runtime
-        let fan_overtime = self.out.run_fan && self.t_fan.timer(now, 60.secs());
-
-        self.faulted = (self.faulted && !reset_fault_edge)
-            || pressure_fault
-            || fan_overtime
-            || self.pressure_high_high
-            || !self.inp.estop_ok
-            || !self.logic_initialized
-            || !self.inp.dc_ok;
+        let fan_overtime = self.out.run_fan && self.t_fan.timer(now, seconds(p.fan_max_runtime_s));
+
+        for (id, condition) in [
+            (AlarmId::Initializing, !self.logic_initialized),
+            (AlarmId::EmergencyStop, !self.inp.estop_ok),
+            (AlarmId::DcSupply, !self.inp.dc_ok),
+            (AlarmId::FieldbusOffline, self.fieldbus_fault),
+            (
+                AlarmId::FieldbusDiagnostic,
+                self.inp.fieldbus_diagnostic_fault,
+            ),
+            (AlarmId::PressureSensor, pressure_fault),
+            (AlarmId::PressureHighHigh, self.pressure_high_high),
+            (AlarmId::FanOvertime, fan_overtime),
+            (AlarmId::PressureLowLow, self.pressure_low_low),
+        ] {
+            self.alarms.update(now, id, condition);
+        }
+        if reset_fault_edge {
+            self.alarms.reset(now);
+        }
+        let faulted = self.alarms.faulted();
+        if faulted
+            && !self.faulted
+            && let Some(first_out) = self.alarms.first_out()
+        {
+            log::warn!("Crab faulted, first-out alarm: {first_out}");
+        }
+        self.faulted = faulted;
 
         metrics::gauge!("crab_faulted").set(f64::from(self.faulted));
         metrics::describe_gauge!(
             "crab_faulted",
             "Whether the crab is currently in a fault condition."
         );
+        metrics::describe_gauge!(
+            "crab_alarm_active",
+            "Whether each alarm is currently active."
+        );
 
         // Fan
-        let fan_cooldown = !self.out.run_fan && self.t_fan.timer(now, 10.secs());
-        let crab_deflated = !self.out.run_fan && self.t_fan.timer(now, (30 * 60).secs());
+        let fan_cooldown = !self.out.run_fan && self.t_fan.timer(now, seconds(p.fan_cooldown_s));
+        let crab_deflated =
+            !self.out.run_fan && self.t_fan.timer(now, seconds(p.deflation_interval_s));
         let start_fan =
             ((self.pressure_low && crab_deflated) || self.inp.trigger_fan) && fan_cooldown;
-        self.run_fan = (self.run_fan || start_fan) && !self.pressure_high;
+        // Without fieldbus we cannot observe the pressure, so the fan must not restart on its own
+        // once communication is back.
+        self.run_fan = (self.run_fan || start_fan) && !self.pressure_high && !self.fieldbus_fault;
         let new_run_fan = self.run_fan && !self.faulted;
 
         let crab_fan_starts_total = metrics::counter!("crab_fan_starts_total");
@@ -351,3 +575,398 @@ impl Logic {
         self.logic_initialized = true;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::harness::{CYCLE, Harness};
+
+    fn assert_about(actual: std::time::Duration, expected: std::time::Duration) {
+        assert!(
+            actual >= expected && actual <= expected + 2 * CYCLE,
+            "took {actual:?}, expected {expected:?}"
+        );
+    }
+
+    // The web mirror lives in crab-httpapi, which cannot see `Channels`
+    #[test]
+    fn mirror_layers() {
+        for layer in crab_httpapi::mirror::LAYERS {
+            for channel in layer.channels {
+                assert!(
+                    Channels::NAMES.contains(channel),
+                    "{} shows unknown channel {channel}",
+                    layer.name
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn blink_cycle() {
+        let mut h = Harness::new();
+        h.inputs().emotion = Some(Emotion::new("Neutral"));
+
+        // Synchronize to the start of a blink, one might already be in progress
+        h.run_until(10.secs(), |l| !l.out.channels.pupil_down)
+            .unwrap();
+        h.run_until(10.secs(), |l| l.out.channels.pupil_down)
+            .unwrap();
+        for _ in 0..3 {
+            assert!(!h.outputs().channels.pupil_top);
+            let closed = h.run_until(10.secs(), |l| !l.out.channels.pupil_down);
+            assert_about(closed.unwrap(), 300.millis());
+            assert!(h.outputs().channels.pupil_top);
+            let open = h.run_until(10.secs(), |l| l.out.channels.pupil_down);
+            assert_about(open.unwrap(), 3.secs());
+        }
+    }
+
+    #[test]
+    fn mouth_closing() {
+        let mut h = Harness::new();
+        h.inputs().emotion = Some(Emotion::new("Happy"));
+        h.step();
+        assert!(h.outputs().channels.mouth_bottom);
+
+        let closed = h.run_until(20.secs(), |l| !l.out.channels.mouth_bottom);
+        assert_about(closed.unwrap(), 10.secs());
+        assert!(h.outputs().channels.mouth_mid);
+        assert!(!h.outputs().channels.mouth_top);
+
+        let open = h.run_until(20.secs(), |l| l.out.channels.mouth_bottom);
+        assert_about(open.unwrap(), 2.secs());
+        let closed = h.run_until(20.secs(), |l| !l.out.channels.mouth_bottom);
+        assert_about(closed.unwrap(), 10.secs());
+
+        // A new emotion opens the mouth as soon as the relays allow
+        h.inputs().emotion = Some(Emotion::new("Surprised"));
+        h.step();
+        assert!(!h.outputs().channels.mouth_bottom);
+        let open = h.run_until(1.secs(), |l| l.out.channels.mouth_bottom);
+        assert_about(open.unwrap(), MOUTH_MIN_SWITCH - CYCLE);
+        assert!(h.outputs().channels.mouth_top);
+    }
+
+    #[test]
+    fn sleep_until_new_emotion() {
+        let mut h = Harness::new();
+        h.inputs().emotion = Some(Emotion::new("Happy"));
+        h.step();
+        h.trigger_sleep();
+        h.run_for(60.secs());
+        assert!(!h.outputs().channels.eyes);
+        assert!(h.outputs().channels.pupil_down);
+
+        h.inputs().emotion = Some(Emotion::new("Sad"));
+        h.step();
+        assert!(h.outputs().channels.eyes);
+    }
+
+    #[test]
+    fn talking() {
+        let mut h = Harness::new();
+        h.inputs().emotion = Some(Emotion::new("Neutral"));
+        h.run_for(20.secs());
+
+        h.inputs().emotion = Some(Emotion::new("Sad"));
+        h.inputs().talk_syllables = 3;
+        h.step();
+        h.inputs().talk_syllables = 0;
+        assert!(h.outputs().channels.mouth_top);
+        assert!(h.outputs().channels.mouth_bottom);
+
+        // Open and close once per syllable, 200 ms each
+        let mut changes = Vec::new();
+        let mut last = h.outputs().channels.clone();
+        let done = h.run_until(5.secs(), |l| {
+            let channels = &l.out.channels;
+            if (
+                channels.mouth_top,
+                channels.mouth_mid,
+                channels.mouth_bottom,
+            ) != (last.mouth_top, last.mouth_mid, last.mouth_bottom)
+            {
+                changes.push(channels.clone());
+                last = channels.clone();
+            }
+            l.talk_syllables == 0
+        });
+        assert_about(done.unwrap(), 1200.millis());
+        assert_eq!(changes.len(), 6, "{changes:#?}");
+        assert!(changes[0].mouth_mid && !changes[0].mouth_top && !changes[0].mouth_bottom);
+
+        // Then the emotion is shown with an open mouth
+        let channels = &h.outputs().channels;
+        assert!(channels.mouth_top && !channels.mouth_mid && !channels.mouth_bottom);
+        let closed = h.run_until(20.secs(), |l| !l.out.channels.mouth_top);
+        assert_about(closed.unwrap(), 10.secs() - CYCLE);
+    }
+
+    /// Step once, checking that no mouth channel switches again within `MOUTH_MIN_SWITCH`
+    ///
+    /// Returns whether the mouth changed its shape.
+    fn step_mouth(h: &mut Harness, last_change: &mut [std::time::Duration; 3]) -> bool {
+        let before = h.outputs().channels.clone();
+        h.step();
+        let channels = &h.outputs().channels;
+        let mut changed = false;
+        for (name, last_change) in ["mouth_top", "mouth_mid", "mouth_bottom"]
+            .into_iter()
+            .zip(last_change)
+        {
+            if channels.get(name) != before.get(name) {
+                let interval = h.elapsed() - *last_change;
+                assert!(interval >= MOUTH_MIN_SWITCH, "{name} after {interval:?}");
+                *last_change = h.elapsed();
+                changed = true;
+            }
+        }
+        changed
+    }
+
+    #[test]
+    fn talking_respects_relays() {
+        let mut h = Harness::new();
+        h.logic.set_parameters(LogicParameters {
+            talk_syllable_s: 0.01,
+            ..Default::default()
+        });
+        let mut last_change = [std::time::Duration::ZERO; 3];
+        h.inputs().emotion = Some(Emotion::new("Happy"));
+        h.inputs().talk_syllables = 10;
+        step_mouth(&mut h, &mut last_change);
+        h.inputs().talk_syllables = 0;
+
+        let mut changes = 0;
+        while h.logic.talk_syllables > 0 {
+            if step_mouth(&mut h, &mut last_change) {
+                changes += 1;
+            }
+        }
+        assert!(changes >= 19, "{changes}");
+    }
+
+    #[test]
+    fn talking_retriggered() {
+        let mut h = Harness::new();
+        let mut last_change = [std::time::Duration::ZERO; 3];
+        h.inputs().emotion = Some(Emotion::new("Sad"));
+        for _ in 0..400 {
+            step_mouth(&mut h, &mut last_change);
+        }
+
+        let talk = |h: &mut Harness, last_change: &mut _| {
+            h.inputs().talk_syllables = 3;
+            step_mouth(h, last_change);
+            h.inputs().talk_syllables = 0;
+        };
+        talk(&mut h, &mut last_change);
+        assert!(h.outputs().channels.mouth_top);
+        let opened = h.elapsed();
+        while h.outputs().channels.mouth_top {
+            step_mouth(&mut h, &mut last_change);
+        }
+        assert_about(h.elapsed() - opened, 200.millis());
+
+        // Starting over mid-syllable must not open the mouth before the relays allow it
+        talk(&mut h, &mut last_change);
+        assert!(!h.outputs().channels.mouth_top);
+        while !h.outputs().channels.mouth_top {
+            step_mouth(&mut h, &mut last_change);
+        }
+        assert_about(h.elapsed() - opened, 200.millis() + MOUTH_MIN_SWITCH);
+
+        // Neither does a new emotion right after
+        h.inputs().emotion = Some(Emotion::new("Surprised"));
+        for _ in 0..40 {
+            step_mouth(&mut h, &mut last_change);
+        }
+    }
+
+    #[test]
+    fn sequence_step() {
+        let mut h = Harness::new();
+        h.inputs().emotion = Some(Emotion::new("Happy"));
+        h.step();
+        h.trigger_sleep();
+        h.step();
+
+        // The step wakes the crab up with its emotion and overrides the blinking pupils
+        h.inputs().sequence_step = Some(crab_httpapi::sequences::SequenceStep {
+            emotion: Some(Emotion::new("Sad")),
+            channels: [
+                ("pupil_top".to_string(), false),
+                ("left_claw".to_string(), false),
+            ]
+            .into_iter()
+            .collect(),
+            duration_s: 1.,
+        });
+        h.step();
+        assert_eq!(h.logic.emotion(), Some(&Emotion::new("Sad")));
+        assert!(h.outputs().channels.eyes);
+        assert!(h.outputs().channels.mouth_top);
+        assert!(!h.outputs().channels.pupil_top);
+        assert!(!h.outputs().channels.left_claw);
+        assert!(h.outputs().channels.right_claw);
+
+        // Without an emotion in the step, the one of the emotion manager is shown
+        h.inputs().sequence_step.as_mut().unwrap().emotion = None;
+        h.step();
+        assert_eq!(h.logic.emotion(), Some(&Emotion::new("Happy")));
+        h.inputs().sequence_step = None;
+        h.step();
+        assert!(h.outputs().channels.left_claw);
+    }
+
+    #[test]
+    fn fan_cooldown() {
+        let mut h = Harness::new();
+        h.trigger_fan();
+        assert!(h.outputs().run_fan);
+        assert!(h.outputs().indicator_refill_air);
+
+        h.ramp_pressure(0.3, 0.5, 5.secs());
+        assert!(!h.outputs().run_fan, "fan did not stop at HIGH");
+        h.set_pressure(0.3);
+
+        h.run_for(5.secs());
+        h.trigger_fan();
+        assert!(!h.outputs().run_fan, "fan restarted during cooldown");
+
+        h.run_for(6.secs());
+        h.trigger_fan();
+        assert!(h.outputs().run_fan);
+    }
+
+    #[test]
+    fn fan_overtime() {
+        let mut h = Harness::new();
+        h.trigger_fan();
+
+        // Pressure never rises, the fan must give up after a minute
+        let stopped = h.run_until(120.secs(), |l| !l.out.run_fan);
+        assert_about(stopped.unwrap(), 60.secs());
+        assert!(h.outputs().indicator_fault);
+
+        h.run_for(60.secs());
+        assert!(h.outputs().indicator_fault, "fault is not latched");
+        h.reset_fault();
+        assert!(!h.outputs().indicator_fault);
+    }
+
+    #[test]
+    fn fan_restarts_after_deflation() {
+        let mut h = Harness::new();
+        h.trigger_fan();
+        h.set_pressure(0.5);
+        h.run_until(1.secs(), |l| !l.out.run_fan).unwrap();
+
+        // Below LOW, but the crab was inflated only recently
+        h.ramp_pressure(0.5, 0.15, 60.secs());
+        assert!(!h.outputs().run_fan);
+
+        let started = h.run_until((60 * 60).secs(), |l| l.out.run_fan);
+        assert_about(started.unwrap() + 60.secs(), (30 * 60).secs());
+    }
+
+    #[test]
+    fn high_high_latching() {
+        let mut h = Harness::new();
+
+        // Short peaks are tolerated
+        h.set_pressure(0.7);
+        h.run_for(400.millis());
+        h.set_pressure(0.3);
+        h.step();
+        assert!(!h.outputs().indicator_fault);
+
+        h.set_pressure(0.7);
+        let latched = h.run_until(2.secs(), |l| l.pressure_high_high);
+        assert_about(latched.unwrap(), 500.millis());
+        assert!(h.outputs().indicator_fault);
+
+        h.set_pressure(0.3);
+        h.run_for(10.secs());
+        assert!(h.logic.pressure_high_high, "HIGHHIGH is not latched");
+        assert!(h.outputs().indicator_fault);
+
+        h.reset_fault();
+        assert!(!h.logic.pressure_high_high);
+        assert!(!h.outputs().indicator_fault);
+    }
+
+    #[test]
+    fn custom_parameters() {
+        let mut h = Harness::new();
+        h.logic.set_parameters(LogicParameters {
+            fan_max_runtime_s: 5.,
+            high_high_delay_s: 2.,
+            ..Default::default()
+        });
+
+        h.trigger_fan();
+        let stopped = h.run_until(10.secs(), |l| !l.out.run_fan);
+        assert_about(stopped.unwrap(), 5.secs());
+        h.reset_fault();
+
+        h.set_pressure(0.7);
+        let latched = h.run_until(5.secs(), |l| l.pressure_high_high);
+        assert_about(latched.unwrap(), 2.secs());
+    }
+
+    #[test]
+    fn alarm_reasons() {
+        use crate::alarms::AlarmId;
+
+        let mut h = Harness::new();
+        assert_eq!(h.logic.alarms().active().count(), 0);
+
+        h.inputs().estop_ok = false;
+        h.step();
+        h.inputs().dc_ok = false;
+        h.step();
+        assert!(h.outputs().indicator_fault);
+        assert_eq!(h.logic.alarms().first_out(), Some(AlarmId::EmergencyStop));
+        assert!(h.logic.alarms().is_active(AlarmId::DcSupply));
+
+        // Only the alarm whose condition went away is cleared by a reset
+        h.inputs().estop_ok = true;
+        h.step();
+        h.reset_fault();
+        let active: Vec<_> = h.logic.alarms().active().collect();
+        assert_eq!(active, [AlarmId::DcSupply]);
+        assert!(h.outputs().indicator_fault);
+
+        h.inputs().dc_ok = true;
+        h.step();
+        h.reset_fault();
+        assert!(!h.outputs().indicator_fault);
+        assert_eq!(h.logic.alarms().first_out(), None);
+    }
+
+    #[test]
+    fn status() {
+        let mut h = Harness::new();
+        h.inputs().emotion = Some(Emotion::new("Surprised"));
+        h.set_pressure(0.3);
+        h.run_for(std::time::Duration::from_secs(1));
+
+        let status = h.logic.status();
+        assert_eq!(status.emotion, Some(Emotion::new("Surprised")));
+        assert!(!status.faulted);
+        assert!(status.active_alarms.is_empty());
+        let pressure = status.pressure.pressure_mbar.unwrap();
+        assert!((pressure - 0.3).abs() < 0.05, "{pressure}");
+        assert!(!status.pressure.low && !status.pressure.high);
+        assert_eq!(status.channels.len(), Channels::NAMES.len());
+        assert_eq!(status.channels["eyes"], h.outputs().channels.eyes);
+
+        h.inputs().estop_ok = false;
+        h.step();
+        let status = h.logic.status();
+        assert!(status.faulted);
+        assert_eq!(status.active_alarms, ["emergency_stop"]);
+    }
+}
@@ -1,42 +1,199 @@
 use crab_httpapi::emotionmanager;
 use emotionmanager::EmotionCommand;
 
+mod alarms;
+mod config;
+#[cfg_attr(not(feature = "fieldbus"), allow(dead_code))]
+mod dpdiag;
+mod emotions;
 #[cfg(feature = "fieldbus")]
 mod fieldbus;
 #[cfg(feature = "graphql")]
 mod graphql;
+#[cfg(test)]
+mod harness;
+#[cfg_attr(not(feature = "graphql"), allow(dead_code))]
+mod history;
+#[cfg_attr(not(feature = "fieldbus"), allow(dead_code))]
+mod iomap;
 mod logic;
+mod plant;
+mod recording;
+mod sequences;
+mod settings;
+#[cfg(feature = "simulator")]
+mod simulator;
 mod timers;
 #[cfg(feature = "visuals")]
 mod visuals;
 
 fn main() {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info"))
+        .format_timestamp_micros()
+        .init();
+
+    if std::env::args().nth(1).as_deref() == Some("--hash-token") {
+        hash_token();
+        return;
+    }
+
+    let config = match config::Config::load() {
+        Ok(config) => config,
+        Err(e) => {
+            log::error!("{e}");
+            std::process::exit(1);
+        }
+    };
+
+    let emotions = match emotions::Emotions::load(&config.emotion.patterns) {
+        Ok(emotions) => std::sync::Arc::new(emotions),
+        Err(e) => {
+            log::error!("{e}");
+            std::process::exit(1);
+        }
+    };
+    if !emotions.contains(&config.emotion.idle) {
+        log::error!("The idle emotion {} is not defined.", config.emotion.idle);
+        std::process::exit(1);
+    }
+    if let Some(emotion) = config.sentiment.emotions().find(|e| !emotions.contains(e)) {
+        log::error!("The sentiment emotion {emotion} is not defined.");
+        std::process::exit(1);
+    }
+
+    if let Some(path) = &config.recording.replay {
+        match recording::replay(path, config.recording.replay_speed, emotions.clone()) {
+            Ok(summary) => {
+                log::info!(
+                    "Replayed {} cycles, outputs differ in {}.",
+                    summary.frames,
+                    summary.mismatches
+                );
+                std::process::exit(if summary.mismatches == 0 { 0 } else { 2 });
+            }
+            Err(e) => {
+                log::error!("{e}");
+                std::process::exit(1);
+            }
+        }
+    }
+
+    let mut recorder =
+        config
+            .recording
+            .record
+            .as_ref()
+            .map(|path| match recording::Recorder::create(path) {
+                Ok(recorder) => {
+                    log::info!("Recording to {}.", path.display());
+                    recorder
+                }
+                Err(e) => {
+                    log::error!("{e}");
+                    std::process::exit(1);
+                }
+            });
+
+    let mut settings = match settings::SettingsStore::load(&config.settings.path) {
+        Ok(settings) => settings,
+        Err(e) => {
+            log::error!("{e}");
+            std::process::exit(1);
+        }
+    };
+    let history: history::SharedHistory = match history::History::open(config.history.clone()) {
+        Ok(history) => std::sync::Arc::new(std::sync::Mutex::new(history)),
+        Err(e) => {
+            log::error!("{e}");
+            std::process::exit(1);
+        }
+    };
+
+    let mut sequences = match sequences::Sequences::open(&config.sequences, emotions.clone()) {
+        Ok(sequences) => sequences,
+        Err(e) => {
+            log::error!("{e}");
+            std::process::exit(1);
+        }
+    };
+
     let (emotion_tx, emotion_rx) = tokio::sync::mpsc::channel::<EmotionCommand>(32);
     let (pressure_limits_tx, mut pressure_limits_rx) = tokio::sync::mpsc::channel(8);
+    let (pressure_limits_watch, pressure_limits) =
+        tokio::sync::watch::channel(settings.settings().pressure_limits.clone());
+    let (logic_parameters_tx, mut logic_parameters_rx) = tokio::sync::mpsc::channel(8);
+    let (logic_parameters_watch, logic_parameters) =
+        tokio::sync::watch::channel(settings.settings().logic.clone());
+    let (alarm_ack_tx, mut alarm_ack_rx) = tokio::sync::mpsc::channel(8);
+    let (plant_tx, mut plant_rx) = tokio::sync::mpsc::channel(8);
+    let (sequence_tx, mut sequence_rx) = tokio::sync::mpsc::channel(8);
+    let (alarms_watch, alarms) = tokio::sync::watch::channel(Default::default());
+    let (status_watch, status) = tokio::sync::watch::channel(Default::default());
+    let events = std::sync::Arc::new(crab_httpapi::events::EventBus::new());
 
-    let emotioncontainer = emotionmanager::EmotionContainer::new();
-    let emotionmanager = emotionmanager::EmotionManager::new(emotioncontainer.clone(), emotion_rx);
+    let emotioncontainer = emotionmanager::EmotionContainer::new(config.emotion.idle.clone());
+    let emotionmanager = emotionmanager::EmotionManager::new(
+        emotioncontainer.clone(),
+        config.emotion.clone(),
+        emotions.names().cloned().collect(),
+        emotion_rx,
+    );
 
     let trigger_fan = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
     let trigger_sleep = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
     let fault_reset = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+    let talk = std::sync::Arc::new(std::sync::atomic::AtomicI32::new(0));
+
+    let audit = match crab_httpapi::audit::AuditLog::open(&config.audit) {
+        Ok(audit) => std::sync::Arc::new(audit),
+        Err(e) => {
+            log::error!("{e}");
+            std::process::exit(1);
+        }
+    };
+
+    if config.auth.users.is_empty() {
+        log::warn!(
+            "No API users configured, everything beyond role {} is locked.",
+            config.auth.anonymous_role
+        );
+    }
 
     let app_state = crab_httpapi::AppState {
+        auth: std::sync::Arc::new(crab_httpapi::auth::Auth::new(&config.auth)),
+        audit,
         emotion_ch_tx: emotion_tx.clone(),
+        emotions: emotions.names().cloned().collect(),
+        sentiment: crab_httpapi::sentiment::analyzer(
+            &config.sentiment,
+            emotions.names().cloned().collect(),
+        ),
         fault_reset: fault_reset.clone(),
         trigger_fan: trigger_fan.clone(),
         trigger_sleep: trigger_sleep.clone(),
+        talk: talk.clone(),
+        pressure_limits,
         pressure_limits_tx,
+        logic_parameters,
+        logic_parameters_tx,
+        plant_tx: config.plant.enabled.then_some(plant_tx),
+        status,
+        events: events.clone(),
+        alarms,
+        alarm_ack_tx,
+        sequence_tx,
     };
 
     #[cfg(feature = "graphql")]
-    let graphql_context = graphql::Context::default();
+    let graphql_context = graphql::Context::new(app_state.clone(), history.clone());
 
     #[cfg(feature = "graphql")]
     std::thread::spawn({
         let graphql_context = graphql_context.clone();
+        let history = history.clone();
         move || {
-            let graphql_router = graphql::axum_router(graphql_context);
+            let graphql_router =
+                graphql::axum_router(graphql_context).merge(history::axum_router(history));
             crab_httpapi::run_http_server(app_state, emotionmanager, Some(graphql_router));
         }
     });
@@ -47,23 +204,36 @@ fn main() {
         }
     });
 
-    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info"))
-        .format_timestamp_micros()
-        .init();
+    let mut plant = config.plant.enabled.then(|| {
+        log::info!("Simulating the crab with the plant model.");
+        plant::Plant::new(config.plant.clone())
+    });
 
     #[cfg(feature = "fieldbus")]
     let mut fieldbus = if std::env::var("FAKE_CRAB")
         .map(|v| v.parse::<bool>().unwrap())
         .unwrap_or_default()
+        || plant.is_some()
     {
-        // Fake crab gets no fieldbus
+        // Fake crab gets no fieldbus, the plant model replaces it
         None
     } else {
-        Some(fieldbus::Fieldbus::new())
+        Some(fieldbus::Fieldbus::new(config.fieldbus.clone()))
+    };
+    #[cfg(feature = "fieldbus")]
+    let iomap = match iomap::IoMap::load(&config.iomap.path) {
+        Ok(iomap) => iomap,
+        Err(e) => {
+            log::error!("{e}");
+            std::process::exit(1);
+        }
     };
     #[cfg(feature = "visuals")]
     let visuals = visuals::Visuals::new();
     let mut logic = logic::Logic::new();
+    logic.set_emotions(emotions.clone());
+    logic.inputs_mut().pressure_limits = settings.settings().pressure_limits.clone();
+    logic.set_parameters(settings.settings().logic.clone());
 
     #[cfg(feature = "fieldbus")]
     if let Some(fieldbus) = &mut fieldbus {
@@ -74,8 +244,11 @@ fn main() {
         #[cfg(feature = "visuals")]
         let visuals = visuals.clone();
         move || {
+            let mut alarms_revision = None;
             loop {
                 let start = std::time::Instant::now();
+                #[allow(unused_mut)]
+                let mut process_images: Option<(Vec<u8>, Vec<u8>)> = None;
 
                 #[cfg(feature = "fieldbus")]
                 if let Some(fieldbus) = &mut fieldbus {
@@ -83,58 +256,29 @@ fn main() {
                     let mut graphql_context = graphql_context.inner.blocking_write();
 
                     fieldbus.with_process_images(|pii, piq| {
-                        use process_image::{tag, tag_mut};
-
-                        // -KEC1-K1 DO1
-                        *tag_mut!(piq, X, 0, 0) = logic.outputs().channels.bottom_front;
-                        // -KEC1-K1 DO2
-                        *tag_mut!(piq, X, 0, 1) = logic.outputs().channels.bottom_back;
-                        // -KEC1-K1 DO3
-                        *tag_mut!(piq, X, 0, 2) = logic.outputs().channels.pupil_down;
-                        // -KEC1-K1 DO4
-                        *tag_mut!(piq, X, 0, 3) = logic.outputs().channels.pupil_top;
-
-                        // -KEC1-K2 DO1
-                        *tag_mut!(piq, X, 1, 0) = logic.outputs().channels.eyes;
-                        // -KEC1-K2 DO2
-                        *tag_mut!(piq, X, 1, 1) = logic.outputs().channels.mouth_mid;
-                        // -KEC1-K2 DO3
-                        *tag_mut!(piq, X, 1, 2) = logic.outputs().channels.mouth_bottom;
-                        // -KEC1-K2 DO4
-                        *tag_mut!(piq, X, 1, 3) = logic.outputs().channels.mouth_top;
-
-                        // -KEC1-K3 DO1
-                        *tag_mut!(piq, X, 2, 0) = logic.outputs().channels.spikes_left;
-                        // -KEC1-K3 DO2
-                        *tag_mut!(piq, X, 2, 1) = logic.outputs().channels.spikes_mid;
-                        // -KEC1-K3 DO3
-                        *tag_mut!(piq, X, 2, 2) = logic.outputs().channels.spikes_right;
-
-                        // -KEC1-K4 DO1
-                        *tag_mut!(piq, X, 3, 0) = logic.outputs().channels.right_claw;
-                        // -KEC1-K4 DO2
-                        *tag_mut!(piq, X, 3, 1) = logic.outputs().channels.left_claw;
-
-                        // -KEC1-K5 DO1 (inverted!)
-                        *tag_mut!(piq, X, 4, 0) = !logic.outputs().indicator_fault;
-                        // -KEC1-K5 DO2
-                        *tag_mut!(piq, X, 4, 1) = logic.outputs().indicator_refill_air;
-                        // -KEC1-K5 DO3
-                        *tag_mut!(piq, X, 4, 2) = logic.outputs().run_fan;
-
-                        // -KEC1-K6 DI1
-                        logic.inputs_mut().dc_ok = tag!(pii, X, 1, 0);
-                        // -KEC1-K6 DI2
-                        logic.inputs_mut().estop_ok = tag!(pii, X, 1, 1);
-
-                        // -KEC1-K7 AI1
-                        logic.inputs_mut().pressure_fullscale = tag!(pii, W, 2).into();
+                        iomap.write_outputs(logic.outputs(), piq);
+                        iomap.read_inputs(pii, logic.inputs_mut());
 
                         #[cfg(feature = "graphql")]
                         graphql_context.pii.copy_from_slice(pii);
                         #[cfg(feature = "graphql")]
                         graphql_context.piq.copy_from_slice(piq);
+
+                        if recorder.is_some() {
+                            process_images = Some((pii.to_vec(), piq.to_vec()));
+                        }
                     });
+
+                    logic.inputs_mut().fieldbus_ok = fieldbus.is_online();
+                    logic.inputs_mut().station_running = fieldbus.peripherals_running();
+
+                    let diagnostics = fieldbus.diagnostics();
+                    logic.inputs_mut().fieldbus_diagnostic_fault =
+                        diagnostics.iter().any(|d| d.is_fault());
+                    #[cfg(feature = "graphql")]
+                    {
+                        graphql_context.fieldbus_diagnostics = diagnostics;
+                    }
                 }
                 #[cfg(not(feature = "fieldbus"))]
                 {
@@ -142,6 +286,15 @@ fn main() {
                     logic.inputs_mut().dc_ok = true;
                     logic.inputs_mut().estop_ok = false;
                     logic.inputs_mut().pressure_fullscale = 64;
+                    logic.inputs_mut().fieldbus_ok = true;
+                    logic.inputs_mut().station_running = true;
+                }
+                if let Some(plant) = &mut plant {
+                    while let Ok(update) = plant_rx.try_recv() {
+                        plant.apply(&update);
+                    }
+                    plant.update(std::time::Instant::now(), logic.outputs());
+                    plant.write_inputs(logic.inputs_mut());
                 }
 
                 #[cfg(feature = "visuals")]
@@ -157,30 +310,94 @@ fn main() {
                         trigger_sleep.swap(false, std::sync::atomic::Ordering::SeqCst);
                     inputs.reset_fault =
                         fault_reset.swap(false, std::sync::atomic::Ordering::SeqCst);
+                    inputs.talk_syllables = talk.swap(0, std::sync::atomic::Ordering::SeqCst);
 
                     if let Ok(limits) = pressure_limits_rx.try_recv() {
-                        if let Some(low_low) = limits.low_low {
-                            log::info!("Updating LOWLOW pressure limit to {low_low:.3} mbar");
-                            inputs.pressure_limits.low_low = low_low;
+                        let new_limits = limits.apply(&inputs.pressure_limits);
+                        if let Err(e) = new_limits.validate() {
+                            log::warn!("Rejecting pressure limits: {e}");
+                        } else {
+                            log::info!(
+                                "Updating pressure limits to LOWLOW {:.3}, LOW {:.3}, HIGH {:.3}, HIGHHIGH {:.3} mbar",
+                                new_limits.low_low,
+                                new_limits.low,
+                                new_limits.high,
+                                new_limits.high_high
+                            );
+                            inputs.pressure_limits = new_limits.clone();
+                            pressure_limits_watch.send_replace(new_limits.clone());
+                            if let Err(e) = settings.update(|s| s.pressure_limits = new_limits) {
+                                log::error!("Failed to save the pressure limits: {e}");
+                            }
                         }
-                        if let Some(low) = limits.low {
-                            log::info!("Updating LOW pressure limit to {low:.3} mbar");
-                            inputs.pressure_limits.low = low;
-                        }
-                        if let Some(high) = limits.high {
-                            log::info!("Updating HIGH pressure limit to {high:.3} mbar");
-                            inputs.pressure_limits.high = high;
-                        }
-                        if let Some(high_high) = limits.high_high {
-                            log::info!("Updating HIGHHIGH pressure limit to {high_high:.3} mbar");
-                            inputs.pressure_limits.high_high = high_high;
+                    }
+                }
+
+                if let Ok(update) = logic_parameters_rx.try_recv() {
+                    let parameters = update.apply(logic.parameters());
+                    if let Err(e) = parameters.validate() {
+                        log::warn!("Rejecting logic parameters: {e}");
+                    } else {
+                        log::info!("Updating logic parameters to {parameters:?}");
+                        logic.set_parameters(parameters.clone());
+                        logic_parameters_watch.send_replace(parameters.clone());
+                        if let Err(e) = settings.update(|s| s.logic = parameters) {
+                            log::error!("Failed to save the logic parameters: {e}");
                         }
                     }
                 }
 
                 let now = std::time::Instant::now();
+                while let Ok(command) = sequence_rx.try_recv() {
+                    sequences.handle(now, command);
+                }
+                logic.inputs_mut().sequence_step = sequences.step(now).cloned();
                 logic.run(now);
 
+                history
+                    .lock()
+                    .unwrap()
+                    .record(std::time::SystemTime::now(), &logic);
+
+                while let Ok(ack) = alarm_ack_rx.try_recv() {
+                    let id = match ack.id.as_deref() {
+                        Some(name) => match alarms::AlarmId::from_name(name) {
+                            Some(id) => Some(id),
+                            None => {
+                                log::warn!("Cannot acknowledge unknown alarm {name:?}");
+                                continue;
+                            }
+                        },
+                        None => None,
+                    };
+                    logic.acknowledge_alarms(now, id);
+                }
+                if alarms_revision != Some(logic.alarms().revision()) {
+                    alarms_revision = Some(logic.alarms().revision());
+                    let wall_clock = std::time::SystemTime::now();
+                    let snapshot = logic.alarms().snapshot(now, wall_clock);
+                    events.update_alarms(wall_clock, &snapshot);
+                    alarms_watch.send_replace(snapshot);
+                }
+
+                let status = logic.status();
+                events.update_status(now, &status);
+                status_watch.send_if_modified(|current| {
+                    let changed = *current != status;
+                    if changed {
+                        *current = status;
+                    }
+                    changed
+                });
+
+                if let Some(rec) = &mut recorder {
+                    let images = process_images.as_ref().map(|(i, q)| (&i[..], &q[..]));
+                    if let Err(e) = rec.record(now, &logic, images) {
+                        log::error!("Stopping the recording: {e}");
+                        recorder = None;
+                    }
+                }
+
                 // Mirror the logic state into the graphql context so it can be queried remotely.
                 #[cfg(feature = "graphql")]
                 {
@@ -189,6 +406,8 @@ fn main() {
                     graphql_context.logic_image.clone_from(&logic);
                     graphql_context.now = now;
                 }
+                #[cfg(feature = "graphql")]
+                graphql_context.publish(now, &logic);
 
                 std::thread::sleep(std::time::Duration::from_millis(50));
 
@@ -203,3 +422,20 @@ fn main() {
     #[cfg(not(feature = "visuals"))]
     _main_loop_handle.join().unwrap();
 }
+
+/// Print the hash of a token read from standard input, for the `[auth]` configuration
+fn hash_token() {
+    let mut token = String::new();
+    if let Err(e) = std::io::stdin().read_line(&mut token) {
+        log::error!("Failed reading the token: {e}");
+        std::process::exit(1);
+    }
+    let token = token.trim_end_matches(['\r', '\n']);
+    if token.is_empty() {
+        log::error!("The token must not be empty.");
+        std::process::exit(1);
+    }
+
+    let hash = crab_httpapi::auth::TokenHash::new(token, crab_httpapi::auth::DEFAULT_ITERATIONS);
+    println!("{hash}");
+}
@@ -0,0 +1,292 @@
+//! Model of the physical crab for closed-loop simulation
+//!
+//! The crab is modelled as a fixed volume of air at (nearly) ambient pressure.  The fan pushes
+//! air in with a flow that drops linearly towards its maximum static pressure, while air leaks
+//! out proportionally to the overpressure.  The resulting pressure is quantized like the value
+//! reported by the 750-466 analog input for the 4-20 mA pressure sensor.
+
+use crate::logic::{LogicInputs, LogicOutputs};
+pub use crab_httpapi::plant::PlantUpdate;
+
+/// Ambient pressure used for the isothermal volume/pressure conversion
+const AMBIENT_PRESSURE_MBAR: f64 = 1013.25;
+
+/// Full scale of the pressure sensor, matching the conversion in the logic
+const SENSOR_FULLSCALE_MBAR: f64 = 250.;
+
+/// Longest time step for the numeric integration
+const MAX_STEP: std::time::Duration = std::time::Duration::from_millis(10);
+
+/// Parameters of the plant model, loaded from the `[plant]` section of the configuration
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PlantConfig {
+    /// Drive the logic from the plant model instead of the real crab
+    pub enabled: bool,
+    /// Volume of air in the inflated crab in liters
+    pub volume_l: f64,
+    /// Fan flow at zero overpressure in liters per second
+    pub fan_flow_lps: f64,
+    /// Overpressure at which the fan no longer delivers any air
+    pub fan_max_pressure_mbar: f64,
+    /// Leakage in liters per second per millibar of overpressure
+    pub leak_lps_per_mbar: f64,
+    pub initial_pressure_mbar: f64,
+    pub dc_ok: bool,
+    pub estop_ok: bool,
+}
+
+impl Default for PlantConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            volume_l: 2000.,
+            fan_flow_lps: 1.,
+            fan_max_pressure_mbar: 2.,
+            // Deflates from HIGH to LOW in a little less than half an hour
+            leak_lps_per_mbar: 0.001,
+            initial_pressure_mbar: 0.,
+            dc_ok: true,
+            estop_ok: true,
+        }
+    }
+}
+
+impl PlantConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if !self.volume_l.is_finite() || self.volume_l <= 0. {
+            return Err(format!("plant volume {} l must be positive", self.volume_l));
+        }
+        if !self.fan_max_pressure_mbar.is_finite() || self.fan_max_pressure_mbar <= 0. {
+            return Err(format!(
+                "fan maximum pressure {} mbar must be positive",
+                self.fan_max_pressure_mbar
+            ));
+        }
+        if !self.fan_flow_lps.is_finite()
+            || self.fan_flow_lps < 0.
+            || !self.leak_lps_per_mbar.is_finite()
+            || self.leak_lps_per_mbar < 0.
+        {
+            return Err("fan flow and leak rate must not be negative".to_string());
+        }
+        if !(0. ..=SENSOR_FULLSCALE_MBAR).contains(&self.initial_pressure_mbar) {
+            return Err(format!(
+                "initial pressure {} mbar is outside the sensor range",
+                self.initial_pressure_mbar
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Plant {
+    config: PlantConfig,
+    pressure_mbar: f64,
+    last_update: Option<std::time::Instant>,
+
+    pub dc_ok: bool,
+    pub estop_ok: bool,
+    /// Simulate a disconnected pressure sensor
+    pub sensor_fault: bool,
+}
+
+impl Plant {
+    pub fn new(config: PlantConfig) -> Self {
+        Self {
+            pressure_mbar: config.initial_pressure_mbar,
+            last_update: None,
+            dc_ok: config.dc_ok,
+            estop_ok: config.estop_ok,
+            sensor_fault: false,
+            config,
+        }
+    }
+
+    #[cfg(test)]
+    pub fn pressure_mbar(&self) -> f64 {
+        self.pressure_mbar
+    }
+
+    pub fn set_leak_rate(&mut self, leak_lps_per_mbar: f64) {
+        log::info!("Plant: Changing leak rate to {leak_lps_per_mbar} l/s/mbar");
+        self.config.leak_lps_per_mbar = leak_lps_per_mbar.max(0.);
+    }
+
+    /// Change the model while it is running, e.g. to rehearse a power failure
+    pub fn apply(&mut self, update: &PlantUpdate) {
+        if let Some(leak_lps_per_mbar) = update.leak_lps_per_mbar {
+            self.set_leak_rate(leak_lps_per_mbar);
+        }
+        for (name, value, field) in [
+            ("DC OK", update.dc_ok, &mut self.dc_ok),
+            ("E-Stop OK", update.estop_ok, &mut self.estop_ok),
+            ("Sensor fault", update.sensor_fault, &mut self.sensor_fault),
+        ] {
+            if let Some(value) = value {
+                log::info!("Plant: Setting {name} to {value}");
+                *field = value;
+            }
+        }
+    }
+
+    /// Rate of pressure change in mbar/s
+    fn pressure_rate(&self, fan_running: bool) -> f64 {
+        let fan_flow = if fan_running {
+            self.config.fan_flow_lps
+                * (1. - self.pressure_mbar / self.config.fan_max_pressure_mbar).max(0.)
+        } else {
+            0.
+        };
+        let leak_flow = self.config.leak_lps_per_mbar * self.pressure_mbar;
+
+        (fan_flow - leak_flow) * AMBIENT_PRESSURE_MBAR / self.config.volume_l
+    }
+
+    /// Pressure as reported by the analog input module
+    ///
+    /// The lowest three bits of the word carry status information, any of them being set marks
+    /// the value as invalid.
+    fn sensor_value(&self) -> i32 {
+        if self.sensor_fault {
+            return 0x0001;
+        }
+        let fullscale = (self.pressure_mbar / SENSOR_FULLSCALE_MBAR * 65535.).round() as i32;
+        fullscale.clamp(0, 0xffff) & !7
+    }
+
+    /// Advance the model to `now` based on the outputs of the logic
+    pub fn update(&mut self, now: std::time::Instant, outputs: &LogicOutputs) {
+        let mut elapsed = self
+            .last_update
+            .map(|last| now.saturating_duration_since(last))
+            .unwrap_or_default();
+        self.last_update = Some(now);
+
+        while !elapsed.is_zero() {
+            let step = elapsed.min(MAX_STEP);
+            elapsed -= step;
+            self.pressure_mbar = (self.pressure_mbar
+                + self.pressure_rate(outputs.run_fan) * step.as_secs_f64())
+            .max(0.);
+        }
+
+        metrics::gauge!("crab_plant_pressure_mbar").set(self.pressure_mbar);
+        metrics::describe_gauge!(
+            "crab_plant_pressure_mbar",
+            "Pressure of the simulated crab in millibar."
+        );
+    }
+
+    /// Provide the sensor values of the model to the logic
+    pub fn write_inputs(&self, inputs: &mut LogicInputs) {
+        inputs.pressure_fullscale = self.sensor_value();
+        inputs.dc_ok = self.dc_ok;
+        inputs.estop_ok = self.estop_ok;
+        inputs.fieldbus_ok = true;
+        inputs.station_running = true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn inflate_and_leak() {
+        let mut plant = Plant::new(PlantConfig::default());
+        let mut outputs = LogicOutputs::default();
+        let start = std::time::Instant::now();
+
+        outputs.run_fan = true;
+        plant.update(start, &outputs);
+        plant.update(start + std::time::Duration::from_secs(1), &outputs);
+        assert!((0.4..0.6).contains(&plant.pressure_mbar()));
+
+        // The fan cannot exceed its maximum pressure, no matter how long it runs
+        plant.update(start + std::time::Duration::from_secs(600), &outputs);
+        assert!(plant.pressure_mbar() < 2.);
+        assert!(plant.pressure_mbar() > 1.9);
+
+        // Leakage is exponential with a time constant of V / (p_amb * k)
+        let p0 = plant.pressure_mbar();
+        let tau = 2000. / (AMBIENT_PRESSURE_MBAR * 0.001);
+        outputs.run_fan = false;
+        plant.update(
+            start + std::time::Duration::from_secs(600) + std::time::Duration::from_secs_f64(tau),
+            &outputs,
+        );
+        let expected = p0 / std::f64::consts::E;
+        assert!((plant.pressure_mbar() - expected).abs() < 0.01);
+    }
+
+    #[test]
+    fn sensor_quantization() {
+        let mut plant = Plant::new(PlantConfig {
+            initial_pressure_mbar: 0.5,
+            ..Default::default()
+        });
+        let mut inputs = LogicInputs::default();
+        plant.write_inputs(&mut inputs);
+
+        assert_eq!(inputs.pressure_fullscale & 7, 0);
+        let mbar = f64::from(inputs.pressure_fullscale) * 250. / 65535.;
+        assert!((mbar - 0.5).abs() < 8. * 250. / 65535.);
+
+        plant.sensor_fault = true;
+        plant.write_inputs(&mut inputs);
+        assert_ne!(inputs.pressure_fullscale & 7, 0);
+    }
+
+    #[test]
+    fn rehearse_scenarios() {
+        let mut plant = Plant::new(PlantConfig {
+            initial_pressure_mbar: 0.5,
+            ..Default::default()
+        });
+        let outputs = LogicOutputs::default();
+        let start = std::time::Instant::now();
+        plant.update(start, &outputs);
+
+        plant.apply(&PlantUpdate {
+            leak_lps_per_mbar: Some(0.01),
+            dc_ok: Some(false),
+            ..Default::default()
+        });
+        let mut inputs = LogicInputs::default();
+        plant.write_inputs(&mut inputs);
+        assert!(!inputs.dc_ok);
+        assert!(inputs.estop_ok);
+
+        // Ten times the default leak rate deflates ten times as fast
+        let tau = 2000. / (AMBIENT_PRESSURE_MBAR * 0.01);
+        plant.update(start + std::time::Duration::from_secs_f64(tau), &outputs);
+        assert!((plant.pressure_mbar() - 0.5 / std::f64::consts::E).abs() < 0.01);
+    }
+
+    /// The logic must keep the simulated crab inflated on its own
+    #[test]
+    fn closed_loop() {
+        let mut h = crate::harness::Harness::with_plant(Plant::new(PlantConfig::default()));
+
+        let mut fan_starts = 0;
+        let mut fan_running = false;
+        let mut max_pressure: f64 = 0.;
+        while h.elapsed() < std::time::Duration::from_secs(45 * 60) {
+            h.step();
+
+            if h.outputs().run_fan && !fan_running {
+                fan_starts += 1;
+            }
+            fan_running = h.outputs().run_fan;
+            max_pressure = max_pressure.max(h.plant.as_ref().unwrap().pressure_mbar());
+        }
+
+        let limits = crate::logic::PressureLimits::default();
+        assert_eq!(fan_starts, 2, "fan did not restart after deflating");
+        assert!(max_pressure >= limits.high);
+        assert!(max_pressure < limits.high_high);
+        assert!(!h.outputs().indicator_fault);
+    }
+}
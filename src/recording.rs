@@ -0,0 +1,380 @@
+//! Recording and replay of the logic's inputs and outputs
+//!
+//! A recording starts with a short header followed by one frame per cycle of the main loop,
+//! encoded with bincode.  Each frame holds the time since the start of the recording, the
+//! `LogicInputs` fed into `Logic::run` and the resulting `LogicOutputs`.  The logic parameters
+//! and raw process images are only stored when they changed since the previous frame, which
+//! keeps the file small.
+//!
+//! Replaying feeds the recorded inputs into a fresh `Logic` on a virtual clock and compares the
+//! outputs against the recorded ones, so a change in behavior shows up as a difference.
+
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::time::{Duration, Instant};
+
+use bincode::Options;
+
+use crate::logic::{Channels, Logic, LogicInputs, LogicOutputs, LogicParameters};
+
+const MAGIC: &[u8; 8] = b"CRABREC\0";
+const VERSION: u32 = 4;
+
+/// Upper bound for the size of a single frame, to fail early on corrupt files
+const MAX_FRAME_SIZE: u64 = 64 * 1024;
+
+/// Settings for recording and replay, taken from the command line or environment
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RecordingConfig {
+    /// Record all cycles of the main loop to this file
+    pub record: Option<std::path::PathBuf>,
+    /// Replay this recording instead of running the crab
+    pub replay: Option<std::path::PathBuf>,
+    /// Replay speed relative to the original timing, 0 to replay as fast as possible
+    pub replay_speed: f64,
+}
+
+impl Default for RecordingConfig {
+    fn default() -> Self {
+        Self {
+            record: None,
+            replay: None,
+            replay_speed: 0.,
+        }
+    }
+}
+
+impl RecordingConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if !self.replay_speed.is_finite() || self.replay_speed < 0. {
+            return Err(format!("invalid replay speed {}", self.replay_speed));
+        }
+        if self.record.is_some() && self.replay.is_some() {
+            return Err("cannot record and replay at the same time".to_string());
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub enum RecordingError {
+    Io(std::path::PathBuf, std::io::Error),
+    Encoding(bincode::Error),
+    BadHeader(std::path::PathBuf),
+}
+
+impl std::fmt::Display for RecordingError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RecordingError::Io(path, e) => write!(f, "failed accessing {}: {e}", path.display()),
+            RecordingError::Encoding(e) => write!(f, "failed encoding recording: {e}"),
+            RecordingError::BadHeader(path) => {
+                write!(f, "{} is not a recording of this version", path.display())
+            }
+        }
+    }
+}
+
+impl std::error::Error for RecordingError {}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Frame {
+    /// Time since the start of the recording in microseconds
+    pub time_us: u64,
+    pub inputs: LogicInputs,
+    pub outputs: LogicOutputs,
+    /// Logic parameters, if they changed since the previous frame
+    pub parameters: Option<LogicParameters>,
+    /// Raw input process image, if it changed since the previous frame
+    pub pii: Option<Vec<u8>>,
+    /// Raw output process image, if it changed since the previous frame
+    pub piq: Option<Vec<u8>>,
+}
+
+fn encoding() -> impl bincode::Options {
+    bincode::DefaultOptions::new().with_limit(MAX_FRAME_SIZE)
+}
+
+pub struct Recorder {
+    path: std::path::PathBuf,
+    writer: BufWriter<std::fs::File>,
+    start: Option<Instant>,
+    last_flush: Option<Instant>,
+    last_parameters: Option<LogicParameters>,
+    last_pii: Vec<u8>,
+    last_piq: Vec<u8>,
+}
+
+impl Recorder {
+    pub fn create(path: &std::path::Path) -> Result<Self, RecordingError> {
+        let io_err = |e| RecordingError::Io(path.to_owned(), e);
+        let mut writer = BufWriter::new(std::fs::File::create(path).map_err(io_err)?);
+        writer.write_all(MAGIC).map_err(io_err)?;
+        writer.write_all(&VERSION.to_le_bytes()).map_err(io_err)?;
+
+        Ok(Self {
+            path: path.to_owned(),
+            writer,
+            start: None,
+            last_flush: None,
+            last_parameters: None,
+            last_pii: Vec::new(),
+            last_piq: Vec::new(),
+        })
+    }
+
+    /// Append one cycle of the logic, after `Logic::run` was called
+    pub fn record(
+        &mut self,
+        now: Instant,
+        logic: &Logic,
+        process_images: Option<(&[u8], &[u8])>,
+    ) -> Result<(), RecordingError> {
+        let start = *self.start.get_or_insert(now);
+
+        let changed = |last: &mut Vec<u8>, current: &[u8]| {
+            (last[..] != *current).then(|| {
+                last.clear();
+                last.extend_from_slice(current);
+                current.to_vec()
+            })
+        };
+        let (pii, piq) = match process_images {
+            Some((pii, piq)) => (
+                changed(&mut self.last_pii, pii),
+                changed(&mut self.last_piq, piq),
+            ),
+            None => (None, None),
+        };
+
+        let parameters = (self.last_parameters.as_ref() != Some(logic.parameters())).then(|| {
+            self.last_parameters = Some(logic.parameters().clone());
+            logic.parameters().clone()
+        });
+
+        let frame = Frame {
+            time_us: (now - start).as_micros().try_into().unwrap_or(u64::MAX),
+            inputs: logic.inputs().clone(),
+            outputs: logic.outputs().clone(),
+            parameters,
+            pii,
+            piq,
+        };
+        encoding()
+            .serialize_into(&mut self.writer, &frame)
+            .map_err(RecordingError::Encoding)?;
+
+        // Flush regularly so little is lost when the process gets killed
+        if self
+            .last_flush
+            .is_none_or(|t| now - t >= Duration::from_secs(1))
+        {
+            self.writer
+                .flush()
+                .map_err(|e| RecordingError::Io(self.path.clone(), e))?;
+            self.last_flush = Some(now);
+        }
+
+        Ok(())
+    }
+}
+
+/// Iterate over the frames of a recording
+pub fn read_frames(
+    path: &std::path::Path,
+) -> Result<impl Iterator<Item = Result<Frame, RecordingError>>, RecordingError> {
+    let io_err = |e| RecordingError::Io(path.to_owned(), e);
+    let mut reader = BufReader::new(std::fs::File::open(path).map_err(io_err)?);
+
+    let mut header = [0u8; 12];
+    reader.read_exact(&mut header).map_err(io_err)?;
+    if header[..8] != MAGIC[..] || header[8..] != VERSION.to_le_bytes() {
+        return Err(RecordingError::BadHeader(path.to_owned()));
+    }
+
+    Ok(std::iter::from_fn(move || {
+        match encoding().deserialize_from::<_, Frame>(&mut reader) {
+            Ok(frame) => Some(Ok(frame)),
+            Err(e) => match *e {
+                // End of the file, possibly with a frame cut short by killing the recording process
+                bincode::ErrorKind::Io(ref io)
+                    if io.kind() == std::io::ErrorKind::UnexpectedEof =>
+                {
+                    None
+                }
+                _ => Some(Err(RecordingError::Encoding(e))),
+            },
+        }
+    }))
+}
+
+/// Names and values of all outputs which differ
+fn output_differences(recorded: &LogicOutputs, replayed: &LogicOutputs) -> Vec<String> {
+    let mut differences: Vec<String> = Channels::NAMES
+        .iter()
+        .filter_map(|name| {
+            let (a, b) = (recorded.channels.get(name)?, replayed.channels.get(name)?);
+            (a != b).then(|| format!("channels.{name}: {a} -> {b}"))
+        })
+        .collect();
+    for (name, a, b) in [
+        (
+            "indicator_fault",
+            recorded.indicator_fault,
+            replayed.indicator_fault,
+        ),
+        (
+            "indicator_refill_air",
+            recorded.indicator_refill_air,
+            replayed.indicator_refill_air,
+        ),
+        ("run_fan", recorded.run_fan, replayed.run_fan),
+    ] {
+        if a != b {
+            differences.push(format!("{name}: {a} -> {b}"));
+        }
+    }
+    differences
+}
+
+#[derive(Debug, Default)]
+pub struct ReplaySummary {
+    pub frames: usize,
+    /// Number of frames whose outputs differ from the recording
+    pub mismatches: usize,
+}
+
+/// Run a recording through a fresh `Logic` with the given emotions and compare the outputs
+pub fn replay(
+    path: &std::path::Path,
+    speed: f64,
+    emotions: std::sync::Arc<crate::emotions::Emotions>,
+) -> Result<ReplaySummary, RecordingError> {
+    let mut logic = Logic::new();
+    logic.set_emotions(emotions);
+    let mut summary = ReplaySummary::default();
+    let virtual_start = Instant::now();
+    let wall_start = Instant::now();
+
+    for frame in read_frames(path)? {
+        let frame = frame?;
+        let time = Duration::from_micros(frame.time_us);
+
+        if speed > 0. {
+            let due = wall_start + time.div_f64(speed);
+            std::thread::sleep(due.saturating_duration_since(Instant::now()));
+        }
+
+        if let Some(parameters) = frame.parameters {
+            logic.set_parameters(parameters);
+        }
+        *logic.inputs_mut() = frame.inputs;
+        logic.run(virtual_start + time);
+
+        let differences = output_differences(&frame.outputs, logic.outputs());
+        if !differences.is_empty() {
+            summary.mismatches += 1;
+            log::warn!(
+                "Outputs differ at {:.3} s: {}",
+                time.as_secs_f64(),
+                differences.join(", ")
+            );
+        }
+        summary.frames += 1;
+    }
+
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::harness::Harness;
+    use crate::timers::TimeExt;
+
+    #[test]
+    fn frame_encoding() {
+        let path = std::env::temp_dir().join(format!("crab-recording-{}.bin", std::process::id()));
+        let mut recorder = Recorder::create(&path).unwrap();
+
+        let mut h = Harness::new();
+        let start = Instant::now();
+        let mut pii = [0u8; 8];
+        for i in 0..1000u32 {
+            if i == 200 {
+                h.inputs().emotion = Some(crate::logic::Emotion::new("Sad"));
+            }
+            if i == 400 {
+                h.inputs().trigger_fan = true;
+            }
+            if i == 401 {
+                h.inputs().trigger_fan = false;
+            }
+            pii[1] = (i / 100) as u8;
+            h.step();
+            recorder
+                .record(start + 50.millis() * i, &h.logic, Some((&pii, &[0u8; 8])))
+                .unwrap();
+        }
+        drop(recorder);
+
+        let frames: Vec<Frame> = read_frames(&path).unwrap().map(Result::unwrap).collect();
+        assert_eq!(frames.len(), 1000);
+        assert!(frames[0].pii.is_some() && frames[0].piq.is_some());
+        assert!(frames[0].parameters.is_some());
+        assert!(frames[1].pii.is_none() && frames[1].piq.is_none());
+        assert!(frames[1].parameters.is_none());
+        assert_eq!(frames.iter().filter(|f| f.pii.is_some()).count(), 10);
+        assert_eq!(frames[999].time_us, 999 * 50_000);
+        assert_eq!(
+            frames[300].inputs.emotion,
+            Some(crate::logic::Emotion::new("Sad"))
+        );
+        assert!(frames[400].inputs.trigger_fan);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn replay_detects_differences() {
+        let path = std::env::temp_dir().join(format!("crab-replay-{}.bin", std::process::id()));
+        let mut recorder = Recorder::create(&path).unwrap();
+
+        // Record a plain logic from the very start, like the main loop does
+        let mut logic = Logic::new();
+        let start = Instant::now();
+        for i in 0..100u32 {
+            let inputs = logic.inputs_mut();
+            inputs.dc_ok = true;
+            inputs.estop_ok = true;
+            inputs.fieldbus_ok = true;
+            inputs.station_running = true;
+            inputs.pressure_fullscale = 80;
+            inputs.reset_fault = i == 10;
+            let now = start + 50.millis() * i;
+            logic.run(now);
+            recorder.record(now, &logic, None).unwrap();
+        }
+        drop(recorder);
+
+        let summary = replay(&path, 0., Default::default()).unwrap();
+        assert_eq!(summary.frames, 100);
+        assert_eq!(summary.mismatches, 0);
+
+        // Tamper with one frame
+        let mut frames: Vec<Frame> = read_frames(&path).unwrap().map(Result::unwrap).collect();
+        frames[50].outputs.run_fan = !frames[50].outputs.run_fan;
+        let mut file = std::fs::File::create(&path).unwrap();
+        file.write_all(MAGIC).unwrap();
+        file.write_all(&VERSION.to_le_bytes()).unwrap();
+        for frame in frames.iter() {
+            encoding().serialize_into(&mut file, frame).unwrap();
+        }
+        drop(file);
+
+        let summary = replay(&path, 0., Default::default()).unwrap();
+        assert_eq!(summary.mismatches, 1);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
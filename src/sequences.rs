@@ -0,0 +1,312 @@
+//! Library and playback of the emotion sequences
+//!
+//! Sequences are stored as one TOML file per sequence in the configured directory, named after
+//! the sequence.  Uploads through the API are written there as well.  The main loop answers the
+//! API commands and feeds the current step of the playing sequence into the logic, which shows
+//! its emotion and channels just like the ones from the emotion manager.
+
+use crab_httpapi::sequences::{
+    Sequence, SequenceCommand, SequenceError, SequenceStatus, SequenceStep, SequencesInfo,
+};
+
+/// Location of the sequences, from the `[sequences]` section of the configuration
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SequencesConfig {
+    /// Directory of the sequence files, uploads are only kept in memory when not set
+    pub path: Option<std::path::PathBuf>,
+}
+
+impl Default for SequencesConfig {
+    fn default() -> Self {
+        Self {
+            path: Some("sequences".into()),
+        }
+    }
+}
+
+fn is_channel(name: &str) -> bool {
+    crate::logic::Channels::NAMES.contains(&name)
+}
+
+#[derive(Debug)]
+struct Playing {
+    name: String,
+    sequence: Sequence,
+    step: usize,
+    step_started: std::time::Instant,
+}
+
+#[derive(Debug, Default)]
+pub struct Sequences {
+    path: Option<std::path::PathBuf>,
+    emotions: std::sync::Arc<crate::emotions::Emotions>,
+    library: std::collections::BTreeMap<String, Sequence>,
+    playing: Option<Playing>,
+}
+
+impl Sequences {
+    /// Load all sequences from the configured directory, which does not need to exist yet
+    pub fn open(
+        config: &SequencesConfig,
+        emotions: std::sync::Arc<crate::emotions::Emotions>,
+    ) -> Result<Self, String> {
+        let mut sequences = Self {
+            path: config.path.clone(),
+            emotions,
+            ..Default::default()
+        };
+        let Some(path) = &config.path else {
+            return Ok(sequences);
+        };
+
+        let entries = match std::fs::read_dir(path) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(sequences),
+            Err(e) => return Err(format!("failed reading {}: {e}", path.display())),
+        };
+        for entry in entries {
+            let file = entry
+                .map_err(|e| format!("failed reading {}: {e}", path.display()))?
+                .path();
+            if file.extension().is_none_or(|ext| ext != "toml") {
+                continue;
+            }
+            let Some(name) = file.file_stem().and_then(|s| s.to_str()) else {
+                continue;
+            };
+            let load = || {
+                crab_httpapi::sequences::validate_name(name).map_err(|e| e.to_string())?;
+                let s = std::fs::read_to_string(&file).map_err(|e| e.to_string())?;
+                let sequence: Sequence = toml::from_str(&s).map_err(|e| e.to_string())?;
+                sequences.validate(&sequence).map_err(|e| e.to_string())?;
+                Ok::<_, String>(sequence)
+            };
+            let sequence = load().map_err(|e| format!("bad sequence {}: {e}", file.display()))?;
+            sequences.library.insert(name.to_owned(), sequence);
+        }
+        log::info!(
+            "Loaded {} sequences from {}.",
+            sequences.library.len(),
+            path.display()
+        );
+        Ok(sequences)
+    }
+
+    /// Answer a command from the API
+    pub fn handle(&mut self, now: std::time::Instant, command: SequenceCommand) {
+        match command {
+            SequenceCommand::List { resp } => {
+                let _ = resp.send(SequencesInfo {
+                    sequences: self.library.clone(),
+                    playing: self.status(now),
+                });
+            }
+            SequenceCommand::Upload {
+                name,
+                sequence,
+                resp,
+            } => {
+                let _ = resp.send(self.upload(name, sequence));
+            }
+            SequenceCommand::Start { name, resp } => {
+                let _ = resp.send(self.start(now, &name));
+            }
+            SequenceCommand::Stop { resp } => {
+                self.stop();
+                let _ = resp.send(Ok(()));
+            }
+        }
+    }
+
+    fn validate(&self, sequence: &Sequence) -> Result<(), SequenceError> {
+        sequence.validate(|emotion| self.emotions.contains(emotion), is_channel)
+    }
+
+    fn upload(&mut self, name: String, sequence: Sequence) -> Result<(), SequenceError> {
+        crab_httpapi::sequences::validate_name(&name)?;
+        self.validate(&sequence)?;
+
+        if let Some(path) = &self.path {
+            let contents = toml::to_string_pretty(&sequence)
+                .map_err(|e| SequenceError::Save(e.to_string()))?;
+            let file = path.join(format!("{name}.toml"));
+            std::fs::create_dir_all(path)
+                .and_then(|()| std::fs::write(&file, contents))
+                .map_err(|e| SequenceError::Save(format!("{}: {e}", file.display())))?;
+        }
+        log::info!("Stored sequence {name:?}.");
+        self.library.insert(name, sequence);
+        Ok(())
+    }
+
+    fn start(&mut self, now: std::time::Instant, name: &str) -> Result<(), SequenceError> {
+        let sequence = self
+            .library
+            .get(name)
+            .ok_or_else(|| SequenceError::NotFound(name.to_owned()))?;
+        log::info!("Playing sequence {name:?}.");
+
+        // Steps without an emotion keep the one of the step before
+        let mut sequence = sequence.clone();
+        let mut emotion = None;
+        for step in sequence.steps.iter_mut() {
+            if step.emotion.is_none() {
+                step.emotion = emotion.clone();
+            }
+            emotion = step.emotion.clone();
+        }
+
+        self.playing = Some(Playing {
+            name: name.to_owned(),
+            sequence,
+            step: 0,
+            step_started: now,
+        });
+        Ok(())
+    }
+
+    fn stop(&mut self) {
+        if let Some(playing) = self.playing.take() {
+            log::info!("Stopped sequence {:?}.", playing.name);
+        }
+    }
+
+    /// Advance to `now` and return the current step, `None` when no sequence is playing
+    pub fn step(&mut self, now: std::time::Instant) -> Option<&SequenceStep> {
+        let playing = self.playing.as_mut()?;
+        loop {
+            let step = &playing.sequence.steps[playing.step];
+            let step_ends =
+                playing.step_started + std::time::Duration::from_secs_f64(step.duration_s);
+            if now < step_ends {
+                break;
+            }
+            playing.step += 1;
+            playing.step_started = step_ends;
+            if playing.step == playing.sequence.steps.len() {
+                log::info!("Sequence {:?} finished.", playing.name);
+                self.playing = None;
+                return None;
+            }
+        }
+        let playing = self.playing.as_ref()?;
+        Some(&playing.sequence.steps[playing.step])
+    }
+
+    fn status(&self, now: std::time::Instant) -> Option<SequenceStatus> {
+        let playing = self.playing.as_ref()?;
+        let remaining: f64 = playing.sequence.steps[playing.step..]
+            .iter()
+            .map(|s| s.duration_s)
+            .sum::<f64>()
+            - (now - playing.step_started).as_secs_f64();
+        Some(SequenceStatus {
+            name: playing.name.clone(),
+            step: playing.step,
+            steps: playing.sequence.steps.len(),
+            remaining_s: remaining.max(0.),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logic::Emotion;
+    use std::time::Duration;
+
+    fn step(emotion: Option<Emotion>, channels: &[(&str, bool)], duration_s: f64) -> SequenceStep {
+        SequenceStep {
+            emotion,
+            channels: channels.iter().map(|&(c, on)| (c.into(), on)).collect(),
+            duration_s,
+        }
+    }
+
+    fn call<T>(
+        sequences: &mut Sequences,
+        now: std::time::Instant,
+        command: impl FnOnce(tokio::sync::oneshot::Sender<T>) -> SequenceCommand,
+    ) -> T {
+        let (tx, mut rx) = tokio::sync::oneshot::channel();
+        sequences.handle(now, command(tx));
+        rx.try_recv().unwrap()
+    }
+
+    #[test]
+    fn upload_and_play() {
+        let dir = std::env::temp_dir().join(format!("crab-sequences-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let config = SequencesConfig {
+            path: Some(dir.clone()),
+        };
+        let mut sequences = Sequences::open(&config, Default::default()).unwrap();
+        let t0 = std::time::Instant::now();
+
+        let show = Sequence {
+            steps: vec![
+                step(Some(Emotion::new("Surprised")), &[], 2.),
+                step(Some(Emotion::new("Happy")), &[("left_claw", false)], 5.),
+                step(None, &[("pupil_top", false), ("pupil_down", true)], 0.5),
+            ],
+        };
+        let upload = |name: &str, sequence: Sequence| {
+            let name = name.to_owned();
+            move |resp| SequenceCommand::Upload {
+                name,
+                sequence,
+                resp,
+            }
+        };
+        call(&mut sequences, t0, upload("show", show.clone())).unwrap();
+        let mut bad = show.clone();
+        bad.steps[1].channels.insert("tail".into(), true);
+        assert_eq!(
+            call(&mut sequences, t0, upload("bad", bad)),
+            Err(SequenceError::UnknownChannels(vec![(1, "tail".into())]))
+        );
+        assert_eq!(
+            call(&mut sequences, t0, |resp| SequenceCommand::Start {
+                name: "bad".into(),
+                resp
+            }),
+            Err(SequenceError::NotFound("bad".into()))
+        );
+
+        // Stored sequences are loaded again
+        let mut sequences = Sequences::open(&config, Default::default()).unwrap();
+        let info = call(&mut sequences, t0, |resp| SequenceCommand::List { resp });
+        assert_eq!(info.sequences.get("show"), Some(&show));
+        assert_eq!(info.playing, None);
+
+        assert_eq!(sequences.step(t0), None);
+        call(&mut sequences, t0, |resp| SequenceCommand::Start {
+            name: "show".into(),
+            resp,
+        })
+        .unwrap();
+        let s = |secs: f64| t0 + Duration::from_secs_f64(secs);
+        assert_eq!(sequences.step(s(1.)), Some(&show.steps[0]));
+        assert_eq!(sequences.step(s(2.)), Some(&show.steps[1]));
+        let info = call(&mut sequences, s(3.), |resp| SequenceCommand::List { resp });
+        let playing = info.playing.unwrap();
+        assert_eq!((playing.step, playing.steps), (1, 3));
+        assert!((playing.remaining_s - 4.5).abs() < 1e-6);
+        // The last step has no emotion of its own and keeps the happy one
+        let last = sequences.step(s(7.2)).unwrap();
+        assert_eq!(last.emotion, Some(Emotion::new("Happy")));
+        assert_eq!(last.channels, show.steps[2].channels);
+        assert_eq!(sequences.step(s(7.5)), None);
+
+        call(&mut sequences, t0, |resp| SequenceCommand::Start {
+            name: "show".into(),
+            resp,
+        })
+        .unwrap();
+        call(&mut sequences, t0, |resp| SequenceCommand::Stop { resp }).unwrap();
+        assert_eq!(sequences.step(t0), None);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
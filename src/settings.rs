@@ -0,0 +1,211 @@
+//! Persistent settings which can be changed at runtime
+//!
+//! Unlike the configuration, the settings are written back whenever they are changed through one
+//! of the APIs, so they survive a restart.  The file is replaced atomically to never leave
+//! half-written settings behind when the power is cut.
+
+use crate::logic::{LogicParameters, PressureLimits};
+
+/// Current version of the settings file format
+///
+/// Increment when making incompatible changes and add a migration to `SettingsStore::load`.
+const SETTINGS_VERSION: u32 = 1;
+
+/// Location of the settings, from the `[settings]` section of the configuration
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SettingsConfig {
+    pub path: std::path::PathBuf,
+}
+
+impl Default for SettingsConfig {
+    fn default() -> Self {
+        Self {
+            path: "crab-settings.toml".into(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    pub version: u32,
+    pub pressure_limits: PressureLimits,
+    pub logic: LogicParameters,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            version: SETTINGS_VERSION,
+            pressure_limits: Default::default(),
+            logic: Default::default(),
+        }
+    }
+}
+
+impl Settings {
+    pub fn validate(&self) -> Result<(), String> {
+        self.pressure_limits.validate()?;
+        self.logic.validate()?;
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub enum SettingsError {
+    Io(std::path::PathBuf, std::io::Error),
+    Parse(toml::de::Error),
+    Serialize(toml::ser::Error),
+    Version(u32),
+    Invalid(String),
+}
+
+impl std::fmt::Display for SettingsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SettingsError::Io(path, e) => write!(f, "failed accessing {}: {e}", path.display()),
+            SettingsError::Parse(e) => write!(f, "failed parsing settings: {e}"),
+            SettingsError::Serialize(e) => write!(f, "failed serializing settings: {e}"),
+            SettingsError::Version(v) => write!(
+                f,
+                "settings version {v} is not supported (expected {SETTINGS_VERSION})"
+            ),
+            SettingsError::Invalid(reason) => write!(f, "invalid settings: {reason}"),
+        }
+    }
+}
+
+impl std::error::Error for SettingsError {}
+
+#[derive(Debug)]
+pub struct SettingsStore {
+    path: std::path::PathBuf,
+    settings: Settings,
+}
+
+impl SettingsStore {
+    /// Load the settings, falling back to defaults if the file does not exist yet
+    pub fn load(path: &std::path::Path) -> Result<Self, SettingsError> {
+        let settings = match std::fs::read_to_string(path) {
+            Ok(s) => {
+                let settings: Settings = toml::from_str(&s).map_err(SettingsError::Parse)?;
+                if settings.version != SETTINGS_VERSION {
+                    return Err(SettingsError::Version(settings.version));
+                }
+                settings.validate().map_err(SettingsError::Invalid)?;
+                settings
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                log::info!("No settings found at {}, using defaults.", path.display());
+                Settings::default()
+            }
+            Err(e) => return Err(SettingsError::Io(path.to_owned(), e)),
+        };
+
+        Ok(Self {
+            path: path.to_owned(),
+            settings,
+        })
+    }
+
+    pub fn settings(&self) -> &Settings {
+        &self.settings
+    }
+
+    /// Modify the settings and write them back
+    ///
+    /// Invalid changes are rejected and leave the settings untouched.
+    pub fn update(&mut self, f: impl FnOnce(&mut Settings)) -> Result<(), SettingsError> {
+        let mut settings = self.settings.clone();
+        f(&mut settings);
+        settings.validate().map_err(SettingsError::Invalid)?;
+        if settings == self.settings {
+            return Ok(());
+        }
+
+        self.settings = settings;
+        self.save()
+    }
+
+    fn save(&self) -> Result<(), SettingsError> {
+        let io_err = |e| SettingsError::Io(self.path.clone(), e);
+        let contents = toml::to_string_pretty(&self.settings).map_err(SettingsError::Serialize)?;
+
+        // Write to a temporary file next to the settings and rename it, which is atomic on POSIX
+        let mut tmp_path = self.path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        let tmp_path = std::path::PathBuf::from(tmp_path);
+        {
+            use std::io::Write;
+            let mut file = std::fs::File::create(&tmp_path).map_err(io_err)?;
+            file.write_all(contents.as_bytes()).map_err(io_err)?;
+            file.sync_all().map_err(io_err)?;
+        }
+        std::fs::rename(&tmp_path, &self.path).map_err(io_err)?;
+
+        log::debug!("Saved settings to {}.", self.path.display());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("crab-{name}-{}.toml", std::process::id()))
+    }
+
+    #[test]
+    fn persist_settings() {
+        let path = temp_path("settings");
+        let _ = std::fs::remove_file(&path);
+
+        let mut store = SettingsStore::load(&path).unwrap();
+        assert_eq!(*store.settings(), Settings::default());
+        assert!(!path.exists(), "defaults must not be written");
+
+        store
+            .update(|s| {
+                s.pressure_limits.high = 0.5;
+                s.logic.fan_cooldown_s = 20.;
+            })
+            .unwrap();
+        assert!(path.exists());
+
+        let store = SettingsStore::load(&path).unwrap();
+        assert_eq!(store.settings().pressure_limits.high, 0.5);
+        assert_eq!(store.settings().logic.fan_cooldown_s, 20.);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn reject_invalid() {
+        let path = temp_path("invalid-settings");
+        let _ = std::fs::remove_file(&path);
+        let mut store = SettingsStore::load(&path).unwrap();
+
+        let result = store.update(|s| s.pressure_limits.low = 0.5);
+        assert!(matches!(result, Err(SettingsError::Invalid(_))));
+        let result = store.update(|s| s.logic.blink_duration_s = f64::NAN);
+        assert!(matches!(result, Err(SettingsError::Invalid(_))));
+        assert_eq!(*store.settings(), Settings::default());
+        assert!(!path.exists());
+
+        std::fs::write(&path, "version = 99\n").unwrap();
+        assert!(matches!(
+            SettingsStore::load(&path),
+            Err(SettingsError::Version(99))
+        ));
+
+        std::fs::write(&path, "[pressure_limits]\nlow_low = 0.3\n").unwrap();
+        assert!(matches!(
+            SettingsStore::load(&path),
+            Err(SettingsError::Invalid(_))
+        ));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
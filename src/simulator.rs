@@ -0,0 +1,737 @@
+//! Simulated PROFIBUS DP peripherals for testing without hardware
+//!
+//! The simulator opens a pseudo-terminal and answers FDL telegrams on its master side while the
+//! DP master uses the slave side as its serial device.  Each configured peripheral is simulated
+//! as a DP-V0 station which checks the parameters and configuration sent by the master and then
+//! exchanges process data with it.  Tests and plant models access the simulated process data
+//! through a [`SimulatorHandle`].
+
+use std::io::{Read, Write};
+use std::os::fd::{AsFd, OwnedFd};
+use std::sync::{Arc, Mutex};
+
+use nix::poll::{PollFd, PollFlags};
+use nix::sys::termios;
+
+use crate::fieldbus::PeripheralConfig;
+
+const SD1: u8 = 0x10;
+const SD2: u8 = 0x68;
+const SD3: u8 = 0xa2;
+const SD4: u8 = 0xdc;
+const SC: u8 = 0xe5;
+const ED: u8 = 0x16;
+
+const BROADCAST_ADDRESS: u8 = 127;
+
+// Service access points of the DP services we implement
+const SAP_MASTER: u8 = 62;
+const SAP_SLAVE_DIAG: u8 = 60;
+const SAP_SET_PRM: u8 = 61;
+const SAP_CHK_CFG: u8 = 62;
+const SAP_GET_CFG: u8 = 59;
+const SAP_GLOBAL_CONTROL: u8 = 58;
+
+// Function codes of requests (lower nibble, with the request bit set)
+const FC_REQUEST: u8 = 0x40;
+const FC_FDL_STATUS: u8 = 0x09;
+const FC_SRD_LOW: u8 = 0x0c;
+const FC_SRD_HIGH: u8 = 0x0d;
+
+// Function codes of responses from a passive station
+const FC_OK: u8 = 0x00;
+const FC_RS: u8 = 0x03;
+const FC_DL: u8 = 0x08;
+const FC_DH: u8 = 0x0a;
+
+/// An FDL telegram as seen on the wire
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Telegram {
+    Token {
+        da: u8,
+        sa: u8,
+    },
+    ShortConfirmation,
+    Data {
+        da: u8,
+        sa: u8,
+        fc: u8,
+        dsap: Option<u8>,
+        ssap: Option<u8>,
+        data: Vec<u8>,
+    },
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Parsed {
+    /// More bytes are needed to decide
+    Incomplete,
+    /// The first byte does not start a valid telegram
+    Garbage,
+    Telegram(Telegram, usize),
+}
+
+fn fcs(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b))
+}
+
+fn parse_telegram(buf: &[u8]) -> Parsed {
+    let Some(&sd) = buf.first() else {
+        return Parsed::Incomplete;
+    };
+
+    let (header, length) = match sd {
+        SC => return Parsed::Telegram(Telegram::ShortConfirmation, 1),
+        SD4 => {
+            let [_, da, sa, ..] = *buf else {
+                return Parsed::Incomplete;
+            };
+            return Parsed::Telegram(Telegram::Token { da, sa }, 3);
+        }
+        SD1 => (1, 6),
+        SD3 => (1, 14),
+        SD2 => {
+            let [_, le, ler, sd, ..] = *buf else {
+                return Parsed::Incomplete;
+            };
+            if le != ler || sd != SD2 || le < 3 {
+                return Parsed::Garbage;
+            }
+            (4, usize::from(le) + 6)
+        }
+        _ => return Parsed::Garbage,
+    };
+
+    let Some(frame) = buf.get(..length) else {
+        return Parsed::Incomplete;
+    };
+    let body = &frame[header..length - 2];
+    if frame[length - 1] != ED || fcs(body) != frame[length - 2] {
+        return Parsed::Garbage;
+    }
+
+    let [da, sa, fc, ref rest @ ..] = *body else {
+        return Parsed::Garbage;
+    };
+    let mut rest = rest;
+    let mut take_sap = |extended: bool| -> Result<Option<u8>, ()> {
+        if !extended {
+            return Ok(None);
+        }
+        let (&sap, remainder) = rest.split_first().ok_or(())?;
+        rest = remainder;
+        Ok(Some(sap & 0x3f))
+    };
+    let (Ok(dsap), Ok(ssap)) = (take_sap(da & 0x80 != 0), take_sap(sa & 0x80 != 0)) else {
+        return Parsed::Garbage;
+    };
+
+    Parsed::Telegram(
+        Telegram::Data {
+            da: da & 0x7f,
+            sa: sa & 0x7f,
+            fc,
+            dsap,
+            ssap,
+            data: rest.to_vec(),
+        },
+        length,
+    )
+}
+
+impl Telegram {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        match self {
+            Telegram::Token { da, sa } => buf.extend_from_slice(&[SD4, *da, *sa]),
+            Telegram::ShortConfirmation => buf.push(SC),
+            Telegram::Data {
+                da,
+                sa,
+                fc,
+                dsap,
+                ssap,
+                data,
+            } => {
+                let mut body = vec![
+                    da | if dsap.is_some() { 0x80 } else { 0 },
+                    sa | if ssap.is_some() { 0x80 } else { 0 },
+                    *fc,
+                ];
+                body.extend(dsap.iter().chain(ssap.iter()));
+                body.extend_from_slice(data);
+
+                if body.len() == 3 {
+                    buf.push(SD1);
+                } else {
+                    let le = u8::try_from(body.len()).expect("telegram too long");
+                    buf.extend_from_slice(&[SD2, le, le, SD2]);
+                }
+                buf.extend_from_slice(&body);
+                buf.extend_from_slice(&[fcs(&body), ED]);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum StationState {
+    WaitPrm,
+    WaitCfg,
+    DataExchange,
+}
+
+#[derive(Debug)]
+struct SimulatedStation {
+    address: u8,
+    ident_number: u16,
+    config: Vec<u8>,
+    state: StationState,
+    master_address: Option<u8>,
+    prm_fault: bool,
+    cfg_fault: bool,
+    watchdog: Option<std::time::Duration>,
+    last_contact: std::time::Instant,
+    inputs: Vec<u8>,
+    outputs: Vec<u8>,
+    ext_diag: Vec<u8>,
+    diag_pending: bool,
+}
+
+impl SimulatedStation {
+    fn new(config: &PeripheralConfig) -> Self {
+        Self {
+            address: config.address,
+            ident_number: config.ident_number,
+            config: config.config.clone(),
+            state: StationState::WaitPrm,
+            master_address: None,
+            prm_fault: false,
+            cfg_fault: false,
+            watchdog: None,
+            last_contact: std::time::Instant::now(),
+            inputs: vec![0u8; config.input_size],
+            outputs: vec![0u8; config.output_size],
+            ext_diag: Vec::new(),
+            diag_pending: false,
+        }
+    }
+
+    fn reset(&mut self) {
+        self.state = StationState::WaitPrm;
+        self.outputs.fill(0);
+    }
+
+    /// Fall back to waiting for parameters when the master is silent for too long
+    fn check_watchdog(&mut self, now: std::time::Instant) {
+        if let Some(watchdog) = self.watchdog
+            && self.state != StationState::WaitPrm
+            && now.duration_since(self.last_contact) > watchdog
+        {
+            log::warn!(
+                "Simulated peripheral #{}: Watchdog expired, leaving data exchange.",
+                self.address
+            );
+            self.reset();
+        }
+    }
+
+    fn diagnostics(&self) -> Vec<u8> {
+        let mut status_1 = 0u8;
+        if self.state != StationState::DataExchange {
+            status_1 |= 0x02; // Station_Not_Ready
+        }
+        if self.cfg_fault {
+            status_1 |= 0x04;
+        }
+        if !self.ext_diag.is_empty() {
+            status_1 |= 0x08;
+        }
+        if self.prm_fault {
+            status_1 |= 0x40;
+        }
+
+        let mut status_2 = 0x04u8;
+        if self.state == StationState::WaitPrm {
+            status_2 |= 0x01; // Prm_Req
+        }
+        if self.watchdog.is_some() {
+            status_2 |= 0x08;
+        }
+
+        let mut diag = vec![status_1, status_2, 0x00, self.master_address.unwrap_or(255)];
+        diag.extend_from_slice(&self.ident_number.to_be_bytes());
+        diag.extend_from_slice(&self.ext_diag);
+        diag
+    }
+
+    fn set_prm(&mut self, master: u8, prm: &[u8]) {
+        let ident_number = prm.get(4..6).map(|b| u16::from_be_bytes([b[0], b[1]]));
+        if ident_number != Some(self.ident_number) {
+            log::warn!(
+                "Simulated peripheral #{}: Rejecting parameters with ident number {:04x?}.",
+                self.address,
+                ident_number
+            );
+            self.prm_fault = true;
+            self.reset();
+            return;
+        }
+
+        // DP-V1 stations may select a watchdog base of 1 ms instead of 10 ms
+        let base_ms = if prm.get(7).is_some_and(|s| s & 0x04 != 0) {
+            1
+        } else {
+            10
+        };
+        self.watchdog = (prm[0] & 0x08 != 0).then(|| {
+            std::time::Duration::from_millis(u64::from(prm[1]) * u64::from(prm[2]) * base_ms)
+        });
+
+        self.prm_fault = false;
+        self.master_address = Some(master);
+        self.state = StationState::WaitCfg;
+    }
+
+    fn chk_cfg(&mut self, config: &[u8]) {
+        if self.state == StationState::WaitPrm {
+            return;
+        }
+        if config == self.config {
+            self.cfg_fault = false;
+            self.state = StationState::DataExchange;
+            log::info!(
+                "Simulated peripheral #{}: Entering data exchange.",
+                self.address
+            );
+        } else {
+            log::warn!(
+                "Simulated peripheral #{}: Rejecting configuration {:02x?}.",
+                self.address,
+                config
+            );
+            self.cfg_fault = true;
+            self.reset();
+        }
+    }
+
+    /// Handle a request telegram, returning the response if one is due
+    fn handle(&mut self, telegram: &Telegram, now: std::time::Instant) -> Option<Telegram> {
+        let Telegram::Data {
+            da,
+            sa,
+            fc,
+            dsap,
+            ssap,
+            data,
+        } = telegram
+        else {
+            return None;
+        };
+        if *da != self.address || fc & FC_REQUEST == 0 {
+            return None;
+        }
+
+        let address = self.address;
+        let respond = |fc: u8, data: Vec<u8>| {
+            Some(Telegram::Data {
+                da: *sa,
+                sa: address,
+                fc,
+                dsap: *ssap,
+                ssap: *dsap,
+                data,
+            })
+        };
+
+        match fc & 0x0f {
+            FC_FDL_STATUS => return respond(FC_OK, Vec::new()),
+            FC_SRD_LOW | FC_SRD_HIGH => (),
+            _ => return None,
+        }
+
+        self.check_watchdog(now);
+        if self.master_address.is_none_or(|m| m == *sa) {
+            self.last_contact = now;
+        }
+
+        match (*dsap, *ssap) {
+            (None, None) => {
+                if self.state != StationState::DataExchange || self.master_address != Some(*sa) {
+                    return respond(FC_RS, Vec::new());
+                }
+                if data.len() == self.outputs.len() {
+                    self.outputs.copy_from_slice(data);
+                }
+                if self.inputs.is_empty() && !self.diag_pending {
+                    return Some(Telegram::ShortConfirmation);
+                }
+                let fc = if self.diag_pending { FC_DH } else { FC_DL };
+                respond(fc, self.inputs.clone())
+            }
+            (Some(SAP_SLAVE_DIAG), Some(SAP_MASTER)) => {
+                self.diag_pending = false;
+                respond(FC_DL, self.diagnostics())
+            }
+            (Some(SAP_SET_PRM), Some(SAP_MASTER)) => {
+                if data.len() < 7 {
+                    self.prm_fault = true;
+                    self.reset();
+                } else {
+                    self.set_prm(*sa, data);
+                }
+                Some(Telegram::ShortConfirmation)
+            }
+            (Some(SAP_CHK_CFG), Some(SAP_MASTER)) => {
+                self.chk_cfg(data);
+                Some(Telegram::ShortConfirmation)
+            }
+            (Some(SAP_GET_CFG), Some(SAP_MASTER)) => respond(FC_DL, self.config.clone()),
+            (Some(SAP_GLOBAL_CONTROL), _) => Some(Telegram::ShortConfirmation),
+            _ => respond(FC_RS, Vec::new()),
+        }
+    }
+}
+
+/// Access to the process data of the simulated peripherals
+#[derive(Debug, Clone)]
+pub struct SimulatorHandle {
+    stations: Arc<Mutex<Vec<SimulatedStation>>>,
+}
+
+#[allow(dead_code)]
+impl SimulatorHandle {
+    fn with_station<R>(&self, address: u8, f: impl FnOnce(&mut SimulatedStation) -> R) -> R {
+        let mut stations = self.stations.lock().unwrap();
+        let station = stations
+            .iter_mut()
+            .find(|s| s.address == address)
+            .unwrap_or_else(|| panic!("no simulated peripheral at address {address}"));
+        f(station)
+    }
+
+    /// Set the input data which the peripheral at `address` reports to the master
+    pub fn set_inputs(&self, address: u8, inputs: &[u8]) {
+        self.with_station(address, |s| {
+            let len = inputs.len().min(s.inputs.len());
+            s.inputs[..len].copy_from_slice(&inputs[..len]);
+        })
+    }
+
+    /// Output data last written by the master to the peripheral at `address`
+    pub fn outputs(&self, address: u8) -> Vec<u8> {
+        self.with_station(address, |s| s.outputs.clone())
+    }
+
+    /// Replace the extended diagnostics blocks of the peripheral and notify the master
+    pub fn set_ext_diag(&self, address: u8, ext_diag: &[u8]) {
+        self.with_station(address, |s| {
+            s.ext_diag = ext_diag.to_vec();
+            s.diag_pending = true;
+        })
+    }
+
+    /// Whether the peripheral at `address` is exchanging data with the master
+    pub fn is_exchanging(&self, address: u8) -> bool {
+        self.with_station(address, |s| s.state == StationState::DataExchange)
+    }
+}
+
+/// A pseudo-terminal with simulated peripherals behind it
+pub struct Simulator {
+    master: std::fs::File,
+    // Kept open so the pseudo-terminal survives until the DP master opens it
+    _slave: OwnedFd,
+    device: std::path::PathBuf,
+    handle: SimulatorHandle,
+}
+
+impl Simulator {
+    pub fn new(peripherals: &[PeripheralConfig]) -> nix::Result<Self> {
+        let pty = nix::pty::openpty(None, None)?;
+
+        // No echo and no newline translation, we are transferring binary telegrams
+        let mut attrs = termios::tcgetattr(pty.slave.as_fd())?;
+        termios::cfmakeraw(&mut attrs);
+        termios::tcsetattr(pty.slave.as_fd(), termios::SetArg::TCSANOW, &attrs)?;
+
+        let device = nix::unistd::ttyname(pty.slave.as_fd())?;
+        let stations = peripherals.iter().map(SimulatedStation::new).collect();
+
+        Ok(Self {
+            master: pty.master.into(),
+            _slave: pty.slave,
+            device,
+            handle: SimulatorHandle {
+                stations: Arc::new(Mutex::new(stations)),
+            },
+        })
+    }
+
+    /// Path of the serial device which the DP master should open
+    pub fn device(&self) -> &std::path::Path {
+        &self.device
+    }
+
+    pub fn handle(&self) -> SimulatorHandle {
+        self.handle.clone()
+    }
+
+    /// Answer telegrams forever, usually called on a separate thread
+    pub fn run(mut self) {
+        let mut rx_buffer = Vec::new();
+        let mut tx_buffer = Vec::new();
+        let mut chunk = [0u8; 256];
+
+        loop {
+            let readable = {
+                let mut fds = [PollFd::new(self.master.as_fd(), PollFlags::POLLIN)];
+                nix::poll::poll(&mut fds, 10u8).expect("polling the pseudo-terminal failed") > 0
+            };
+            if readable {
+                let n = self
+                    .master
+                    .read(&mut chunk)
+                    .expect("reading from the pseudo-terminal failed");
+                rx_buffer.extend_from_slice(&chunk[..n]);
+            }
+
+            let now = std::time::Instant::now();
+            let mut stations = self.handle.stations.lock().unwrap();
+            loop {
+                match parse_telegram(&rx_buffer) {
+                    Parsed::Incomplete => break,
+                    Parsed::Garbage => {
+                        rx_buffer.remove(0);
+                    }
+                    Parsed::Telegram(telegram, length) => {
+                        rx_buffer.drain(..length);
+                        if let Telegram::Data {
+                            da: BROADCAST_ADDRESS,
+                            ..
+                        } = telegram
+                        {
+                            continue;
+                        }
+                        for station in stations.iter_mut() {
+                            if let Some(response) = station.handle(&telegram, now) {
+                                response.encode(&mut tx_buffer);
+                            }
+                        }
+                    }
+                }
+            }
+            for station in stations.iter_mut() {
+                station.check_watchdog(now);
+            }
+            drop(stations);
+
+            if !tx_buffer.is_empty() {
+                self.master
+                    .write_all(&tx_buffer)
+                    .expect("writing to the pseudo-terminal failed");
+                tx_buffer.clear();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(dsap: Option<u8>, data: &[u8]) -> Telegram {
+        Telegram::Data {
+            da: 8,
+            sa: 3,
+            fc: FC_REQUEST | FC_SRD_HIGH,
+            dsap,
+            ssap: dsap.map(|_| SAP_MASTER),
+            data: data.to_vec(),
+        }
+    }
+
+    #[test]
+    fn telegram_codec() {
+        let telegrams = [
+            Telegram::Token { da: 3, sa: 3 },
+            Telegram::ShortConfirmation,
+            request(None, &[]),
+            request(None, &[0x01, 0x02, 0x16, 0x68]),
+            request(Some(SAP_SLAVE_DIAG), &[]),
+        ];
+        for telegram in telegrams {
+            let mut buf = vec![0x00];
+            telegram.encode(&mut buf);
+            assert_eq!(parse_telegram(&buf), Parsed::Garbage);
+            assert_eq!(
+                parse_telegram(&buf[1..]),
+                Parsed::Telegram(telegram, buf.len() - 1)
+            );
+            assert_eq!(parse_telegram(&buf[1..buf.len() - 1]), Parsed::Incomplete);
+        }
+
+        // Fixed length telegram with 8 data bytes
+        let body = [0x08, 0x03, 0x5d, 1, 2, 3, 4, 5, 6, 7, 8];
+        let mut sd3 = vec![SD3];
+        sd3.extend_from_slice(&body);
+        sd3.extend_from_slice(&[fcs(&body), ED]);
+        assert!(matches!(
+            parse_telegram(&sd3),
+            Parsed::Telegram(Telegram::Data { ref data, .. }, 14) if data.len() == 8
+        ));
+
+        // Corrupted checksum
+        let mut buf = Vec::new();
+        request(None, &[0x42]).encode(&mut buf);
+        buf[7] ^= 0x01;
+        assert_eq!(parse_telegram(&buf), Parsed::Garbage);
+    }
+
+    #[test]
+    fn station_startup() {
+        let config = PeripheralConfig::wago_750_343();
+        let mut station = SimulatedStation::new(&config);
+        let now = std::time::Instant::now();
+        station
+            .inputs
+            .copy_from_slice(&[0x03, 0x12, 0x34, 0x00, 0x00]);
+
+        // Not parameterized yet
+        let Some(Telegram::Data { data, .. }) =
+            station.handle(&request(Some(SAP_SLAVE_DIAG), &[]), now)
+        else {
+            panic!("no diagnostics response");
+        };
+        assert_eq!(data, [0x02, 0x05, 0x00, 0xff, 0xb7, 0x57]);
+        assert!(matches!(
+            station.handle(&request(None, &[0; 5]), now),
+            Some(Telegram::Data { fc: FC_RS, .. })
+        ));
+
+        // Wrong ident number
+        let mut prm = vec![0x88, 10, 5, 11, 0x12, 0x34, 0x00];
+        station.handle(&request(Some(SAP_SET_PRM), &prm), now);
+        assert!(station.prm_fault);
+
+        prm[4..6].copy_from_slice(&config.ident_number.to_be_bytes());
+        prm.extend_from_slice(&config.user_parameters);
+        assert_eq!(
+            station.handle(&request(Some(SAP_SET_PRM), &prm), now),
+            Some(Telegram::ShortConfirmation)
+        );
+        assert_eq!(station.state, StationState::WaitCfg);
+
+        station.handle(&request(Some(SAP_CHK_CFG), &[0x00]), now);
+        assert!(station.cfg_fault);
+        assert_eq!(station.state, StationState::WaitPrm);
+
+        station.handle(&request(Some(SAP_SET_PRM), &prm), now);
+        station.handle(&request(Some(SAP_CHK_CFG), &config.config), now);
+        assert_eq!(station.state, StationState::DataExchange);
+
+        let Some(Telegram::Data { data, .. }) =
+            station.handle(&request(Some(SAP_SLAVE_DIAG), &[]), now)
+        else {
+            panic!("no diagnostics response");
+        };
+        assert_eq!(data, [0x00, 0x0c, 0x00, 0x03, 0xb7, 0x57]);
+
+        let response = station.handle(&request(None, &[0xa5, 0, 0, 0, 0x0f]), now);
+        assert_eq!(
+            response,
+            Some(Telegram::Data {
+                da: 3,
+                sa: 8,
+                fc: FC_DL,
+                dsap: None,
+                ssap: None,
+                data: vec![0x03, 0x12, 0x34, 0x00, 0x00],
+            })
+        );
+        assert_eq!(station.outputs, [0xa5, 0, 0, 0, 0x0f]);
+
+        // New diagnostics are announced with a high priority response
+        station.ext_diag = vec![0x03, 0x04, 0x05];
+        station.diag_pending = true;
+        assert!(matches!(
+            station.handle(&request(None, &[0; 5]), now),
+            Some(Telegram::Data { fc: FC_DH, .. })
+        ));
+
+        // Watchdog of 10 * 5 * 10 ms
+        station.check_watchdog(now + std::time::Duration::from_millis(600));
+        assert_eq!(station.state, StationState::WaitPrm);
+        assert_eq!(station.outputs, [0; 5]);
+    }
+
+    #[test]
+    fn pseudo_terminal() {
+        let simulator = Simulator::new(&[PeripheralConfig::wago_750_343()]).unwrap();
+        let mut port = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(simulator.device())
+            .unwrap();
+        std::thread::spawn(move || simulator.run());
+
+        // Request FDL status; line endings in the data must pass through untranslated
+        let mut request = Vec::new();
+        Telegram::Data {
+            da: 8,
+            sa: 3,
+            fc: FC_REQUEST | FC_FDL_STATUS,
+            dsap: None,
+            ssap: None,
+            data: vec![0x0a, 0x0d],
+        }
+        .encode(&mut request);
+        port.write_all(&request).unwrap();
+
+        let mut response = [0u8; 6];
+        port.read_exact(&mut response).unwrap();
+        assert_eq!(response, [SD1, 0x03, 0x08, FC_OK, 0x0b, ED]);
+    }
+
+    /// Full DP startup and data exchange between the real DP master and a simulated peripheral
+    #[test]
+    fn data_exchange() {
+        let config = crate::fieldbus::FieldbusConfig {
+            simulate: true,
+            sleep_time_ms: 1,
+            ..Default::default()
+        };
+        let mut fieldbus = crate::fieldbus::Fieldbus::new(config);
+        let simulator = fieldbus.simulator().unwrap();
+        fieldbus.enter_state(crate::fieldbus::OperatingState::Operate);
+        simulator.set_inputs(8, &[0x03, 0x12, 0x34, 0x00, 0x00]);
+
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(10);
+        let wait_for = |what: &str, f: &mut dyn FnMut() -> bool| {
+            while !f() {
+                assert!(
+                    std::time::Instant::now() < deadline,
+                    "timeout waiting for {what}"
+                );
+                std::thread::sleep(std::time::Duration::from_millis(10));
+            }
+        };
+
+        wait_for("data exchange", &mut || fieldbus.peripherals_running());
+        assert!(simulator.is_exchanging(8));
+        fieldbus.with_process_images(|_, piq| piq[..5].copy_from_slice(&[0xa5, 0, 0, 0, 0x0f]));
+        wait_for("outputs", &mut || {
+            simulator.outputs(8) == [0xa5, 0, 0, 0, 0x0f]
+        });
+        wait_for("inputs", &mut || {
+            fieldbus.with_process_images(|pii, _| pii[..6] == [0x01, 0x03, 0x12, 0x34, 0, 0])
+        });
+
+        simulator.set_ext_diag(8, &[0x03, 0x04, 0x05]);
+        wait_for("diagnostics", &mut || {
+            fieldbus
+                .diagnostics()
+                .iter()
+                .any(|d| d.device.as_ref().is_some_and(|d| d.code == 4))
+        });
+    }
+}
@@ -284,7 +284,7 @@ mod tests {
     fn timer_pulse() {
         let inp = [0, 1, 1, 1, 1, 0, 0, 0, 0, 1, 0, 1, 0, 1, 1, 1, 1, 0, 0, 0];
         let done = [0, 1, 1, 1, 0, 0, 0, 0, 0, 1, 1, 1, 0, 1, 1, 1, 0, 0, 0, 0];
-        let timing = done.clone();
+        let timing = done;
 
         let mut timer = PulseTimer::new();
         let mut now = time::Instant::now();
@@ -307,7 +307,7 @@ mod tests {
     fn timer_pulse_start() {
         let inp = [1, 1, 1, 1, 1, 0, 0, 0, 0, 1, 0, 1, 0, 1, 1, 1, 1, 0, 0, 0];
         let done = [1, 1, 1, 0, 0, 0, 0, 0, 0, 1, 1, 1, 0, 1, 1, 1, 0, 0, 0, 0];
-        let timing = done.clone();
+        let timing = done;
 
         let mut timer = PulseTimer::new();
         let mut now = time::Instant::now();